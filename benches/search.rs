@@ -0,0 +1,102 @@
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use crony_researcher::distance::{DistanceBuffers, levenshtein_distance_raw};
+use crony_researcher::index::{IndexBuilder, tokenize};
+use std::hint::black_box;
+
+const WORDS: [&str; 16] = [
+    "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", "hotel", "india", "juliet",
+    "kilo", "lima", "mike", "november", "oscar", "papa",
+];
+
+/// deterministic pseudo random text, so runs are comparable between commits
+fn synthetic_text(seed: usize, words: usize) -> String {
+    let mut state = seed
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    (0..words)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            WORDS[(state >> 33) % WORDS.len()]
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// pair of strings of roughly `len` chars, differing by a few edits
+fn similar_pair(len: usize) -> (Vec<char>, Vec<char>) {
+    let a: Vec<char> = synthetic_text(len, len / 6 + 1).chars().take(len).collect();
+    let mut b = a.clone();
+    for i in (0..b.len()).step_by(17) {
+        b[i] = 'x';
+    }
+    (a, b)
+}
+
+fn bench_distance(c: &mut Criterion) {
+    let mut group = c.benchmark_group("levenshtein_distance_raw");
+    let mut bufs = DistanceBuffers::new();
+
+    for len in [16, 64, 256, 1024] {
+        let (a, b) = similar_pair(len);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(len),
+            &(a, b),
+            |bench, (a, b)| {
+                bench.iter(|| levenshtein_distance_raw(black_box(a), black_box(b), 8, &mut bufs))
+            },
+        );
+    }
+
+    group.finish();
+}
+
+fn bench_tokenize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tokenize");
+
+    for words in [4, 32, 256] {
+        let text = synthetic_text(words, words);
+        group.bench_with_input(BenchmarkId::from_parameter(words), &text, |bench, text| {
+            bench.iter(|| tokenize(black_box(text)))
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_build_and_search(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build_and_search");
+    group.sample_size(10);
+
+    for records in [1_000, 10_000] {
+        let data: Vec<(usize, String)> =
+            (0..records).map(|id| (id, synthetic_text(id, 5))).collect();
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(records),
+            &data,
+            |bench, data| {
+                bench.iter(|| {
+                    let builder = IndexBuilder::new(0.85);
+                    builder.bulk_add(data.clone());
+                    let indexer = builder.build();
+
+                    (0..data.len())
+                        .map(|id| indexer.search_by_id(id, 8).len())
+                        .sum::<usize>()
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_distance,
+    bench_tokenize,
+    bench_build_and_search
+);
+criterion_main!(benches);
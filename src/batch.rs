@@ -0,0 +1,494 @@
+use crate::index::{Indexer, SearchStats};
+use crate::results::SimilarityResult;
+use rayon::prelude::*;
+use rustc_hash::FxHashSet;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Pairs found by a batch run, with the queries whose search panicked and were skipped.
+#[derive(Debug, Default)]
+pub struct BatchOutput {
+    pub results: Vec<SimilarityResult>,
+    /// sorted
+    pub failed_queries: Vec<usize>,
+    /// totals of all searched queries, zero for searches that do not count (`search_all_with`)
+    pub stats: SearchStats,
+    /// the `max_pairs` limit dropped pairs or skipped queries, so pairs may be missing
+    pub truncated: bool,
+}
+
+/// `SearchStats` totals shared by the threads of a batch run.
+#[derive(Debug, Default)]
+struct StatsCounters {
+    candidates: AtomicUsize,
+    rejected: AtomicUsize,
+    distance_computations: AtomicUsize,
+}
+
+impl StatsCounters {
+    fn add(&self, stats: &SearchStats) {
+        self.candidates
+            .fetch_add(stats.candidates, Ordering::Relaxed);
+        self.rejected.fetch_add(stats.rejected, Ordering::Relaxed);
+        self.distance_computations
+            .fetch_add(stats.distance_computations, Ordering::Relaxed);
+    }
+
+    fn into_stats(self) -> SearchStats {
+        SearchStats {
+            candidates: self.candidates.into_inner(),
+            rejected: self.rejected.into_inner(),
+            distance_computations: self.distance_computations.into_inner(),
+        }
+    }
+}
+
+/// One line summary of a run for monitoring, `key=value` pairs separated by spaces, see `Display`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunMetrics {
+    pub records: usize,
+    /// candidate pairs that passed the trigram prefilter
+    pub candidates: usize,
+    pub distance_computations: usize,
+    /// pairs in the output
+    pub pairs: usize,
+}
+
+impl RunMetrics {
+    pub fn new(records: usize, stats: &SearchStats, pairs: usize) -> Self {
+        Self {
+            records,
+            candidates: stats.candidates,
+            distance_computations: stats.distance_computations,
+            pairs,
+        }
+    }
+
+    pub fn pairs_per_record(&self) -> f64 {
+        if self.records == 0 {
+            return 0.0;
+        }
+        self.pairs as f64 / self.records as f64
+    }
+}
+
+impl fmt::Display for RunMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "records={} candidates={} distance_computations={} pairs={} pairs_per_record={:.4}",
+            self.records,
+            self.candidates,
+            self.distance_computations,
+            self.pairs,
+            self.pairs_per_record()
+        )
+    }
+}
+
+/// Search every id against the index in parallel. Results are in no particular order, the caller
+/// sorts all pairs once.
+///
+/// With `max_pairs` a global counter is shared across threads, once a pair beyond it is dropped no
+/// further queries are searched and the output is never longer than the limit. Which pairs are kept
+/// depends on scheduling, so a limited run is only useful for exploration.
+pub fn search_all(
+    indexer: &Indexer,
+    query_ids: &[usize],
+    max_distance: usize,
+    max_pairs: Option<usize>,
+) -> BatchOutput {
+    search_all_counted(indexer, query_ids, max_distance, max_pairs, true)
+}
+
+/// Same as `search_all`, but ids are searched in ascending order, `every` ids at a time, and the pairs of
+/// each part are handed to `checkpoint` (e.g. to append them to the results file) before the next part
+/// starts. A crash loses at most one part. Stops at the first error of `checkpoint`.
+pub fn search_all_checkpointed<F>(
+    indexer: &Indexer,
+    query_ids: &[usize],
+    max_distance: usize,
+    max_pairs: Option<usize>,
+    every: usize,
+    mut checkpoint: F,
+) -> Result<BatchOutput, Box<dyn Error>>
+where
+    F: FnMut(&mut Vec<SimilarityResult>) -> Result<(), Box<dyn Error>>,
+{
+    let mut query_ids = query_ids.to_vec();
+    query_ids.sort_unstable();
+
+    let mut output = BatchOutput::default();
+    for part in query_ids.chunks(every.max(1)) {
+        if output.truncated {
+            break;
+        }
+        let remaining = max_pairs.map(|limit| limit.saturating_sub(output.results.len()));
+
+        let mut part_output = search_all(indexer, part, max_distance, remaining);
+        checkpoint(&mut part_output.results)?;
+
+        output.results.append(&mut part_output.results);
+        output
+            .failed_queries
+            .append(&mut part_output.failed_queries);
+        output.stats.candidates += part_output.stats.candidates;
+        output.stats.rejected += part_output.stats.rejected;
+        output.stats.distance_computations += part_output.stats.distance_computations;
+        output.truncated |= part_output.truncated;
+    }
+
+    Ok(output)
+}
+
+/// Search only `query_ids` against the whole index, twins with smaller ids are found too
+/// (see `Indexer::neighbors`). A pair of two queried ids is reported from both sides.
+pub fn search_subset(
+    indexer: &Indexer,
+    query_ids: &[usize],
+    max_distance: usize,
+    max_pairs: Option<usize>,
+) -> BatchOutput {
+    search_all_counted(indexer, query_ids, max_distance, max_pairs, false)
+}
+
+/// `search_all` and `search_subset` with the counters of every search totalled.
+fn search_all_counted(
+    indexer: &Indexer,
+    query_ids: &[usize],
+    max_distance: usize,
+    max_pairs: Option<usize>,
+    only_greater_ids: bool,
+) -> BatchOutput {
+    let counters = StatsCounters::default();
+    let mut output = search_all_with(query_ids, max_pairs, |query_id| {
+        let (results, stats) =
+            indexer.search_by_id_with_stats(query_id, max_distance, only_greater_ids);
+        counters.add(&stats);
+        results
+            .into_iter()
+            .map(|a| SimilarityResult::new(query_id, a.id, a.distance, a.metric))
+            .collect()
+    });
+    output.stats = counters.into_stats();
+    output
+}
+
+/// Driver of `search_all` with any per-query search. A panic inside `search` only loses that query,
+/// it is recorded in `failed_queries` and the other queries complete (requires `panic = "unwind"`).
+pub fn search_all_with<F>(query_ids: &[usize], max_pairs: Option<usize>, search: F) -> BatchOutput
+where
+    F: Fn(usize) -> Vec<SimilarityResult> + Sync,
+{
+    let limit = max_pairs.unwrap_or(usize::MAX);
+    let found = AtomicUsize::new(0);
+    let truncated = AtomicBool::new(false);
+    let failed = Mutex::new(Vec::new());
+
+    let results = query_ids
+        .par_iter()
+        .flat_map_iter(|&query_id| {
+            // at the limit queries are still searched until one has a pair to drop, so a run with
+            // exactly `max_pairs` pairs is not reported as truncated
+            if truncated.load(Ordering::Relaxed) {
+                return Vec::new();
+            }
+
+            // the index is only read during search, a panic can not leave it half updated
+            let mut results = match panic::catch_unwind(AssertUnwindSafe(|| search(query_id))) {
+                Ok(results) => results,
+                Err(_) => {
+                    failed.lock().unwrap().push(query_id);
+                    return Vec::new();
+                }
+            };
+
+            // reserve slots for this query, whatever exceeds the limit is dropped
+            let before = found.fetch_add(results.len(), Ordering::Relaxed);
+            let keep = limit.saturating_sub(before);
+            if results.len() > keep {
+                truncated.store(true, Ordering::Relaxed);
+                results.truncate(keep);
+            }
+            results
+        })
+        .collect();
+
+    let mut failed_queries = failed.into_inner().unwrap();
+    failed_queries.sort_unstable();
+
+    BatchOutput {
+        results,
+        failed_queries,
+        stats: SearchStats::default(),
+        truncated: truncated.into_inner(),
+    }
+}
+
+/// Pairs found by two indexes of the same records, e.g. before and after a change of normalization or
+/// token settings. Pairs are `(smaller id, greater id)` regardless of metric, sorted.
+#[derive(Debug, Default, PartialEq)]
+pub struct CoverageDiff {
+    pub only_a: Vec<(usize, usize)>,
+    pub only_b: Vec<(usize, usize)>,
+    pub both: Vec<(usize, usize)>,
+}
+
+impl CoverageDiff {
+    /// True when B finds every pair A finds.
+    pub fn b_covers_a(&self) -> bool {
+        self.only_a.is_empty()
+    }
+}
+
+/// Run all pairs of `ids` on both indexes and sort the pairs by who found them, pairs with a twin
+/// outside `ids` are left out on both sides. The indexes are meant to be built from the same records,
+/// ids missing from one index only find pairs in the other.
+pub fn compare_coverage(
+    a: &Indexer,
+    b: &Indexer,
+    ids: &[usize],
+    max_distance: usize,
+) -> CoverageDiff {
+    let known: FxHashSet<usize> = ids.iter().copied().collect();
+    let pairs = |indexer: &Indexer| -> BTreeSet<(usize, usize)> {
+        search_all(indexer, ids, max_distance, None)
+            .results
+            .iter()
+            .filter(|r| known.contains(&r.twin_id))
+            .map(|r| (r.query_id.min(r.twin_id), r.query_id.max(r.twin_id)))
+            .collect()
+    };
+    let (pairs_a, pairs_b) = (pairs(a), pairs(b));
+
+    CoverageDiff {
+        only_a: pairs_a.difference(&pairs_b).copied().collect(),
+        only_b: pairs_b.difference(&pairs_a).copied().collect(),
+        both: pairs_a.intersection(&pairs_b).copied().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexBuilder;
+
+    #[test]
+    fn test_max_pairs_limits_total_output() {
+        let builder = IndexBuilder::new(0.3);
+        let records: Vec<(usize, String)> = (0..50)
+            .map(|i| (i, format!("acme corporation branch {}", i % 5)))
+            .collect();
+        let ids: Vec<usize> = records.iter().map(|(id, _)| *id).collect();
+        builder.bulk_add(records);
+        let indexer = builder.build();
+
+        let unlimited = search_all(&indexer, &ids, 2, None);
+        assert!(unlimited.results.len() > 100);
+        assert!(!unlimited.truncated);
+
+        for limit in [0, 1, 7, 100] {
+            let output = search_all(&indexer, &ids, 2, Some(limit));
+            assert_eq!(output.results.len(), limit);
+            assert!(output.truncated);
+        }
+
+        // a limit above the number of pairs never cuts anything
+        let above = search_all(&indexer, &ids, 2, Some(unlimited.results.len() + 1));
+        assert_eq!(above.results.len(), unlimited.results.len());
+        assert!(!above.truncated);
+
+        // neither does a limit of exactly the number of pairs
+        let exact = search_all(&indexer, &ids, 2, Some(unlimited.results.len()));
+        assert_eq!(exact.results.len(), unlimited.results.len());
+        assert!(!exact.truncated);
+        let exact_checkpointed =
+            search_all_checkpointed(&indexer, &ids, 2, Some(unlimited.results.len()), 10, |_| {
+                Ok(())
+            })
+            .unwrap();
+        assert!(!exact_checkpointed.truncated);
+
+        let checkpointed =
+            search_all_checkpointed(&indexer, &ids, 2, Some(7), 10, |_| Ok(())).unwrap();
+        assert_eq!(checkpointed.results.len(), 7);
+        assert!(checkpointed.truncated);
+    }
+
+    #[test]
+    fn test_run_metrics() {
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(vec![
+            (1, "hello world".to_string()),
+            (2, "hello worlds".to_string()),
+            (3, "hello wrld".to_string()),
+            (4, "foo bar baz".to_string()),
+        ]);
+        let indexer = builder.build();
+
+        let output = search_all(&indexer, &[1, 2, 3, 4], 2, None);
+        // 1 finds 2 and 3, 2 finds 3 and 3 has no greater candidate
+        assert_eq!(output.results.len(), 3);
+        assert_eq!(output.stats.candidates, 3);
+        assert_eq!(output.stats.distance_computations, 3);
+
+        let metrics = RunMetrics::new(indexer.len(), &output.stats, output.results.len());
+        assert_eq!(
+            metrics.to_string(),
+            "records=4 candidates=3 distance_computations=3 pairs=3 pairs_per_record=0.7500"
+        );
+
+        // checkpointed parts add up to the same totals
+        let checkpointed =
+            search_all_checkpointed(&indexer, &[1, 2, 3, 4], 2, None, 1, |_| Ok(())).unwrap();
+        assert_eq!(checkpointed.stats, output.stats);
+    }
+
+    #[test]
+    fn test_compare_coverage_of_trigrams_and_bigrams() {
+        use crate::index::TokenMode;
+
+        let records = vec![
+            (1, "北京大学".to_string()),
+            (2, "北京大學".to_string()),
+            (3, "東京大学".to_string()),
+            (4, "hello world".to_string()),
+            (5, "hello worlds".to_string()),
+        ];
+        let ids: Vec<usize> = records.iter().map(|(id, _)| *id).collect();
+        let index = |mode: TokenMode| {
+            let builder = IndexBuilder::new(0.6).with_token_mode(mode);
+            builder.bulk_add(records.clone());
+            builder.build()
+        };
+        let (trigrams, bigrams) = (index(TokenMode::Trigrams), index(TokenMode::Cjk));
+
+        // the CJK pairs share 2 of 3 bigrams but only 1 of 2 trigrams, latin text is tokenized alike
+        let diff = compare_coverage(&trigrams, &bigrams, &ids, 1);
+        assert_eq!(
+            diff,
+            CoverageDiff {
+                only_a: vec![],
+                only_b: vec![(1, 2), (1, 3)],
+                both: vec![(4, 5)],
+            }
+        );
+        assert!(diff.b_covers_a());
+
+        let reversed = compare_coverage(&bigrams, &trigrams, &ids, 1);
+        assert_eq!(reversed.only_a, diff.only_b);
+        assert!(!reversed.b_covers_a());
+
+        // 2 and 4 are not compared, the pair 1 finds with 2 is left out
+        let subset = compare_coverage(&trigrams, &bigrams, &[1, 3, 5], 1);
+        assert_eq!(
+            subset,
+            CoverageDiff {
+                only_a: vec![],
+                only_b: vec![(1, 3)],
+                both: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_failing_query_is_skipped() {
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(vec![
+            (1, "hello world".to_string()),
+            (2, "hello worlds".to_string()),
+            (3, "hello wrld".to_string()),
+        ]);
+        let indexer = builder.build();
+
+        let output = search_all_with(&[1, 2, 3], None, |query_id| {
+            if query_id == 2 {
+                panic!("injected failure");
+            }
+            indexer
+                .search_by_id(query_id, 2)
+                .into_iter()
+                .map(|a| SimilarityResult::new(query_id, a.id, a.distance, a.metric))
+                .collect()
+        });
+
+        assert_eq!(output.failed_queries, vec![2]);
+        assert!(output.results.iter().all(|r| r.query_id != 2));
+        assert!(output.results.iter().any(|r| r.query_id == 1));
+    }
+
+    #[test]
+    fn test_search_subset_finds_older_twins() {
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(vec![
+            (1, "hello world".to_string()),
+            (2, "acme corporation".to_string()),
+            (3, "foo bar baz".to_string()),
+            (10, "hello worlds".to_string()),
+            (11, "acme corporations".to_string()),
+        ]);
+        let indexer = builder.build();
+
+        // only the new records are queried, their twins are older ids
+        let mut results = search_subset(&indexer, &[10, 11], 2, None).results;
+        results.sort_unstable_by_key(|r| r.query_id);
+
+        let pairs: Vec<(usize, usize)> = results.iter().map(|r| (r.query_id, r.twin_id)).collect();
+        assert_eq!(pairs, vec![(10, 1), (11, 2)]);
+    }
+
+    #[test]
+    fn test_checkpoints_are_prefixes_of_final_output() {
+        use crate::results::{CsvAppender, OutputOptions, save_results_to_csv, sort_results};
+
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(
+            (0..40)
+                .map(|i| (i, format!("record number {} of the set", i / 2)))
+                .collect(),
+        );
+        let indexer = builder.build();
+        let ids: Vec<usize> = (0..40).rev().collect();
+
+        let temp_path = |name: &str| {
+            std::env::temp_dir()
+                .join(format!("crony_batch_{}_{}.csv", name, std::process::id()))
+                .to_string_lossy()
+                .into_owned()
+        };
+        let path = temp_path("checkpoint");
+        let options = OutputOptions::default();
+        let mut appender = CsvAppender::create(&path, &options).unwrap();
+
+        let mut snapshots = Vec::new();
+        let mut output = search_all_checkpointed(&indexer, &ids, 2, None, 7, |part| {
+            sort_results(part);
+            appender.append(part)?;
+            snapshots.push(std::fs::read_to_string(&path)?);
+            Ok(())
+        })
+        .unwrap();
+        drop(appender);
+        assert_eq!(snapshots.len(), 6);
+
+        // the final file written in one go from all pairs
+        sort_results(&mut output.results);
+        let final_path = temp_path("final");
+        save_results_to_csv(&output.results, &final_path, &options).unwrap();
+        let final_content = std::fs::read_to_string(&final_path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), final_content);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&final_path).unwrap();
+
+        for snapshot in &snapshots {
+            assert!(final_content.starts_with(snapshot.as_str()));
+            let mut rdr = csv::Reader::from_reader(snapshot.as_bytes());
+            assert!(rdr.records().all(|r| r.is_ok_and(|r| r.len() == 3)));
+        }
+        assert!(snapshots.windows(2).all(|w| w[0].len() < w[1].len()));
+    }
+}
@@ -0,0 +1,355 @@
+use crate::results::SimilarityResult;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::error::Error;
+use std::fs::File;
+use std::str::FromStr;
+
+/// Disjoint sets over record ids (union by size, path halving), ids are added on first use.
+#[derive(Debug, Default, Clone)]
+pub struct UnionFind {
+    parent: FxHashMap<usize, usize>,
+    size: FxHashMap<usize, usize>,
+}
+
+impl UnionFind {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn find(&mut self, id: usize) -> usize {
+        let mut current = id;
+        loop {
+            let parent = *self.parent.entry(current).or_insert(current);
+            if parent == current {
+                return current;
+            }
+            let grandparent = *self.parent.entry(parent).or_insert(parent);
+            self.parent.insert(current, grandparent);
+            current = grandparent;
+        }
+    }
+
+    /// Same as `find` without path halving, for shared references. Ids never added are their own root.
+    pub fn root(&self, id: usize) -> usize {
+        let mut current = id;
+        while let Some(&parent) = self.parent.get(&current) {
+            if parent == current {
+                break;
+            }
+            current = parent;
+        }
+        current
+    }
+
+    /// Returns false when both were already in the same set.
+    pub fn union(&mut self, a: usize, b: usize) -> bool {
+        let (root_a, root_b) = (self.find(a), self.find(b));
+        if root_a == root_b {
+            return false;
+        }
+
+        let size_a = *self.size.get(&root_a).unwrap_or(&1);
+        let size_b = *self.size.get(&root_b).unwrap_or(&1);
+        let (big, small) = if size_a >= size_b {
+            (root_a, root_b)
+        } else {
+            (root_b, root_a)
+        };
+
+        self.parent.insert(small, big);
+        self.size.insert(big, size_a + size_b);
+        true
+    }
+}
+
+/// Connected components of the twin graph, every id is in exactly one cluster (singletons included).
+/// Members are sorted and clusters are ordered by their smallest id.
+pub fn clusters(ids: &[usize], results: &[SimilarityResult]) -> Vec<Vec<usize>> {
+    let mut sets = UnionFind::new();
+    for result in results {
+        sets.union(result.query_id, result.twin_id);
+    }
+
+    let mut grouped: FxHashMap<usize, Vec<usize>> = FxHashMap::default();
+    for &id in ids {
+        grouped.entry(sets.find(id)).or_default().push(id);
+    }
+
+    let mut clusters: Vec<Vec<usize>> = grouped.into_values().collect();
+    for cluster in &mut clusters {
+        cluster.sort_unstable();
+        cluster.dedup();
+    }
+    clusters.sort_unstable_by_key(|c| c[0]);
+    clusters
+}
+
+/// Like `clusters`, but a cluster only grows `max_hops` edges away from its seed, so a long chain of
+/// weak links is cut into several clusters instead of one. Seeds are taken in id order from the ids
+/// not clustered yet and grow by breadth first search over the ids not clustered yet. Pairs with an
+/// id outside `ids` are ignored, so clusters only hold (and only link through) the given ids.
+pub fn clusters_within_hops(
+    ids: &[usize],
+    results: &[SimilarityResult],
+    max_hops: usize,
+) -> Vec<Vec<usize>> {
+    let known: FxHashSet<usize> = ids.iter().copied().collect();
+    let mut neighbors: FxHashMap<usize, Vec<usize>> = FxHashMap::default();
+    for result in results {
+        if !known.contains(&result.query_id) || !known.contains(&result.twin_id) {
+            continue;
+        }
+        neighbors
+            .entry(result.query_id)
+            .or_default()
+            .push(result.twin_id);
+        neighbors
+            .entry(result.twin_id)
+            .or_default()
+            .push(result.query_id);
+    }
+    for list in neighbors.values_mut() {
+        list.sort_unstable();
+    }
+
+    let mut seeds = ids.to_vec();
+    seeds.sort_unstable();
+    seeds.dedup();
+
+    let mut clustered: FxHashSet<usize> = FxHashSet::default();
+    let mut clusters = Vec::new();
+    for seed in seeds {
+        if !clustered.insert(seed) {
+            continue;
+        }
+
+        let mut cluster = vec![seed];
+        let mut frontier = vec![seed];
+        for _ in 0..max_hops {
+            let mut next = Vec::new();
+            for id in frontier {
+                for &neighbor in neighbors.get(&id).into_iter().flatten() {
+                    if clustered.insert(neighbor) {
+                        next.push(neighbor);
+                    }
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            cluster.extend_from_slice(&next);
+            frontier = next;
+        }
+
+        // every smaller id is clustered already, so the seed stays first
+        cluster.sort_unstable();
+        clusters.push(cluster);
+    }
+    clusters
+}
+
+/// Drop clusters with fewer than `min_size` members, keeps the order of the rest.
+pub fn retain_min_size(clusters: &mut Vec<Vec<usize>>, min_size: usize) {
+    clusters.retain(|cluster| cluster.len() >= min_size);
+}
+
+/// How the representative of a cluster is picked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanonicalPolicy {
+    SmallestId,
+    /// longest original text, ties go to the smallest id
+    LongestText,
+}
+
+impl FromStr for CanonicalPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "smallest-id" => Ok(Self::SmallestId),
+            "longest-text" => Ok(Self::LongestText),
+            _ => Err(format!(
+                "unknown canonical policy '{}', expected smallest-id or longest-text",
+                s
+            )),
+        }
+    }
+}
+
+/// (record_id, canonical_id) for every member of every cluster, deterministic for the same input.
+pub fn canonical_ids(
+    clusters: &[Vec<usize>],
+    policy: CanonicalPolicy,
+    text_len: impl Fn(usize) -> usize,
+) -> Vec<(usize, usize)> {
+    let mut mapping = Vec::with_capacity(clusters.iter().map(Vec::len).sum());
+
+    for cluster in clusters {
+        let canonical = match policy {
+            CanonicalPolicy::SmallestId => cluster.iter().copied().min(),
+            // max_by_key keeps the last maximum, reversed ids make it the smallest one
+            CanonicalPolicy::LongestText => cluster
+                .iter()
+                .copied()
+                .max_by_key(|&id| (text_len(id), std::cmp::Reverse(id))),
+        };
+
+        if let Some(canonical) = canonical {
+            mapping.extend(cluster.iter().map(|&id| (id, canonical)));
+        }
+    }
+
+    mapping
+}
+
+/// export mapping table to csv
+pub fn save_canonical_ids_to_csv(
+    mapping: &[(usize, usize)],
+    file_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let file = File::create(file_path)?;
+    let mut wtr = csv::Writer::from_writer(file);
+
+    wtr.write_record(["record_id", "canonical_id"])?;
+    for (record_id, canonical_id) in mapping {
+        wtr.write_record([record_id.to_string(), canonical_id.to_string()])?;
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Ids that appear in no pair, in the order of `ids`.
+pub fn singletons(ids: &[usize], matched: impl IntoIterator<Item = usize>) -> Vec<usize> {
+    let matched: FxHashSet<usize> = matched.into_iter().collect();
+    ids.iter()
+        .copied()
+        .filter(|id| !matched.contains(id))
+        .collect()
+}
+
+/// Write singleton ids, with their original text when `texts` are given (same order as `ids`).
+pub fn save_singletons_to_csv(
+    ids: &[usize],
+    texts: Option<&[&str]>,
+    file_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let file = File::create(file_path)?;
+    let mut wtr = csv::Writer::from_writer(file);
+
+    match texts {
+        Some(texts) => {
+            wtr.write_record(["record_id", "text"])?;
+            for (id, text) in ids.iter().zip(texts) {
+                wtr.write_record([id.to_string().as_str(), text])?;
+            }
+        }
+        None => {
+            wtr.write_record(["record_id"])?;
+            for id in ids {
+                wtr.write_record([id.to_string()])?;
+            }
+        }
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distance::Metric;
+
+    fn pair(query_id: usize, twin_id: usize) -> SimilarityResult {
+        SimilarityResult::new(query_id, twin_id, 1, Metric::Levenshtein)
+    }
+
+    #[test]
+    fn test_clusters_and_canonical_ids() {
+        let ids = [1, 2, 3, 4, 5, 6];
+        let results = vec![pair(1, 2), pair(2, 5), pair(3, 4)];
+
+        let clusters = clusters(&ids, &results);
+        assert_eq!(clusters, vec![vec![1, 2, 5], vec![3, 4], vec![6]]);
+
+        let smallest = canonical_ids(&clusters, CanonicalPolicy::SmallestId, |_| 0);
+        assert_eq!(
+            smallest,
+            vec![(1, 1), (2, 1), (5, 1), (3, 3), (4, 3), (6, 6)]
+        );
+
+        // 5 has the longest text in the first cluster, 3 and 4 tie so the smaller id wins
+        let lengths = |id: usize| if id == 5 { 20 } else { 10 };
+        let longest = canonical_ids(&clusters, CanonicalPolicy::LongestText, lengths);
+        assert_eq!(
+            longest,
+            vec![(1, 5), (2, 5), (5, 5), (3, 3), (4, 3), (6, 6)]
+        );
+    }
+
+    #[test]
+    fn test_clusters_within_hops() {
+        let ids = [1, 2, 3, 4, 5, 6, 7, 8];
+        let chain: Vec<SimilarityResult> = (1..7).map(|id| pair(id, id + 1)).collect();
+
+        assert_eq!(clusters(&ids, &chain).len(), 2);
+        assert_eq!(
+            clusters_within_hops(&ids, &chain, 2),
+            vec![vec![1, 2, 3], vec![4, 5, 6], vec![7], vec![8]]
+        );
+        assert_eq!(clusters_within_hops(&ids, &chain, 0).len(), ids.len());
+        assert_eq!(
+            clusters_within_hops(&ids, &chain, 10),
+            clusters(&ids, &chain)
+        );
+
+        // 9 is not an id, it neither joins a cluster nor links 1 and 8
+        let outside = vec![pair(1, 9), pair(9, 8), pair(2, 3)];
+        assert_eq!(
+            clusters_within_hops(&ids, &outside, 2),
+            vec![
+                vec![1],
+                vec![2, 3],
+                vec![4],
+                vec![5],
+                vec![6],
+                vec![7],
+                vec![8]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_min_cluster_size() {
+        let ids = [1, 2, 3, 4, 5, 6];
+        let results = vec![pair(1, 2), pair(2, 5), pair(3, 4)];
+
+        let mut large = clusters(&ids, &results);
+        retain_min_size(&mut large, 3);
+        assert_eq!(large, vec![vec![1, 2, 5]]);
+
+        let mut pairs = clusters(&ids, &results);
+        retain_min_size(&mut pairs, 2);
+        assert_eq!(pairs, vec![vec![1, 2, 5], vec![3, 4]]);
+    }
+
+    #[test]
+    fn test_singletons() {
+        let ids = [1, 2, 3, 4];
+        let results = [pair(1, 3)];
+
+        let lonely = singletons(&ids, results.iter().flat_map(|r| [r.query_id, r.twin_id]));
+        assert_eq!(lonely, vec![2, 4]);
+
+        let path = std::env::temp_dir()
+            .join(format!("crony_singletons_{}.csv", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        save_singletons_to_csv(&lonely, Some(&["two", "four, too"]), &path).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(content, "record_id,text\n2,two\n4,\"four, too\"\n");
+    }
+}
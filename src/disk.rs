@@ -0,0 +1,118 @@
+use rustc_hash::FxHashMap;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Normalized texts kept in a flat file instead of RAM, each record is fetched by its offset.
+/// Chars are stored as little endian u32.
+#[derive(Debug)]
+pub struct DiskArena {
+    file: File,
+    offsets: FxHashMap<usize, (u64, usize)>, // id -> (byte offset, number of chars)
+}
+
+/// Appends records to an arena file while the index is built, so normalized texts never pile up
+/// in memory. Safe to share between the threads of `bulk_add`.
+#[derive(Debug)]
+pub struct DiskArenaWriter {
+    path: PathBuf,
+    state: Mutex<WriterState>,
+}
+
+#[derive(Debug)]
+struct WriterState {
+    writer: BufWriter<File>,
+    offset: u64,
+    offsets: FxHashMap<usize, (u64, usize)>,
+}
+
+impl DiskArenaWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Mutex::new(WriterState {
+                writer: BufWriter::new(File::create(path)?),
+                offset: 0,
+                offsets: FxHashMap::default(),
+            }),
+        })
+    }
+
+    /// Store the chars of `id`, a later append of the same id replaces it.
+    pub fn append(&self, id: usize, chars: &[char]) -> io::Result<()> {
+        // encoded outside the lock, only the write itself is serialized
+        let bytes: Vec<u8> = chars
+            .iter()
+            .flat_map(|&c| (c as u32).to_le_bytes())
+            .collect();
+
+        let mut state = self.state.lock().unwrap();
+        state.writer.write_all(&bytes)?;
+        let offset = state.offset;
+        state.offsets.insert(id, (offset, chars.len()));
+        state.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Flush the file and open it for reading.
+    pub fn finish(self) -> io::Result<DiskArena> {
+        let mut state = self.state.into_inner().unwrap();
+        state.writer.flush()?;
+        drop(state.writer);
+
+        Ok(DiskArena {
+            file: File::open(&self.path)?,
+            offsets: state.offsets,
+        })
+    }
+}
+
+thread_local! {
+    // raw bytes of the last read, reused by every read of the thread
+    static READ_BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+impl DiskArena {
+    /// Read the chars of `id` into `buf`, returns false when the id is not stored.
+    pub fn read_into(&self, id: usize, buf: &mut Vec<char>) -> io::Result<bool> {
+        let (offset, len) = match self.offsets.get(&id) {
+            Some(&entry) => entry,
+            None => return Ok(false),
+        };
+
+        READ_BUF.with_borrow_mut(|bytes| {
+            bytes.resize(len * 4, 0);
+            read_exact_at(&self.file, bytes, offset)?;
+
+            buf.clear();
+            buf.extend(bytes.chunks_exact(4).map(|b| {
+                char::from_u32(u32::from_le_bytes([b[0], b[1], b[2], b[3]])).unwrap_or('\0')
+            }));
+            Ok(true)
+        })
+    }
+}
+
+// positional reads, so parallel searches don't fight over a shared cursor
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
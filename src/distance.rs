@@ -1,4 +1,8 @@
+use serde::Serialize;
 use std::cmp::min;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
 
 /// This struct is used to store the intermediate results of the distance calculations.
 /// It is used to avoid reallocating strings and vectors all the time.
@@ -12,6 +16,8 @@ pub struct DistanceBuffers {
     pub char_a: Vec<char>,
     pub char_b: Vec<char>,
     pub cache: Vec<usize>,
+    pub prev_row: Vec<usize>,
+    pub prev_prev_row: Vec<usize>,
 }
 
 impl Default for DistanceBuffers {
@@ -22,21 +28,46 @@ impl Default for DistanceBuffers {
 
 impl DistanceBuffers {
     pub fn new() -> Self {
+        // This is to ensure that we dont have to reallocate strings and vectors all the time, sizes are chosen by me "eye"
+        Self::with_capacity(256)
+    }
+
+    /// Buffers for strings of up to `n` chars, distance rows hold one more cell than the string.
+    pub fn with_capacity(n: usize) -> Self {
         Self {
-            // This is to ensure that we dont have to reallocate strings and vectors all the time, sizes are chosen by me "eye"
-            cleaned_a: String::with_capacity(256),
-            cleaned_b: String::with_capacity(256),
+            cleaned_a: String::with_capacity(n),
+            cleaned_b: String::with_capacity(n),
 
-            sorted_a: String::with_capacity(256),
-            sorted_b: String::with_capacity(256),
+            sorted_a: String::with_capacity(n),
+            sorted_b: String::with_capacity(n),
 
-            char_a: Vec::with_capacity(256),
-            char_b: Vec::with_capacity(256),
+            char_a: Vec::with_capacity(n),
+            char_b: Vec::with_capacity(n),
 
-            cache: Vec::with_capacity(256),
+            cache: Vec::with_capacity(n + 1),
+            prev_row: Vec::with_capacity(n + 1),
+            prev_prev_row: Vec::with_capacity(n + 1),
             ranges: Vec::with_capacity(32),
         }
     }
+
+    /// Grows the buffers to hold strings of up to `n` chars, never shrinks them.
+    pub fn reserve(&mut self, n: usize) {
+        for text in [
+            &mut self.cleaned_a,
+            &mut self.cleaned_b,
+            &mut self.sorted_a,
+            &mut self.sorted_b,
+        ] {
+            text.reserve(n.saturating_sub(text.len()));
+        }
+        for chars in [&mut self.char_a, &mut self.char_b] {
+            chars.reserve(n.saturating_sub(chars.len()));
+        }
+        for row in [&mut self.cache, &mut self.prev_row, &mut self.prev_prev_row] {
+            row.reserve((n + 1).saturating_sub(row.len()));
+        }
+    }
 }
 
 // pub fn token_sort_ratio(
@@ -56,27 +87,217 @@ impl DistanceBuffers {
 //     levenshtein_distance(max_distance, bufs)
 // }
 
+/// Distance function used to verify candidates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    Levenshtein,
+    /// optimal string alignment, Levenshtein with adjacent transpositions counted as one edit
+    Osa,
+    /// Levenshtein, only between records sharing a Double Metaphone code (see `phonetic`),
+    /// records sharing a code are candidates even without shared trigrams
+    Phonetic,
+    /// `token_set_match`, kept when its similarity ratio reaches `IndexBuilder::with_token_set_ratio`
+    /// instead of the max distance, the distance is that of the closest comparison (0 when the tokens
+    /// of one record are a subset of the other's)
+    #[serde(rename = "token-set")]
+    TokenSet,
+}
+
+impl Metric {
+    pub fn distance(
+        self,
+        a: &[char],
+        b: &[char],
+        max_distance: usize,
+        bufs: &mut DistanceBuffers,
+    ) -> usize {
+        match self {
+            Self::Levenshtein => levenshtein_distance_raw(a, b, max_distance, bufs),
+            Self::Osa => osa_distance_raw(a, b, max_distance, bufs),
+            Self::Phonetic => levenshtein_distance_raw(a, b, max_distance, bufs),
+            Self::TokenSet => token_set_match(a, b, bufs).0,
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "levenshtein" => Ok(Self::Levenshtein),
+            "osa" => Ok(Self::Osa),
+            "phonetic" => Ok(Self::Phonetic),
+            "token-set" => Ok(Self::TokenSet),
+            _ => Err(format!(
+                "unknown metric '{}', expected levenshtein, osa, phonetic or token-set",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Levenshtein => write!(f, "levenshtein"),
+            Self::Osa => write!(f, "osa"),
+            Self::Phonetic => write!(f, "phonetic"),
+            Self::TokenSet => write!(f, "token-set"),
+        }
+    }
+}
+
+/// Extra rules applied by `normalize_with`, default gives the plain lowercase+strip behavior of `normalize`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct NormalizeOptions {
+    /// characters replaced before the alphanumeric filter, e.g. '&' -> "and" or 'ß' -> "ss", ordered so
+    /// the manifest lists the rules the same way every run
+    pub char_map: BTreeMap<char, String>,
+    /// join digit groups separated by number formatting ('-', '.', '/', parentheses), so
+    /// "(555) 123-4567" and "5551234567" are the same while "12 14" stays two numbers
+    pub normalize_numbers: bool,
+    /// collapse runs of the same letter longer than this to this length, e.g. with 2 "helllo" -> "hello",
+    /// digits are left alone so numbers keep their value
+    pub squeeze_repeats: Option<usize>,
+    /// use the text verbatim (no char map, lowercasing, stripping or token sorting), for callers
+    /// who normalize upstream, the other options are ignored
+    pub raw: bool,
+    /// key tokens are sorted by instead of their bytes, e.g. `fold_diacritics` so "émile" sorts with "e",
+    /// the tokens themselves are not changed
+    #[serde(serialize_with = "serialize_collation")]
+    pub collation_key: Option<CollationKey>,
+}
+
+/// Sort key of a token for the token sort in `normalize_with`.
+pub type CollationKey = fn(&str) -> String;
+
+fn serialize_collation<S: serde::Serializer>(
+    key: &Option<CollationKey>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    // a function can't be serialized, only record whether one was used
+    serializer.serialize_bool(key.is_some())
+}
+
+/// Diacritic insensitive collation key, Latin letters with accents sort as their base letter.
+pub fn fold_diacritics(token: &str) -> String {
+    let mut key = String::with_capacity(token.len());
+    for c in token.chars() {
+        match c {
+            'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => key.push('a'),
+            'æ' => key.push_str("ae"),
+            'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => key.push('c'),
+            'ď' | 'đ' | 'ð' => key.push('d'),
+            'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => key.push('e'),
+            'ĝ' | 'ğ' | 'ġ' | 'ģ' => key.push('g'),
+            'ĥ' | 'ħ' => key.push('h'),
+            'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => key.push('i'),
+            'ĵ' => key.push('j'),
+            'ķ' => key.push('k'),
+            'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => key.push('l'),
+            'ñ' | 'ń' | 'ņ' | 'ň' => key.push('n'),
+            'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => key.push('o'),
+            'œ' => key.push_str("oe"),
+            'ŕ' | 'ŗ' | 'ř' => key.push('r'),
+            'ś' | 'ŝ' | 'ş' | 'š' => key.push('s'),
+            'ß' => key.push_str("ss"),
+            'ţ' | 'ť' | 'ŧ' => key.push('t'),
+            'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => key.push('u'),
+            'ŵ' => key.push('w'),
+            'ý' | 'ÿ' | 'ŷ' => key.push('y'),
+            'ź' | 'ż' | 'ž' => key.push('z'),
+            'þ' => key.push_str("th"),
+            other => key.push(other),
+        }
+    }
+    key
+}
+
 pub fn normalize(
     s: &str,
     cleaned_buf: &mut String,
     sorted_buf: &mut String,
     token_ranges: &mut Vec<(usize, usize)>,
+) {
+    normalize_with(
+        s,
+        &NormalizeOptions::default(),
+        cleaned_buf,
+        sorted_buf,
+        token_ranges,
+    );
+}
+
+pub fn normalize_with(
+    s: &str,
+    options: &NormalizeOptions,
+    cleaned_buf: &mut String,
+    sorted_buf: &mut String,
+    token_ranges: &mut Vec<(usize, usize)>,
 ) {
     cleaned_buf.clear();
 
-    for c in s.chars() {
-        if c.is_alphanumeric() || c.is_whitespace() {
-            for lc in c.to_lowercase() {
-                cleaned_buf.push(lc);
+    if options.raw {
+        cleaned_buf.push_str(s);
+        split_tokens(cleaned_buf, token_ranges);
+        sorted_buf.clear();
+        sorted_buf.push_str(s);
+        return;
+    }
+
+    let source = if options.normalize_numbers {
+        // sorted_buf is free until the very end, use it as scratch space
+        join_number_groups(s, sorted_buf);
+        sorted_buf.as_str()
+    } else {
+        s
+    };
+
+    for c in source.chars() {
+        // mapped replacement still goes through the filter, so mapping to punctuation drops it
+        match options.char_map.get(&c) {
+            Some(replacement) => {
+                for rc in replacement.chars() {
+                    push_cleaned(rc, cleaned_buf);
+                }
             }
+            None => push_cleaned(c, cleaned_buf),
+        }
+    }
+
+    if let Some(max_run) = options.squeeze_repeats {
+        squeeze_repeats(cleaned_buf, sorted_buf, max_run.max(1));
+    }
+
+    split_tokens(cleaned_buf, token_ranges);
+
+    match options.collation_key {
+        // ties are broken by the token itself, so equal keys still sort the same way everywhere
+        Some(key) => token_ranges.sort_by_cached_key(|&(s, e)| {
+            let token = &cleaned_buf[s..e];
+            (key(token), token.to_string())
+        }),
+        None => token_ranges.sort_unstable_by_key(|&(s, e)| &cleaned_buf[s..e]),
+    }
+
+    sorted_buf.clear();
+    for (i, &(s, e)) in token_ranges.iter().enumerate() {
+        if i > 0 {
+            sorted_buf.push(' ');
         }
+        sorted_buf.push_str(&cleaned_buf[s..e]);
     }
+}
 
+/// byte ranges of whitespace separated tokens
+fn split_tokens(text: &str, token_ranges: &mut Vec<(usize, usize)>) {
     token_ranges.clear();
     let mut start = 0;
     let mut in_word = false;
 
-    for (i, c) in cleaned_buf.char_indices() {
+    for (i, c) in text.char_indices() {
         if c.is_whitespace() {
             if in_word {
                 token_ranges.push((start, i));
@@ -88,17 +309,88 @@ pub fn normalize(
         }
     }
     if in_word {
-        token_ranges.push((start, cleaned_buf.len()));
+        token_ranges.push((start, text.len()));
     }
+}
 
-    token_ranges.sort_unstable_by_key(|&(s, e)| &cleaned_buf[s..e]);
+/// keep at most `max_run` consecutive copies of the same non-digit character
+fn squeeze_repeats(cleaned_buf: &mut String, scratch: &mut String, max_run: usize) {
+    scratch.clear();
+    let mut prev = None;
+    let mut run = 0;
 
-    sorted_buf.clear();
-    for (i, &(s, e)) in token_ranges.iter().enumerate() {
-        if i > 0 {
-            sorted_buf.push(' ');
+    for c in cleaned_buf.chars() {
+        if prev == Some(c) {
+            run += 1;
+        } else {
+            prev = Some(c);
+            run = 1;
+        }
+        if run <= max_run || c.is_numeric() {
+            scratch.push(c);
+        }
+    }
+
+    std::mem::swap(cleaned_buf, scratch);
+}
+
+/// characters that format a number, a gap between two digit groups needs one of them to be joined
+const NUMBER_SEPARATORS: [char; 5] = ['-', '.', '/', '(', ')'];
+
+/// "(555) 123-4567" -> "5551234567", digit groups touching a letter like "12b" are never joined
+fn join_number_groups(s: &str, out: &mut String) {
+    out.clear();
+    let mut i = 0;
+    let mut prev_alpha = false;
+
+    while let Some(c) = s[i..].chars().next() {
+        if !c.is_numeric() {
+            out.push(c);
+            prev_alpha = c.is_alphabetic();
+            i += c.len_utf8();
+            continue;
+        }
+
+        let mut end = digit_group_end(s, i);
+        out.push_str(&s[i..end]);
+        if !prev_alpha && !starts_with_alpha(&s[end..]) {
+            loop {
+                let gap = s[end..]
+                    .find(|c: char| !c.is_whitespace() && !NUMBER_SEPARATORS.contains(&c))
+                    .map_or(s.len(), |n| end + n);
+                if !s[end..gap].contains(NUMBER_SEPARATORS)
+                    || !s[gap..].starts_with(char::is_numeric)
+                {
+                    break;
+                }
+                let next_end = digit_group_end(s, gap);
+                if starts_with_alpha(&s[next_end..]) {
+                    break;
+                }
+                out.push_str(&s[gap..next_end]);
+                end = next_end;
+            }
+        }
+        i = end;
+        prev_alpha = false;
+    }
+}
+
+fn digit_group_end(s: &str, start: usize) -> usize {
+    s[start..]
+        .find(|c: char| !c.is_numeric())
+        .map_or(s.len(), |n| start + n)
+}
+
+fn starts_with_alpha(s: &str) -> bool {
+    s.starts_with(char::is_alphabetic)
+}
+
+fn push_cleaned(c: char, cleaned_buf: &mut String) {
+    if c.is_alphanumeric() || c.is_whitespace() {
+        for lc in c.to_lowercase() {
+            cleaned_buf.push(lc);
         }
-        sorted_buf.push_str(&cleaned_buf[s..e]);
     }
 }
 
@@ -175,6 +467,15 @@ pub fn normalize(
 //     }
 // }
 
+/// Distance scaled by the longer text to a similarity in [0, 1], 1.0 means identical.
+pub fn similarity_ratio(distance: usize, len_a: usize, len_b: usize) -> f64 {
+    let longer = len_a.max(len_b);
+    if longer == 0 {
+        return 1.0;
+    }
+    (1.0 - distance as f64 / longer as f64).clamp(0.0, 1.0)
+}
+
 /// This is implementation of bounded Levenshtein - ukkonen's algorithm
 /// https://en.wikipedia.org/wiki/Levenshtein_distance ; https://en.wikipedia.org/wiki/Ukkonen%27s_algorithm
 pub fn levenshtein_distance_raw(
@@ -226,8 +527,7 @@ pub fn levenshtein_distance_raw(
 
         let mut min_in_row = max_val;
 
-        for j in (start - 1)..end {
-            let t_char = target[j];
+        for (j, &t_char) in target.iter().enumerate().take(end).skip(start - 1) {
             let next_diagonal = bufs.cache[j + 1];
             let cost = if s_char == t_char { 0 } else { 1 };
 
@@ -255,6 +555,218 @@ pub fn levenshtein_distance_raw(
     }
 }
 
+/// Token set comparison like fuzzywuzzy's token_set_ratio: the shared tokens of both texts are compared
+/// with either text's tokens (shared ones first) and the texts without the shared tokens with each other,
+/// so extra tokens on one side cost nothing. Returns the distance and `similarity_ratio` of the closest
+/// of these comparisons, unbounded.
+pub fn token_set_match(a: &[char], b: &[char], bufs: &mut DistanceBuffers) -> (usize, f64) {
+    fn token_set(text: &[char]) -> Vec<&[char]> {
+        let mut tokens: Vec<&[char]> = text
+            .split(|c| c.is_whitespace())
+            .filter(|token| !token.is_empty())
+            .collect();
+        tokens.sort_unstable();
+        tokens.dedup();
+        tokens
+    }
+    fn join(first: &[&[char]], second: &[&[char]]) -> Vec<char> {
+        let mut joined = Vec::new();
+        for token in first.iter().chain(second) {
+            if !joined.is_empty() {
+                joined.push(' ');
+            }
+            joined.extend_from_slice(token);
+        }
+        joined
+    }
+
+    let (a_tokens, b_tokens) = (token_set(a), token_set(b));
+    let (shared, only_a): (Vec<&[char]>, Vec<&[char]>) = a_tokens
+        .iter()
+        .partition(|token| b_tokens.binary_search(token).is_ok());
+    let only_b: Vec<&[char]> = b_tokens
+        .iter()
+        .copied()
+        .filter(|token| shared.binary_search(token).is_err())
+        .collect();
+
+    let (shared_text, a_text, b_text) = (
+        join(&shared, &[]),
+        join(&shared, &only_a),
+        join(&shared, &only_b),
+    );
+    // nothing shared leaves an empty text that would match every text without extra tokens
+    let pairs: &[(&[char], &[char])] = if shared.is_empty() {
+        &[(&a_text, &b_text)]
+    } else {
+        &[
+            (&shared_text, &a_text),
+            (&shared_text, &b_text),
+            (&a_text, &b_text),
+        ]
+    };
+
+    pairs
+        .iter()
+        .map(|&(x, y)| {
+            let distance = levenshtein_distance_raw(x, y, x.len().max(y.len()), bufs);
+            (distance, similarity_ratio(distance, x.len(), y.len()))
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+        .unwrap_or((0, 1.0))
+}
+
+/// Optimal string alignment distance (restricted Damerau-Levenshtein), swapping two adjacent chars costs 1.
+/// Bounded the same way as `levenshtein_distance_raw`, returns max_distance + 1 when the bound is exceeded.
+pub fn osa_distance_raw(
+    a: &[char],
+    b: &[char],
+    max_distance: usize,
+    bufs: &mut DistanceBuffers,
+) -> usize {
+    let a_len = a.len();
+    let b_len = b.len();
+
+    if a_len.abs_diff(b_len) > max_distance {
+        return max_distance + 1;
+    }
+
+    if a_len == 0 {
+        return b_len;
+    }
+    if b_len == 0 {
+        return a_len;
+    }
+
+    let max_val = max_distance + 1;
+
+    // rows i-2, i-1 and i of the DP matrix, taken out of the buffers to satisfy the borrow checker
+    let mut prev_prev = std::mem::take(&mut bufs.prev_prev_row);
+    let mut prev = std::mem::take(&mut bufs.prev_row);
+    let mut current = std::mem::take(&mut bufs.cache);
+
+    prev_prev.clear();
+    prev.clear();
+    prev.extend(0..=b_len);
+
+    let mut exceeded = false;
+
+    for i in 1..=a_len {
+        current.clear();
+        current.push(i);
+        let mut min_in_row = i;
+
+        for j in 1..=b_len {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+
+            let mut res = min(min(prev[j] + 1, current[j - 1] + 1), prev[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                res = min(res, prev_prev[j - 2] + 1);
+            }
+
+            current.push(res);
+            min_in_row = min(min_in_row, res);
+        }
+
+        std::mem::swap(&mut prev_prev, &mut prev);
+        std::mem::swap(&mut prev, &mut current);
+
+        if min_in_row > max_distance {
+            exceeded = true;
+            break;
+        }
+    }
+
+    let result = if exceeded || prev[b_len] > max_distance {
+        max_val
+    } else {
+        prev[b_len]
+    };
+
+    bufs.prev_prev_row = prev_prev;
+    bufs.prev_row = prev;
+    bufs.cache = current;
+
+    result
+}
+
+/// Costs of `weighted_osa_distance_raw` are kept as integers in 1/COST_SCALE units.
+const COST_SCALE: usize = 100;
+
+/// OSA where a transposition costs `transpose_cost` (0.0 to 2.0, two substitutions do the same job)
+/// and every other edit 1. The returned distance is the total cost rounded up, so with a cost of 0.5
+/// two swaps are distance 1.
+pub fn weighted_osa_distance_raw(
+    a: &[char],
+    b: &[char],
+    max_distance: usize,
+    transpose_cost: f64,
+    bufs: &mut DistanceBuffers,
+) -> usize {
+    let a_len = a.len();
+    let b_len = b.len();
+
+    // transpositions keep the length, so the length difference is still a lower bound
+    if a_len.abs_diff(b_len) > max_distance {
+        return max_distance + 1;
+    }
+
+    let edit = COST_SCALE;
+    let transpose = (transpose_cost.clamp(0.0, 2.0) * COST_SCALE as f64).round() as usize;
+    let max_scaled = max_distance * COST_SCALE;
+
+    let mut prev_prev = std::mem::take(&mut bufs.prev_prev_row);
+    let mut prev = std::mem::take(&mut bufs.prev_row);
+    let mut current = std::mem::take(&mut bufs.cache);
+
+    prev_prev.clear();
+    prev.clear();
+    prev.extend((0..=b_len).map(|j| j * edit));
+
+    let mut exceeded = false;
+
+    for i in 1..=a_len {
+        current.clear();
+        current.push(i * edit);
+        let mut min_in_row = i * edit;
+
+        for j in 1..=b_len {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { edit };
+
+            let mut res = min(
+                min(prev[j] + edit, current[j - 1] + edit),
+                prev[j - 1] + cost,
+            );
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                res = min(res, prev_prev[j - 2] + transpose);
+            }
+
+            current.push(res);
+            min_in_row = min(min_in_row, res);
+        }
+
+        std::mem::swap(&mut prev_prev, &mut prev);
+        std::mem::swap(&mut prev, &mut current);
+
+        if min_in_row > max_scaled {
+            exceeded = true;
+            break;
+        }
+    }
+
+    let result = if exceeded || prev[b_len] > max_scaled {
+        max_distance + 1
+    } else {
+        prev[b_len].div_ceil(COST_SCALE)
+    };
+
+    bufs.prev_prev_row = prev_prev;
+    bufs.prev_row = prev;
+    bufs.cache = current;
+
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -294,6 +806,128 @@ mod tests {
         assert_eq!(sorted_buf, "cases test");
     }
 
+    #[test]
+    fn test_normalize_with_char_map() {
+        let mut options = NormalizeOptions::default();
+        options.char_map.insert('&', "and".to_string());
+
+        let mut cleaned_buf = String::new();
+        let mut sorted_a = String::new();
+        let mut sorted_b = String::new();
+        let mut token_ranges = Vec::new();
+
+        normalize_with(
+            "AT&T",
+            &options,
+            &mut cleaned_buf,
+            &mut sorted_a,
+            &mut token_ranges,
+        );
+        normalize_with(
+            "ATandT",
+            &options,
+            &mut cleaned_buf,
+            &mut sorted_b,
+            &mut token_ranges,
+        );
+
+        assert_eq!(sorted_a, "atandt");
+        assert_eq!(sorted_a, sorted_b);
+
+        // without the map the ampersand is just stripped
+        normalize("AT&T", &mut cleaned_buf, &mut sorted_a, &mut token_ranges);
+        assert_eq!(sorted_a, "att");
+    }
+
+    #[test]
+    fn test_normalize_numbers() {
+        let options = NormalizeOptions {
+            normalize_numbers: true,
+            ..Default::default()
+        };
+
+        let mut cleaned_buf = String::new();
+        let mut sorted_buf = String::new();
+        let mut token_ranges = Vec::new();
+
+        normalize_with(
+            "Call (555) 123-4567",
+            &options,
+            &mut cleaned_buf,
+            &mut sorted_buf,
+            &mut token_ranges,
+        );
+        assert_eq!(sorted_buf, "5551234567 call");
+
+        // mixed tokens stay separate from the digit group
+        normalize_with(
+            "Flat 12B-34 56/78",
+            &options,
+            &mut cleaned_buf,
+            &mut sorted_buf,
+            &mut token_ranges,
+        );
+        assert_eq!(cleaned_buf, "flat 12b34 5678");
+
+        // numbers separated only by whitespace are unrelated and stay apart
+        normalize_with(
+            "Rooms 12 14, 2019 2020",
+            &options,
+            &mut cleaned_buf,
+            &mut sorted_buf,
+            &mut token_ranges,
+        );
+        assert_eq!(cleaned_buf, "rooms 12 14 2019 2020");
+    }
+
+    #[test]
+    fn test_squeeze_repeats() {
+        let mut cleaned_buf = String::new();
+        let mut sorted_a = String::new();
+        let mut sorted_b = String::new();
+        let mut token_ranges = Vec::new();
+
+        for max_run in [1, 2] {
+            let options = NormalizeOptions {
+                squeeze_repeats: Some(max_run),
+                ..Default::default()
+            };
+            normalize_with(
+                "helllllo",
+                &options,
+                &mut cleaned_buf,
+                &mut sorted_a,
+                &mut token_ranges,
+            );
+            normalize_with(
+                "hello",
+                &options,
+                &mut cleaned_buf,
+                &mut sorted_b,
+                &mut token_ranges,
+            );
+
+            let a: Vec<char> = sorted_a.chars().collect();
+            let b: Vec<char> = sorted_b.chars().collect();
+            let dist = levenshtein_distance_raw(&a, &b, 2, &mut DistanceBuffers::new());
+            assert_eq!(dist, 0);
+        }
+
+        // numbers are kept as they are
+        let options = NormalizeOptions {
+            squeeze_repeats: Some(1),
+            ..Default::default()
+        };
+        normalize_with(
+            "1000 zzz",
+            &options,
+            &mut cleaned_buf,
+            &mut sorted_a,
+            &mut token_ranges,
+        );
+        assert_eq!(sorted_a, "1000 z");
+    }
+
     #[test]
     fn test_levenshtein_distance_raw() {
         let mut bufs = DistanceBuffers::new();
@@ -316,4 +950,101 @@ mod tests {
         let dist_exact = levenshtein_distance_raw(&a, &a, 10, &mut bufs);
         assert_eq!(dist_exact, 0);
     }
+
+    #[test]
+    fn test_token_set_match() {
+        let mut bufs = DistanceBuffers::new();
+        let chars = |text: &str| text.chars().collect::<Vec<char>>();
+
+        // every token of the shorter text is in the longer one
+        assert_eq!(
+            token_set_match(
+                &chars("john smith"),
+                &chars("jr john md phd smith"),
+                &mut bufs
+            ),
+            (0, 1.0)
+        );
+        // shared "john", then "smith" and "smyth" differ by one edit
+        let (distance, ratio) =
+            token_set_match(&chars("john smith"), &chars("john smyth"), &mut bufs);
+        assert_eq!(distance, 1);
+        assert!((ratio - 0.9).abs() < 1e-9, "{}", ratio);
+        // nothing shared is a plain comparison of the texts
+        assert_eq!(
+            token_set_match(&chars("abc"), &chars("xyz"), &mut bufs),
+            (3, 0.0)
+        );
+    }
+
+    #[test]
+    fn test_osa_distance_raw() {
+        let mut bufs = DistanceBuffers::new();
+
+        let a: Vec<char> = "abcd".chars().collect();
+        let b: Vec<char> = "bacd".chars().collect();
+
+        assert_eq!(osa_distance_raw(&a, &b, 10, &mut bufs), 1);
+        assert_eq!(levenshtein_distance_raw(&a, &b, 10, &mut bufs), 2);
+
+        let kitten: Vec<char> = "kitten".chars().collect();
+        let sitting: Vec<char> = "sitting".chars().collect();
+        assert_eq!(osa_distance_raw(&kitten, &sitting, 10, &mut bufs), 3);
+        assert_eq!(osa_distance_raw(&kitten, &sitting, 2, &mut bufs), 3);
+        assert_eq!(osa_distance_raw(&kitten, &kitten, 0, &mut bufs), 0);
+    }
+
+    #[test]
+    fn test_weighted_osa_distance_raw() {
+        let mut bufs = DistanceBuffers::new();
+        let a: Vec<char> = "abcdefgh".chars().collect();
+        let b: Vec<char> = "badcefgx".chars().collect();
+
+        // two swaps and one substitution
+        assert_eq!(osa_distance_raw(&a, &b, 5, &mut bufs), 3);
+        assert_eq!(weighted_osa_distance_raw(&a, &b, 5, 1.0, &mut bufs), 3);
+        assert_eq!(weighted_osa_distance_raw(&a, &b, 5, 0.5, &mut bufs), 2);
+        assert_eq!(weighted_osa_distance_raw(&a, &b, 5, 0.0, &mut bufs), 1);
+        // swaps are capped at two edits, which is plain Levenshtein
+        assert_eq!(weighted_osa_distance_raw(&a, &b, 5, 3.0, &mut bufs), 4);
+        assert_eq!(levenshtein_distance_raw(&a, &b, 5, &mut bufs), 4);
+        assert_eq!(weighted_osa_distance_raw(&a, &b, 1, 1.0, &mut bufs), 2);
+    }
+
+    #[test]
+    fn test_collation_key_orders_accented_tokens() {
+        let mut cleaned_buf = String::new();
+        let mut sorted_buf = String::new();
+        let mut token_ranges = Vec::new();
+
+        // by bytes 'é' sorts after 'z'
+        normalize(
+            "Émile Zola",
+            &mut cleaned_buf,
+            &mut sorted_buf,
+            &mut token_ranges,
+        );
+        assert_eq!(sorted_buf, "zola émile");
+
+        let options = NormalizeOptions {
+            collation_key: Some(fold_diacritics),
+            ..Default::default()
+        };
+        for (text, expected) in [
+            ("Émile Zola", "émile zola"),
+            ("Zola Emile", "emile zola"),
+            ("José Maria", "josé maria"),
+            ("Maria José", "josé maria"),
+            ("Łukasz Kowalski Ołówek", "kowalski łukasz ołówek"),
+        ] {
+            normalize_with(
+                text,
+                &options,
+                &mut cleaned_buf,
+                &mut sorted_buf,
+                &mut token_ranges,
+            );
+            assert_eq!(sorted_buf, expected);
+        }
+    }
 }
@@ -1,15 +1,79 @@
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use rayon::prelude::*;
 use rustc_hash::FxHashMap;
 use rustc_hash::FxHasher;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fmt;
 use std::hash::Hasher;
-
-use crate::distance::{DistanceBuffers, levenshtein_distance_raw, normalize};
-
-#[derive(Debug)]
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use crate::cluster::UnionFind;
+use crate::disk::{DiskArena, DiskArenaWriter};
+use crate::distance::{
+    DistanceBuffers, Metric, NormalizeOptions, normalize_with, similarity_ratio, token_set_match,
+    weighted_osa_distance_raw,
+};
+use crate::lsh::LshIndex;
+use crate::phonetic::phonetic_keys;
+
+#[derive(Debug, Serialize)]
 pub struct SearchResult {
     pub id: usize,
     pub distance: usize,
+    pub metric: Metric,
+}
+
+/// Key of the inverted index, a packed character trigram (or CJK bigram) or a hashed word shingle (see `TokenMode`).
+pub type Gram = u64;
+
+/// What the inverted index is keyed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenMode {
+    /// character trigrams inside words, the default for short texts like names
+    #[default]
+    Trigrams,
+    /// shingles of n consecutive words, much smaller index for long documents
+    Words(usize),
+    /// character trigrams for most words, but bigrams inside runs of Chinese, Japanese or Korean
+    /// characters (a single CJK character is a unigram), which are written without spaces and have
+    /// many words of one or two characters
+    Cjk,
+}
+
+/// Order of search results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RankBy {
+    /// raw distance ascending
+    #[default]
+    Distance,
+    /// similarity ratio (see `similarity_ratio`) descending, a distance 3 twin of a 100 char text
+    /// ranks above a distance 3 twin of a 5 char one
+    Ratio,
+}
+
+impl FromStr for RankBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "distance" => Ok(Self::Distance),
+            "ratio" => Ok(Self::Ratio),
+            _ => Err(format!(
+                "unknown ranking '{}', expected distance or ratio",
+                s
+            )),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -18,32 +82,431 @@ pub struct PreparedText {
     pub normalized_vec: Vec<char>,
     pub normalized_len: usize,
     pub normalized_hash: u64,
-    pub trigrams: Vec<[char; 3]>,
+    pub grams: Vec<Gram>,
+    /// Double Metaphone blocking keys, only computed when `Metric::Phonetic` is enabled
+    pub phonetic_keys: Vec<u64>,
+    /// relative position (0..1) of the first occurrence of each gram, parallel to `grams`
+    #[cfg(feature = "positional")]
+    pub trigram_positions: Vec<f32>,
+}
+
+/// Entry of the inverted index, with the `positional` feature it also keeps relative position of the trigram.
+#[cfg(not(feature = "positional"))]
+type Posting = usize;
+#[cfg(feature = "positional")]
+type Posting = (usize, f32);
+
+#[cfg(not(feature = "positional"))]
+#[inline]
+fn posting_id(posting: &Posting) -> usize {
+    *posting
+}
+
+#[cfg(feature = "positional")]
+#[inline]
+fn posting_id(posting: &Posting) -> usize {
+    posting.0
+}
+
+thread_local! {
+    // one set per search thread, sized to the longest indexed record (see `Indexer::with_distance_buffers`)
+    // and grown by use past it for longer queries
+    static DISTANCE_BUFFERS: RefCell<DistanceBuffers> = RefCell::new(DistanceBuffers::new());
+}
+
+/// Why `IndexBuilder::try_build` failed.
+#[derive(Debug)]
+pub enum BuildError {
+    /// writing the file of `IndexBuilder::with_disk_storage` failed
+    Disk(io::Error),
+    /// records with empty text were added under `EmptyRecordPolicy::Error`, sorted ids
+    EmptyRecords(Vec<usize>),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disk(e) => write!(f, "cannot write disk storage: {}", e),
+            Self::EmptyRecords(ids) => write!(f, "found {} empty records: {:?}", ids.len(), ids),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl From<io::Error> for BuildError {
+    fn from(e: io::Error) -> Self {
+        Self::Disk(e)
+    }
+}
+
+/// What happens to records whose text is empty or whitespace only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmptyRecordPolicy {
+    /// drop them silently
+    #[default]
+    Skip,
+    /// drop them, their ids are available from `Indexer::empty_records`
+    Report,
+    /// keep them as empty records, they are twins (distance 0) of each other
+    Index,
+    /// fail `IndexBuilder::try_build` with `BuildError::EmptyRecords`, and reject them in `Indexer::insert`
+    Error,
+}
+
+impl FromStr for EmptyRecordPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "report" => Ok(Self::Report),
+            "index" => Ok(Self::Index),
+            "error" => Ok(Self::Error),
+            _ => Err(format!(
+                "unknown empty record policy '{}', expected skip, report, index or error",
+                s
+            )),
+        }
+    }
+}
+
+/// Max distance per normalized length range, e.g. "0-10=2,11-30=4,31+=8". Lengths outside every bucket
+/// keep the `max_distance` of the search.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LengthBuckets {
+    buckets: Vec<(usize, usize, usize)>, // (min_len, max_len inclusive, max_distance), sorted by min_len
+}
+
+impl LengthBuckets {
+    fn largest(&self) -> Option<usize> {
+        self.buckets.iter().map(|&(_, _, distance)| distance).max()
+    }
+
+    /// Bound for a pair whose longer normalized text has `len` chars.
+    pub fn max_distance(&self, len: usize) -> Option<usize> {
+        self.buckets
+            .iter()
+            .find(|&&(min, max, _)| min <= len && len <= max)
+            .map(|&(_, _, distance)| distance)
+    }
+}
+
+impl FromStr for LengthBuckets {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut buckets = Vec::new();
+
+        for entry in s
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let invalid = || {
+                format!(
+                    "invalid length bucket '{}', expected min-max=distance or min+=distance",
+                    entry
+                )
+            };
+
+            let (range, distance) = entry.split_once('=').ok_or_else(invalid)?;
+            let distance = distance.trim().parse().map_err(|_| invalid())?;
+            let range = range.trim();
+            let (min, max) = match range.strip_suffix('+') {
+                Some(min) => (min.parse().map_err(|_| invalid())?, usize::MAX),
+                None => {
+                    let (min, max) = range.split_once('-').ok_or_else(invalid)?;
+                    (
+                        min.trim().parse().map_err(|_| invalid())?,
+                        max.trim().parse().map_err(|_| invalid())?,
+                    )
+                }
+            };
+            if min > max {
+                return Err(invalid());
+            }
+
+            buckets.push((min, max, distance));
+        }
+
+        buckets.sort_unstable();
+        if buckets.windows(2).any(|w| w[0].1 >= w[1].0) {
+            return Err(format!("length buckets in '{}' overlap", s));
+        }
+
+        Ok(Self { buckets })
+    }
 }
 
+/// Where a low memory index reads original texts from, see `IndexBuilder::with_original_source`.
+pub trait OriginalSource: fmt::Debug + Send + Sync {
+    fn original(&self, id: usize) -> Option<String>;
+}
+
+/// Records compared per query by the brute force fallback, see `IndexBuilder::with_brute_force_limit`.
+pub const DEFAULT_BRUTE_FORCE_LIMIT: usize = 10_000;
+
+/// Seed of the normalized text hash, see `IndexBuilder::with_hash_seed`. The 64 bit FNV prime.
+pub const DEFAULT_HASH_SEED: u64 = 0x0000_0100_0000_01b3;
+
+/// Min similarity ratio of `Metric::TokenSet`, see `IndexBuilder::with_token_set_ratio`.
+pub const DEFAULT_TOKEN_SET_RATIO: f64 = 0.9;
+
 /// Only used during building phase, clone will be never used here, and is unneccessary.
 #[derive(Debug)]
 pub struct IndexBuilder {
-    index: DashMap<[char; 3], Vec<usize>>,
+    index: DashMap<Gram, Vec<Posting>>,
+    phonetic_index: DashMap<u64, Vec<usize>>,
     storage: DashMap<usize, PreparedText>,
+    empty_records: DashSet<usize>,
+    empty_record_policy: EmptyRecordPolicy,
     min_trigram_match_ratio: f64,
+    max_distance_ratio: Option<f64>,
+    min_length_ratio: Option<f64>,
+    max_postings_per_trigram: Option<usize>,
+    min_distinct_trigrams: usize,
+    include_exact: bool,
+    metrics: Vec<Metric>,
+    normalize_options: NormalizeOptions,
+    lsh: Option<(usize, usize)>, // (num_hashes, bands)
+    token_mode: TokenMode,
+    transpose_cost: Option<f64>,
+    token_set_ratio: f64,
+    normalized_len_limit: Option<usize>,
+    truncated_records: AtomicUsize,
+    length_buckets: LengthBuckets,
+    brute_force_limit: usize,
+    rank_by: RankBy,
+    low_memory: bool,
+    original_source: Option<Box<dyn OriginalSource>>,
+    hash_seed: u64,
+    cluster_distance: Option<usize>,
+    profile_records: Option<usize>, // how many of the slowest records to keep, None disables profiling
+    preprocess_times: Mutex<Vec<(usize, Duration)>>,
+    disk: Option<DiskArenaWriter>,
+    disk_error: Mutex<Option<io::Error>>, // first failed write, returned by try_build
 }
 
 /// Main "index" of program, used for searching trigrams. Avoid "clone" at all cost.
 #[derive(Debug)]
 pub struct Indexer {
-    index: FxHashMap<[char; 3], Vec<usize>>,
+    index: FxHashMap<Gram, Vec<Posting>>,
+    phonetic_index: FxHashMap<u64, Vec<usize>>, // sorted by id, empty unless Metric::Phonetic is enabled
     storage: FxHashMap<usize, PreparedText>, // make index "freeze" and immutable after building to avoid locks
+    disk: Option<DiskArena>, // when set, normalized_vec in storage is empty and chars are read from here
+    empty_records: Vec<usize>, // sorted, collected unless the policy is Skip
+    empty_record_policy: EmptyRecordPolicy,
     min_trigram_match_ratio: f64,
+    max_distance_ratio: Option<f64>,
+    min_length_ratio: Option<f64>,
+    max_postings_per_trigram: Option<usize>,
+    min_distinct_trigrams: usize,
+    include_exact: bool,
+    metrics: Vec<Metric>,
+    normalize_options: NormalizeOptions,
+    lsh: Option<LshIndex>,
+    token_mode: TokenMode,
+    transpose_cost: Option<f64>,         // only used by Metric::Osa
+    token_set_ratio: f64,                // only used by Metric::TokenSet
+    normalized_len_limit: Option<usize>, // queries are cut like stored records
+    truncated_records: AtomicUsize,
+    length_buckets: LengthBuckets,
+    brute_force_limit: usize,
+    rank_by: RankBy,
+    low_memory: bool, // originals in storage are empty, read from original_source if any
+    original_source: Option<Box<dyn OriginalSource>>,
+    hash_seed: u64, // queries are hashed like stored records
+    cluster_distance: Option<usize>,
+    clusters: UnionFind, // twins within cluster_distance, kept current by insert
+    by_length: BTreeSet<(usize, usize)>, // (normalized_len, id), for the brute force fallback
+    slowest_records: Vec<(usize, Duration)>, // empty unless built with profiling
+    max_normalized_len: usize, // sizes the distance buffers so the hot loop does not reallocate
+    disk_error: Mutex<Option<io::Error>>, // first failed read, see take_disk_error
 }
 
 impl IndexBuilder {
     pub fn new(match_ratio: f64) -> Self {
         Self {
             index: DashMap::new(),
+            phonetic_index: DashMap::new(),
             storage: DashMap::new(),
+            empty_records: DashSet::new(),
+            empty_record_policy: EmptyRecordPolicy::default(),
             min_trigram_match_ratio: match_ratio.clamp(0.0, 1.0),
+            max_distance_ratio: None,
+            min_length_ratio: None,
+            max_postings_per_trigram: None,
+            min_distinct_trigrams: 1,
+            include_exact: true,
+            metrics: vec![Metric::Levenshtein],
+            normalize_options: NormalizeOptions::default(),
+            lsh: None,
+            token_mode: TokenMode::default(),
+            transpose_cost: None,
+            token_set_ratio: DEFAULT_TOKEN_SET_RATIO,
+            normalized_len_limit: None,
+            truncated_records: AtomicUsize::new(0),
+            length_buckets: LengthBuckets::default(),
+            brute_force_limit: DEFAULT_BRUTE_FORCE_LIMIT,
+            rank_by: RankBy::default(),
+            low_memory: false,
+            original_source: None,
+            hash_seed: DEFAULT_HASH_SEED,
+            cluster_distance: None,
+            profile_records: None,
+            preprocess_times: Mutex::new(Vec::new()),
+            disk: None,
+            disk_error: Mutex::new(None),
+        }
+    }
+
+    /// Cost of an adjacent transposition for `Metric::Osa` (default 1, at most 2), e.g. 0.5 makes swapped
+    /// letters cheaper than substitutions. Reported distances are the total cost rounded up.
+    pub fn with_transpose_cost(mut self, cost: f64) -> Self {
+        self.transpose_cost = Some(cost);
+        self
+    }
+
+    /// Min `token_set_match` similarity ratio of `Metric::TokenSet` pairs (default 0.9), the max distance
+    /// does not apply to them. With other metrics enabled a pair is kept when any metric accepts it,
+    /// e.g. Levenshtein and token set together find reworded and extended records in one pass.
+    pub fn with_token_set_ratio(mut self, min_ratio: f64) -> Self {
+        self.token_set_ratio = min_ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Keep only the first `max_len` chars of normalized texts, records and queries alike, so a few huge
+    /// fields cannot make distance computations arbitrarily slow. Distances are those of the prefixes,
+    /// trigrams still come from the whole text. See `Indexer::truncated_records`.
+    pub fn with_max_normalized_len(mut self, max_len: usize) -> Self {
+        self.normalized_len_limit = Some(max_len);
+        self
+    }
+
+    /// Do not keep original texts, search only needs the normalized ones. Cuts memory of large corpora,
+    /// `Indexer::original` returns None unless a source is set with `with_original_source`.
+    pub fn with_low_memory(mut self) -> Self {
+        self.low_memory = true;
+        self
+    }
+
+    /// Read original texts on demand from `source` instead of keeping them, implies `with_low_memory`.
+    pub fn with_original_source(mut self, source: Box<dyn OriginalSource>) -> Self {
+        self.low_memory = true;
+        self.original_source = Some(source);
+        self
+    }
+
+    /// Seed of the hash behind the exact twin shortcut, see `hash_chars`. Equal hashes are always
+    /// confirmed by comparing the texts, so the seed never changes results, only how often the
+    /// confirmation runs. Seed 1 makes texts with the same chars collide, which is useful in tests.
+    pub fn with_hash_seed(mut self, seed: u64) -> Self {
+        self.hash_seed = seed;
+        self
+    }
+
+    /// Keep clusters (connected twins within `max_distance`) with the index, computed at build time and
+    /// updated by `Indexer::insert`, see `Indexer::cluster_of`.
+    pub fn with_incremental_clusters(mut self, max_distance: usize) -> Self {
+        self.cluster_distance = Some(max_distance);
+        self
+    }
+
+    /// Order of the results of every search, `RankBy::Ratio` ranks by similarity ratio instead of raw distance.
+    pub fn with_rank_by(mut self, rank_by: RankBy) -> Self {
+        self.rank_by = rank_by;
+        self
+    }
+
+    /// When no other record shares a gram with a query (e.g. every record is shorter than a trigram),
+    /// search falls back to comparing the query with records of similar length directly, at most `limit`
+    /// of them per query, closest lengths first. 0 disables the fallback.
+    pub fn with_brute_force_limit(mut self, limit: usize) -> Self {
+        self.brute_force_limit = limit;
+        self
+    }
+
+    /// Times normalization and tokenization of every record, the `n` slowest are available from
+    /// `Indexer::slowest_records` after `build`. Helps to find outlier rows dominating indexing time.
+    pub fn with_profile_indexing(mut self, n: usize) -> Self {
+        self.profile_records = Some(n);
+        self
+    }
+
+    /// Index keys used for candidate generation, `TokenMode::Words(n)` is meant for document level dedup.
+    pub fn with_token_mode(mut self, mode: TokenMode) -> Self {
+        self.token_mode = mode;
+        self
+    }
+
+    /// Compute a MinHash signature (`num_hashes` values) of every record's trigram set at build time and
+    /// keep it in an LSH table of `bands` bands, used by `Indexer::search_lsh`. More bands give higher
+    /// recall and more candidates, the default trigram index is not affected.
+    pub fn with_lsh(mut self, num_hashes: usize, bands: usize) -> Self {
+        self.lsh = Some((num_hashes, bands));
+        self
+    }
+
+    pub fn with_empty_record_policy(mut self, policy: EmptyRecordPolicy) -> Self {
+        self.empty_record_policy = policy;
+        self
+    }
+
+    /// Metrics used to verify candidates, a candidate passing several of them is reported once per metric.
+    pub fn with_metrics(mut self, metrics: Vec<Metric>) -> Self {
+        if !metrics.is_empty() {
+            self.metrics = metrics;
         }
+        self
+    }
+
+    /// Pairs whose normalized lengths ratio (shorter / longer) is below this threshold are dropped,
+    /// a short and a long record within `max_distance` of each other are mostly coincidence.
+    pub fn with_min_length_ratio(mut self, ratio: f64) -> Self {
+        self.min_length_ratio = Some(ratio.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Bounds the work per query, each trigram contributes at most `cap` candidates (the ones with
+    /// the smallest ids). Twins reachable only through the cut part of long posting lists are missed,
+    /// so it trades recall for a predictable worst case.
+    pub fn with_max_postings_per_trigram(mut self, cap: usize) -> Self {
+        self.max_postings_per_trigram = Some(cap.max(1));
+        self
+    }
+
+    /// Candidates must share at least `k` different trigrams (grams) with the query, on top of the
+    /// `match_ratio` bound. A candidate overlapping only in one repeated trigram ("aaaaaa") is not
+    /// credited like one sharing several different ones. Queries with fewer than `k` grams need all of them.
+    pub fn with_min_distinct_trigrams(mut self, k: usize) -> Self {
+        self.min_distinct_trigrams = k.max(1);
+        self
+    }
+
+    /// When false, distance 0 twins (exact duplicates after normalization) are left out of search results.
+    pub fn with_include_exact(mut self, include_exact: bool) -> Self {
+        self.include_exact = include_exact;
+        self
+    }
+
+    /// Max distance per length range of the longer normalized text, replaces the `max_distance` of the search
+    /// for pairs in a bucket. The relative bound of `with_max_distance_ratio` still applies on top.
+    pub fn with_length_buckets(mut self, buckets: LengthBuckets) -> Self {
+        self.length_buckets = buckets;
+        self
+    }
+
+    /// Relative bound on distance (fraction of the longer normalized text), applied together with `max_distance`.
+    pub fn with_max_distance_ratio(mut self, ratio: f64) -> Self {
+        self.max_distance_ratio = Some(ratio.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Custom normalization rules, the same rules are applied to queries at search time.
+    pub fn with_normalize_options(mut self, options: NormalizeOptions) -> Self {
+        self.normalize_options = options;
+        self
     }
 
     pub fn bulk_add(&self, records: Vec<(usize, String)>) {
@@ -52,230 +515,1350 @@ impl IndexBuilder {
         });
     }
 
+    /// Index records as they arrive on a channel, returns once every sender is dropped.
+    /// Records are indexed in parallel, producers may keep sending while earlier ones are processed.
+    pub fn add_receiver(&self, rx: Receiver<(usize, String)>) {
+        rx.into_iter().par_bridge().for_each(|(id, text)| {
+            self.add_single(id, text);
+        });
+    }
+
     fn add_single(&self, id: usize, text: String) {
         if text.trim().is_empty() {
+            if self.empty_record_policy != EmptyRecordPolicy::Skip {
+                self.empty_records.insert(id);
+            }
             return;
         }
 
-        let mut tokens = tokenize(&text);
-        tokens.sort_unstable();
-        tokens.dedup();
+        let started = self.profile_records.map(|_| Instant::now());
+        let mut prepared = self.preparation().prepare(text);
+        if let Some(disk) = &self.disk {
+            if let Err(e) = disk.append(id, &prepared.normalized_vec) {
+                self.disk_error.lock().unwrap().get_or_insert(e);
+            }
+            prepared.normalized_vec = Vec::new();
+        }
+        if let Some(started) = started {
+            let elapsed = started.elapsed();
+            self.preprocess_times.lock().unwrap().push((id, elapsed));
+        }
 
-        let mut cleaned = String::new();
-        let mut sorted = String::new();
-        let mut ranges = Vec::new();
-        normalize(&text, &mut cleaned, &mut sorted, &mut ranges);
-
-        let normalized_vec: Vec<char> = sorted.chars().collect();
-        let normalized_len = normalized_vec.len();
-        let normalized_hash = hash_chars(&normalized_vec);
-
-        let trigrams = tokens.clone();
-
-        self.storage.insert(
-            id,
-            PreparedText {
-                original: text,
-                normalized_vec,
-                normalized_len,
-                normalized_hash,
-                trigrams,
-            },
-        );
+        for &key in &prepared.phonetic_keys {
+            self.phonetic_index.entry(key).or_default().push(id);
+        }
 
-        for token in tokens {
-            self.index.entry(token).or_insert_with(Vec::new).push(id);
+        #[cfg(not(feature = "positional"))]
+        for &token in &prepared.grams {
+            self.index.entry(token).or_default().push(id);
+        }
+
+        #[cfg(feature = "positional")]
+        for (&token, &position) in prepared.grams.iter().zip(&prepared.trigram_positions) {
+            self.index.entry(token).or_default().push((id, position));
+        }
+
+        self.storage.insert(id, prepared);
+    }
+
+    fn preparation(&self) -> Preparation<'_> {
+        Preparation {
+            normalize_options: &self.normalize_options,
+            token_mode: self.token_mode,
+            hash_seed: self.hash_seed,
+            phonetic: self.metrics.contains(&Metric::Phonetic),
+            keep_original: !self.low_memory,
+            len_limit: self.normalized_len_limit,
+            truncated: &self.truncated_records,
         }
     }
 
+    /// Keep normalized texts in a file at `path` instead of memory, written as records are added so they
+    /// never all sit in RAM. The inverted index stays in memory and texts are read per candidate during
+    /// search: slower search, much smaller memory footprint. Write errors are returned by `try_build`.
+    pub fn with_disk_storage(mut self, path: &Path) -> io::Result<Self> {
+        self.disk = Some(DiskArenaWriter::create(path)?);
+        Ok(self)
+    }
+
+    /// Same as `try_build`.
+    ///
+    /// # Panics
+    /// When the file of `with_disk_storage` could not be written.
     pub fn build(self) -> Indexer {
-        let index: FxHashMap<[char; 3], Vec<usize>> = self.index.into_iter().collect();
+        match self.try_build() {
+            Ok(indexer) => indexer,
+            Err(e) => panic!("building the index failed: {}", e),
+        }
+    }
+
+    pub fn try_build(self) -> Result<Indexer, BuildError> {
+        if let Some(e) = self.disk_error.into_inner().unwrap() {
+            return Err(BuildError::Disk(e));
+        }
+        let disk = self.disk.map(DiskArenaWriter::finish).transpose()?;
+
+        let mut index: FxHashMap<Gram, Vec<Posting>> = self.index.into_iter().collect();
+        // parallel inserts leave posting lists in random order
+        index
+            .par_iter_mut()
+            .for_each(|(_, postings)| postings.sort_unstable_by_key(posting_id));
+
+        let mut phonetic_index: FxHashMap<u64, Vec<usize>> =
+            self.phonetic_index.into_iter().collect();
+        phonetic_index
+            .par_iter_mut()
+            .for_each(|(_, ids)| ids.sort_unstable());
+
         let storage: FxHashMap<usize, PreparedText> = self.storage.into_iter().collect();
 
-        Indexer {
+        let lsh = self.lsh.map(|(num_hashes, bands)| {
+            let mut lsh = LshIndex::new(num_hashes, bands);
+            let signatures: Vec<(usize, Vec<u64>)> = storage
+                .par_iter()
+                .map(|(&id, prepared)| (id, lsh.signature(&prepared.grams)))
+                .collect();
+            for (id, signature) in signatures {
+                lsh.insert(id, &signature);
+            }
+            lsh
+        });
+
+        let mut empty_records: Vec<usize> = self.empty_records.into_iter().collect();
+        empty_records.sort_unstable();
+        if self.empty_record_policy == EmptyRecordPolicy::Error && !empty_records.is_empty() {
+            return Err(BuildError::EmptyRecords(empty_records));
+        }
+
+        let mut slowest_records = self.preprocess_times.into_inner().unwrap();
+        slowest_records.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        slowest_records.truncate(self.profile_records.unwrap_or(0));
+
+        let by_length: BTreeSet<(usize, usize)> = storage
+            .iter()
+            .map(|(&id, prepared)| (prepared.normalized_len, id))
+            .collect();
+
+        let max_normalized_len = storage
+            .values()
+            .map(|prepared| prepared.normalized_len)
+            .max()
+            .unwrap_or(0);
+
+        let mut indexer = Indexer {
             index,
+            phonetic_index,
             storage,
+            disk,
+            empty_records,
+            empty_record_policy: self.empty_record_policy,
             min_trigram_match_ratio: self.min_trigram_match_ratio,
+            max_distance_ratio: self.max_distance_ratio,
+            min_length_ratio: self.min_length_ratio,
+            max_postings_per_trigram: self.max_postings_per_trigram,
+            min_distinct_trigrams: self.min_distinct_trigrams,
+            include_exact: self.include_exact,
+            metrics: self.metrics,
+            normalize_options: self.normalize_options,
+            lsh,
+            token_mode: self.token_mode,
+            transpose_cost: self.transpose_cost,
+            token_set_ratio: self.token_set_ratio,
+            normalized_len_limit: self.normalized_len_limit,
+            truncated_records: self.truncated_records,
+            length_buckets: self.length_buckets,
+            brute_force_limit: self.brute_force_limit,
+            rank_by: self.rank_by,
+            low_memory: self.low_memory,
+            original_source: self.original_source,
+            hash_seed: self.hash_seed,
+            cluster_distance: self.cluster_distance,
+            clusters: UnionFind::new(),
+            by_length,
+            slowest_records,
+            max_normalized_len,
+            disk_error: Mutex::new(None),
+        };
+
+        if let Some(max_distance) = indexer.cluster_distance {
+            let mut ids: Vec<usize> = indexer.storage.keys().copied().collect();
+            ids.extend_from_slice(&indexer.empty_records);
+            let pairs: Vec<(usize, usize)> = ids
+                .par_iter()
+                .flat_map_iter(|&id| {
+                    indexer
+                        .search_by_id_unsorted(id, max_distance)
+                        .into_iter()
+                        .map(move |twin| (id, twin.id))
+                })
+                .collect();
+            for (a, b) in pairs {
+                indexer.clusters.union(a, b);
+            }
         }
+
+        Ok(indexer)
     }
 }
 
-impl Indexer {
-    pub fn search_by_id(&self, query_id: usize, max_distance: usize) -> Vec<SearchResult> {
-        let query = match self.storage.get(&query_id) {
-            Some(q) => q,
-            None => return vec![],
+/// Settings turning a text into a `PreparedText`, shared by `IndexBuilder` and `Indexer::insert`.
+struct Preparation<'a> {
+    normalize_options: &'a NormalizeOptions,
+    token_mode: TokenMode,
+    hash_seed: u64,
+    phonetic: bool,
+    keep_original: bool,
+    len_limit: Option<usize>,
+    truncated: &'a AtomicUsize, // counts the records cut to len_limit
+}
+
+impl Preparation<'_> {
+    fn prepare(&self, text: String) -> PreparedText {
+        let mut cleaned = String::new();
+        let mut sorted = String::new();
+        let mut ranges = Vec::new();
+        normalize_with(
+            &text,
+            self.normalize_options,
+            &mut cleaned,
+            &mut sorted,
+            &mut ranges,
+        );
+
+        // grams come from the cleaned text, so char_map rules also affect candidate generation
+        let grams = grams(&cleaned, &mut ranges, self.token_mode);
+
+        #[cfg(not(feature = "positional"))]
+        let grams = {
+            let mut grams = grams;
+            grams.sort_unstable();
+            grams.dedup();
+            grams
         };
 
-        let q_chars = &query.normalized_vec;
-        let q_len = query.normalized_len;
-        let q_hash = query.normalized_hash;
-        let tokens = &query.trigrams;
+        #[cfg(feature = "positional")]
+        let (grams, trigram_positions) = positioned_grams(grams);
 
-        if tokens.is_empty() {
-            return vec![];
+        let mut normalized_vec: Vec<char> = sorted.chars().collect();
+        if let Some(limit) = self.len_limit
+            && normalized_vec.len() > limit
+        {
+            normalized_vec.truncate(limit);
+            self.truncated.fetch_add(1, Ordering::Relaxed);
         }
+        let phonetic_keys = if self.phonetic {
+            phonetic_keys(sorted.split_whitespace())
+        } else {
+            Vec::new()
+        };
 
-        let mut candidates: FxHashMap<usize, usize> = FxHashMap::default();
-        for token in tokens {
-            if let Some(ids) = self.index.get(token) {
-                for &id in ids {
-                    if id > query_id {
-                        *candidates.entry(id).or_insert(0) += 1;
-                    }
-                }
-            }
+        PreparedText {
+            original: if self.keep_original {
+                text
+            } else {
+                String::new()
+            },
+            normalized_len: normalized_vec.len(),
+            normalized_hash: hash_chars(&normalized_vec, self.hash_seed),
+            normalized_vec,
+            grams,
+            phonetic_keys,
+            #[cfg(feature = "positional")]
+            trigram_positions,
         }
+    }
+}
 
-        let min_matches = (tokens.len() as f64 * self.min_trigram_match_ratio).ceil() as usize;
-        let min_matches = std::cmp::max(1, min_matches);
-
-        let mut bufs = DistanceBuffers::new();
-        let mut results = Vec::new();
+/// Normalized form of a query, borrowed from storage or prepared from the text at search time.
+struct QueryText<'a> {
+    chars: &'a [char],
+    len: usize,
+    hash: u64,
+    tokens: &'a [Gram],
+    phonetic_keys: &'a [u64],
+}
 
-        for (id, matches) in candidates {
-            if matches >= min_matches {
-                if let Some(prepared) = self.storage.get(&id) {
+/// Counters of a single search, see `Indexer::search_by_id_instrumented`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SearchStats {
+    /// records that passed the trigram prefilter (`min_trigram_match_ratio`)
+    pub candidates: usize,
+    /// candidates rejected by the length or distance check
+    pub rejected: usize,
+    /// calls to the distance function, exact twins found by the hash shortcut are not counted
+    pub distance_computations: usize,
+}
 
-                    // Fast pre-filter: length difference > max_distance - impossible match
-                    if q_len.abs_diff(prepared.normalized_len) > max_distance {
-                        continue;
-                    }
-                    
-                    // trying to avoid costly calculations 
-                    if q_len == prepared.normalized_len && q_hash == prepared.normalized_hash {
-                        // avoid hash collision - very rare but possible, so we double check with actual chars
-                        if q_chars == &prepared.normalized_vec {
-                            results.push(SearchResult { id, distance: 0 });
-                            continue;
-                        }
-                    }
+impl Indexer {
+    /// Indexed records, empty ones included.
+    pub fn len(&self) -> usize {
+        self.storage.len() + self.empty_records.len()
+    }
 
-                    let dist = levenshtein_distance_raw(
-                        q_chars,
-                        &prepared.normalized_vec,
-                        max_distance,
-                        &mut bufs,
-                    );
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-                    if dist <= max_distance {
-                        results.push(SearchResult { id, distance: dist });
-                    }
-                }
-            }
+    /// Sorted ids of the searchable records, empty ones only with `EmptyRecordPolicy::Index`.
+    pub fn ids(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self.storage.keys().copied().collect();
+        if self.empty_record_policy == EmptyRecordPolicy::Index {
+            ids.extend_from_slice(&self.empty_records);
         }
-
-        results.sort_unstable_by_key(|r| r.distance);
-        results
+        ids.sort_unstable();
+        ids
     }
 
-    pub fn search(&self, query: &str, max_distance: usize) -> Vec<SearchResult> {
-        let mut q_cleaned = String::new();
-        let mut q_sorted = String::new();
-        let mut q_ranges = Vec::new();
+    /// Ids of records with empty text, empty unless built with a policy other than `EmptyRecordPolicy::Skip`.
+    pub fn empty_records(&self) -> &[usize] {
+        &self.empty_records
+    }
 
-        normalize(query, &mut q_cleaned, &mut q_sorted, &mut q_ranges);
+    /// Records that took longest to normalize and tokenize with their time, slowest first.
+    /// Empty unless built with `IndexBuilder::with_profile_indexing`.
+    pub fn slowest_records(&self) -> &[(usize, Duration)] {
+        &self.slowest_records
+    }
 
-        let q_chars: Vec<char> = q_sorted.chars().collect();
-        let q_len = q_chars.len();
-        let q_hash = hash_chars(&q_chars);
+    /// First disk storage read that failed since the last call, the records it concerned were skipped
+    /// by the searches. Callers of indexes made `with_disk_storage` should check it after searching.
+    pub fn take_disk_error(&self) -> Option<io::Error> {
+        self.disk_error.lock().unwrap().take()
+    }
 
-        let mut tokens = tokenize(query);
+    /// Records whose normalized text was cut by `IndexBuilder::with_max_normalized_len`, inserts included.
+    pub fn truncated_records(&self) -> usize {
+        self.truncated_records.load(Ordering::Relaxed)
+    }
 
-        if tokens.is_empty() {
-            return vec![];
+    /// Original (not normalized) text of an indexed record. A low memory index reads it from its
+    /// original source, without one it is not known.
+    pub fn original(&self, id: usize) -> Option<Cow<'_, str>> {
+        let prepared = self.storage.get(&id)?;
+        match &self.original_source {
+            Some(source) => source.original(id).map(Cow::Owned),
+            None if self.low_memory => None,
+            None => Some(Cow::Borrowed(prepared.original.as_str())),
         }
+    }
 
-        tokens.sort_unstable();
-        tokens.dedup();
+    /// Normalized text of an indexed record, exactly what the distance functions see.
+    pub fn normalized_text(&self, id: usize) -> Option<String> {
+        let prepared = self.storage.get(&id)?;
+        let mut buf = Vec::new();
+        self.stored_chars(id, prepared, &mut buf)
+            .map(|chars| chars.iter().collect())
+    }
 
-        let mut candidates: FxHashMap<usize, usize> = FxHashMap::default();
-        for token in &tokens {
-            if let Some(ids) = self.index.get(token) {
-                for &id in ids {
-                    *candidates.entry(id).or_insert(0) += 1;
+    /// Length in chars of the normalized text, what distances are relative to.
+    pub fn normalized_len(&self, id: usize) -> Option<usize> {
+        self.storage
+            .get(&id)
+            .map(|prepared| prepared.normalized_len)
+    }
+
+    /// How distinctive the grams two records share are, in [0, 1]: the mean IDF (ln of records / records
+    /// containing the gram) of the shared grams relative to the largest possible IDF. 0 without shared grams.
+    pub fn overlap_rarity(&self, a: usize, b: usize) -> Option<f64> {
+        let (a, b) = (self.storage.get(&a)?, self.storage.get(&b)?);
+        let max_idf = (self.storage.len() as f64).ln();
+        if max_idf <= 0.0 {
+            return Some(0.0);
+        }
+
+        // grams are sorted and deduplicated
+        let (mut i, mut j) = (0, 0);
+        let (mut shared, mut idf_sum) = (0usize, 0.0);
+        while i < a.grams.len() && j < b.grams.len() {
+            match a.grams[i].cmp(&b.grams[j]) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => {
+                    let df = self.index.get(&a.grams[i]).map_or(1, Vec::len).max(1);
+                    idf_sum += (self.storage.len() as f64 / df as f64).ln();
+                    shared += 1;
+                    i += 1;
+                    j += 1;
                 }
             }
         }
 
-        let min_matches = (tokens.len() as f64 * self.min_trigram_match_ratio).ceil() as usize;
-        let min_matches = std::cmp::max(1, min_matches);
-
-        let mut bufs = DistanceBuffers::new();
-        let mut results = Vec::new();
-
-        for (id, matches) in candidates {
-            if matches >= min_matches {
-                if let Some(prepared) = self.storage.get(&id) {
-                    if q_len.abs_diff(prepared.normalized_len) > max_distance {
-                        continue;
-                    }
+        if shared == 0 {
+            return Some(0.0);
+        }
+        Some((idf_sum / shared as f64 / max_idf).clamp(0.0, 1.0))
+    }
 
-                    if q_len == prepared.normalized_len && q_hash == prepared.normalized_hash {
-                        if q_chars.as_slice() == prepared.normalized_vec.as_slice() {
-                            results.push(SearchResult { id, distance: 0 });
-                            continue;
-                        }
-                    }
+    /// Add a record to a built index, e.g. when records arrive as a stream. Returns false, and changes
+    /// nothing, when the id is already indexed or the text is empty under `EmptyRecordPolicy::Error`.
+    /// Ids greater than every indexed one are appended to the posting lists, a smaller id has to shift
+    /// the lists it joins. With `IndexBuilder::with_incremental_clusters` the record
+    /// joins the clusters of its twins, merging them when it bridges several.
+    pub fn insert(&mut self, id: usize, text: String) -> bool {
+        if self.storage.contains_key(&id) || self.empty_records.binary_search(&id).is_ok() {
+            return false;
+        }
 
-                    let dist = levenshtein_distance_raw(
-                        &q_chars,
-                        &prepared.normalized_vec,
-                        max_distance,
-                        &mut bufs,
-                    );
+        if text.trim().is_empty() {
+            if self.empty_record_policy == EmptyRecordPolicy::Error {
+                return false;
+            }
+            if self.empty_record_policy != EmptyRecordPolicy::Skip {
+                let at = self.empty_records.partition_point(|&other| other < id);
+                self.empty_records.insert(at, id);
+            }
+        } else {
+            let prepared = self.preparation().prepare(text);
+
+            // posting lists and phonetic ids stay sorted as after `build`, ids arriving in ascending
+            // order are appended, only a smaller id shifts the lists it joins
+            for &key in &prepared.phonetic_keys {
+                insert_sorted(self.phonetic_index.entry(key).or_default(), id, |&other| {
+                    other
+                });
+            }
 
-                    if dist <= max_distance {
-                        results.push(SearchResult { id, distance: dist });
-                    }
-                }
+            #[cfg(not(feature = "positional"))]
+            let postings = prepared.grams.iter().map(|&token| (token, id));
+            #[cfg(feature = "positional")]
+            let postings = prepared
+                .grams
+                .iter()
+                .zip(&prepared.trigram_positions)
+                .map(|(&token, &position)| (token, (id, position)));
+            for (token, posting) in postings {
+                insert_sorted(self.index.entry(token).or_default(), posting, posting_id);
             }
-        }
 
-        results.sort_unstable_by_key(|r| r.distance);
-        results
-    }
-}
+            if let Some(lsh) = &mut self.lsh {
+                let signature = lsh.signature(&prepared.grams);
+                lsh.insert(id, &signature);
+            }
 
-pub fn tokenize(text: &str) -> Vec<[char; 3]> {
-    let mut trigrams = Vec::new();
-    let mut window = ['\0'; 3];
-    let mut current_word_len = 0;
-    let mut chars_processed = 0;
+            self.by_length.insert((prepared.normalized_len, id));
+            self.max_normalized_len = self.max_normalized_len.max(prepared.normalized_len);
 
-    for c in text.chars().flat_map(|c| c.to_lowercase()) {
-        if chars_processed >= 10_000 {
-            break;
+            self.storage.insert(id, prepared);
         }
-        chars_processed += 1;
-
-        if c.is_alphanumeric() {
-            window[0] = window[1];
-            window[1] = window[2];
-            window[2] = c;
-            current_word_len += 1;
 
-            if current_word_len >= 3 {
-                trigrams.push([window[0], window[1], window[2]]);
+        if let Some(max_distance) = self.cluster_distance {
+            for twin in self.neighbors(id, max_distance) {
+                self.clusters.union(id, twin.id);
             }
-        } else {
-            current_word_len = 0;
         }
+        true
     }
 
-    trigrams
-}
-
-fn hash_chars(chars: &[char]) -> u64 {
-    let mut hasher = FxHasher::default();
-    for &c in chars {
-        hasher.write_u32(c as u32);
+    /// Records are prepared with the settings of the builder.
+    fn preparation(&self) -> Preparation<'_> {
+        Preparation {
+            normalize_options: &self.normalize_options,
+            token_mode: self.token_mode,
+            hash_seed: self.hash_seed,
+            phonetic: self.metrics.contains(&Metric::Phonetic),
+            keep_original: !self.low_memory,
+            len_limit: self.normalized_len_limit,
+            truncated: &self.truncated_records,
+        }
     }
-    hasher.finish()
-}
-
 
+    /// Representative of the cluster of an indexed record, records share it only when they are connected
+    /// by twins. The representative may change when `insert` merges clusters, so compare, don't store it.
+    /// None for unknown ids or without `IndexBuilder::with_incremental_clusters`.
+    pub fn cluster_of(&self, id: usize) -> Option<usize> {
+        self.cluster_distance?;
+        if !self.storage.contains_key(&id) && self.empty_records.binary_search(&id).is_err() {
+            return None;
+        }
+        Some(self.clusters.root(id))
+    }
 
+    /// Trigrams two indexed records share, what made them candidates of each other, in packed order.
+    /// Empty for unknown ids and with word shingles, shingle hashes cannot be turned back into text.
+    /// CJK bigrams and unigrams are padded with `'\0'`, see `tokenize_cjk`.
+    pub fn shared_trigrams_between(&self, query_id: usize, twin_id: usize) -> Vec<[char; 3]> {
+        let (Some(query), Some(twin)) = (self.storage.get(&query_id), self.storage.get(&twin_id))
+        else {
+            return Vec::new();
+        };
+        if let TokenMode::Words(_) = self.token_mode {
+            return Vec::new();
+        }
+
+        // grams are sorted and deduplicated at build time
+        let mut shared = Vec::new();
+        let (mut a, mut b) = (query.grams.iter().peekable(), twin.grams.iter().peekable());
+        while let (Some(&&x), Some(&&y)) = (a.peek(), b.peek()) {
+            match x.cmp(&y) {
+                std::cmp::Ordering::Less => {
+                    a.next();
+                }
+                std::cmp::Ordering::Greater => {
+                    b.next();
+                }
+                std::cmp::Ordering::Equal => {
+                    shared.push(unpack_trigram(x));
+                    a.next();
+                    b.next();
+                }
+            }
+        }
+        shared
+    }
+
+    pub fn search_by_id(&self, query_id: usize, max_distance: usize) -> Vec<SearchResult> {
+        self.search_by_id_instrumented(query_id, max_distance).0
+    }
+
+    /// Same as `search_by_id`, also returns how many candidates passed the trigram prefilter
+    /// and how many of them the distance check rejected, useful to tune `min_trigram_match_ratio`.
+    pub fn search_by_id_instrumented(
+        &self,
+        query_id: usize,
+        max_distance: usize,
+    ) -> (Vec<SearchResult>, SearchStats) {
+        let (mut results, stats) = self.search_stored(query_id, max_distance, Some(query_id));
+        self.rank(&mut results, self.normalized_len(query_id).unwrap_or(0));
+        (results, stats)
+    }
+
+    /// Same as `search_by_id` in candidate order, for batch callers that sort all pairs once anyway.
+    pub fn search_by_id_unsorted(&self, query_id: usize, max_distance: usize) -> Vec<SearchResult> {
+        self.search_stored(query_id, max_distance, Some(query_id)).0
+    }
+
+    /// All twins of an indexed record, not only those with greater ids as in `search_by_id`.
+    /// Meant for querying a subset of ids (e.g. newly added records) against the whole index.
+    pub fn neighbors(&self, query_id: usize, max_distance: usize) -> Vec<SearchResult> {
+        self.search_by_id_with(query_id, max_distance, false)
+    }
+
+    /// Search with a stored record as the query. With `only_greater_ids` only twins with ids greater
+    /// than `query_id` are reported (as in `search_by_id`, every pair is found once when enumerating all
+    /// records), without it every twin except the record itself (as in `neighbors`).
+    pub fn search_by_id_with(
+        &self,
+        query_id: usize,
+        max_distance: usize,
+        only_greater_ids: bool,
+    ) -> Vec<SearchResult> {
+        let (mut results, _) =
+            self.search_by_id_with_stats(query_id, max_distance, only_greater_ids);
+        self.rank(&mut results, self.normalized_len(query_id).unwrap_or(0));
+        results
+    }
+
+    /// `search_by_id_with` in candidate order, with the counters of the search for batch callers
+    /// that sort all pairs once and total the counters.
+    pub fn search_by_id_with_stats(
+        &self,
+        query_id: usize,
+        max_distance: usize,
+        only_greater_ids: bool,
+    ) -> (Vec<SearchResult>, SearchStats) {
+        let after = only_greater_ids.then_some(query_id);
+        let (mut results, stats) = self.search_stored(query_id, max_distance, after);
+        results.retain(|r| r.id != query_id);
+        (results, stats)
+    }
+
+    /// Search with a stored record as the query, only ids greater than `after` are reported. Unsorted.
+    fn search_stored(
+        &self,
+        query_id: usize,
+        max_distance: usize,
+        after: Option<usize>,
+    ) -> (Vec<SearchResult>, SearchStats) {
+        let mut stats = SearchStats::default();
+
+        let query = match self.storage.get(&query_id) {
+            Some(q) => q,
+            None => {
+                if self.empty_records.binary_search(&query_id).is_ok() {
+                    let accept = |id| id != query_id && after.is_none_or(|after| id > after);
+                    return (self.empty_twins(accept), stats);
+                }
+                return (vec![], stats);
+            }
+        };
+
+        let mut query_buf = Vec::new();
+        let query = QueryText {
+            chars: match self.stored_chars(query_id, query, &mut query_buf) {
+                Some(chars) => chars,
+                None => return (vec![], stats),
+            },
+            len: query.normalized_len,
+            hash: query.normalized_hash,
+            tokens: &query.grams,
+            phonetic_keys: &query.phonetic_keys,
+        };
+
+        let mut candidates = self.collect_candidates(query.tokens, after);
+        self.add_phonetic_candidates(query.phonetic_keys, after, &mut candidates);
+        candidates.remove(&query_id);
+
+        if candidates.is_empty() && !self.shares_grams(query.tokens, Some(query_id)) {
+            candidates = self.brute_force_candidates(query.len, max_distance, after);
+            candidates.remove(&query_id);
+        }
+
+        let results = self.verify_candidates(&query, candidates, max_distance, &mut stats);
+        (results, stats)
+    }
+
+    pub fn search(&self, query: &str, max_distance: usize) -> Vec<SearchResult> {
+        self.search_with_stats(query, max_distance).0
+    }
+
+    /// `search` with the counters of the search.
+    pub fn search_with_stats(
+        &self,
+        query: &str,
+        max_distance: usize,
+    ) -> (Vec<SearchResult>, SearchStats) {
+        let mut stats = SearchStats::default();
+        if query.trim().is_empty() {
+            return (self.empty_twins(|_| true), stats);
+        }
+
+        let (q_chars, tokens) = self.prepare_query(query);
+        let keys = self.query_phonetic_keys(&q_chars);
+
+        let query = QueryText {
+            chars: &q_chars,
+            len: q_chars.len(),
+            hash: hash_chars(&q_chars, self.hash_seed),
+            tokens: &tokens,
+            phonetic_keys: &keys,
+        };
+
+        let mut candidates = self.collect_candidates(query.tokens, None);
+        self.add_phonetic_candidates(query.phonetic_keys, None, &mut candidates);
+        if candidates.is_empty() && !self.shares_grams(query.tokens, None) {
+            candidates = self.brute_force_candidates(query.len, max_distance, None);
+        }
+
+        let mut results = self.verify_candidates(&query, candidates, max_distance, &mut stats);
+        self.rank(&mut results, q_chars.len());
+        (results, stats)
+    }
+
+    /// Top-1 lookup for spell correction when the index is a dictionary: id and distance of the closest
+    /// record, ties go to the shorter record, then the smaller id. None when nothing is within `max_distance`.
+    pub fn correct(&self, word: &str, max_distance: usize) -> Option<(usize, usize)> {
+        self.search(word, max_distance)
+            .into_iter()
+            .map(|r| {
+                let len = self.normalized_len(r.id).unwrap_or(0);
+                (r.distance, len, r.id)
+            })
+            .min()
+            .map(|(distance, _, id)| (id, distance))
+    }
+
+    /// Type-ahead search, the query is compared only with the leading part of each record's normalized
+    /// text (cut to the query length), so "hel" matches "hello world" at distance 0.
+    /// Keep in mind the normalized text is token sorted, the prefix of "world hello" is "hello".
+    /// Only the edit distance metrics (Levenshtein, OSA) are used, token sets and phonetic codes of a
+    /// cut off prefix mean nothing; an index without either searches prefixes with Levenshtein.
+    pub fn search_prefix(&self, query: &str, max_distance: usize) -> Vec<SearchResult> {
+        let (q_chars, tokens) = self.prepare_query(query);
+
+        if tokens.is_empty() {
+            return vec![];
+        }
+
+        let mut metrics: Vec<Metric> = self
+            .metrics
+            .iter()
+            .copied()
+            .filter(|metric| matches!(metric, Metric::Levenshtein | Metric::Osa))
+            .collect();
+        if metrics.is_empty() {
+            metrics.push(Metric::Levenshtein);
+        }
+
+        let candidates = self.collect_candidates(&tokens, None);
+
+        let mut candidate_buf = Vec::new();
+        let mut results = Vec::new();
+
+        self.with_distance_buffers(|bufs| {
+            for id in candidates.into_keys() {
+                let prepared = match self.storage.get(&id) {
+                    Some(prepared) => prepared,
+                    None => continue,
+                };
+
+                // shorter records can still match when the missing tail fits in max_distance
+                if prepared.normalized_len + max_distance < q_chars.len() {
+                    continue;
+                }
+
+                let candidate_chars = match self.stored_chars(id, prepared, &mut candidate_buf) {
+                    Some(chars) => chars,
+                    None => continue,
+                };
+                let prefix = &candidate_chars[..candidate_chars.len().min(q_chars.len())];
+
+                for &metric in &metrics {
+                    let dist = self.distance(metric, &q_chars, prefix, max_distance, bufs);
+                    if dist <= max_distance && (dist > 0 || self.include_exact) {
+                        results.push(SearchResult {
+                            id,
+                            distance: dist,
+                            metric,
+                        });
+                    }
+                }
+            }
+        });
+
+        self.rank(&mut results, q_chars.len());
+        results
+    }
+
+    /// Probable candidates of a query from the LSH bands, sorted, unverified. Empty unless the index
+    /// was built with `IndexBuilder::with_lsh`.
+    pub fn lsh_candidates(&self, query: &str) -> Vec<usize> {
+        let lsh = match &self.lsh {
+            Some(lsh) => lsh,
+            None => return vec![],
+        };
+
+        let (_, tokens) = self.prepare_query(query);
+        if tokens.is_empty() {
+            return vec![];
+        }
+
+        lsh.candidates(&lsh.signature(&tokens))
+    }
+
+    /// Same as `search`, but candidates come from the LSH bands instead of counting shared trigrams,
+    /// work per query does not grow with posting list lengths. Some twins may be missed.
+    pub fn search_lsh(&self, query: &str, max_distance: usize) -> Vec<SearchResult> {
+        let (q_chars, tokens) = self.prepare_query(query);
+
+        let lsh = match &self.lsh {
+            Some(lsh) if !tokens.is_empty() => lsh,
+            _ => return vec![],
+        };
+
+        let keys = self.query_phonetic_keys(&q_chars);
+        let query = QueryText {
+            chars: &q_chars,
+            len: q_chars.len(),
+            hash: hash_chars(&q_chars, self.hash_seed),
+            tokens: &tokens,
+            phonetic_keys: &keys,
+        };
+
+        let candidates = lsh
+            .candidates(&lsh.signature(&tokens))
+            .into_iter()
+            .map(|id| (id, 1))
+            .collect();
+
+        let mut results = self.verify_candidates(
+            &query,
+            candidates,
+            max_distance,
+            &mut SearchStats::default(),
+        );
+        self.rank(&mut results, q_chars.len());
+        results
+    }
+
+    /// Search records that are not part of this index (e.g. input records against an indexed blocklist).
+    /// Returns (record id, matched indexed entry) for every match.
+    pub fn search_against(
+        &self,
+        records: &[(usize, String)],
+        max_distance: usize,
+    ) -> Vec<(usize, SearchResult)> {
+        self.search_against_with_stats(records, max_distance).0
+    }
+
+    /// `search_against` with the counters of all searches totalled.
+    pub fn search_against_with_stats(
+        &self,
+        records: &[(usize, String)],
+        max_distance: usize,
+    ) -> (Vec<(usize, SearchResult)>, SearchStats) {
+        records
+            .par_iter()
+            .map(|(record_id, text)| {
+                let (results, stats) = self.search_with_stats(text, max_distance);
+                let results: Vec<(usize, SearchResult)> = results
+                    .into_iter()
+                    .map(|result| (*record_id, result))
+                    .collect();
+                (results, stats)
+            })
+            .reduce(
+                || (Vec::new(), SearchStats::default()),
+                |(mut results, mut stats), (more, more_stats)| {
+                    results.extend(more);
+                    stats.candidates += more_stats.candidates;
+                    stats.rejected += more_stats.rejected;
+                    stats.distance_computations += more_stats.distance_computations;
+                    (results, stats)
+                },
+            )
+    }
+
+    /// Normalized chars and deduplicated grams of a query text, prepared the same way as indexed records.
+    fn prepare_query(&self, query: &str) -> (Vec<char>, Vec<Gram>) {
+        let mut q_cleaned = String::new();
+        let mut q_sorted = String::new();
+        let mut q_ranges = Vec::new();
+
+        normalize_with(
+            query,
+            &self.normalize_options,
+            &mut q_cleaned,
+            &mut q_sorted,
+            &mut q_ranges,
+        );
+
+        let mut tokens = grams(&q_cleaned, &mut q_ranges, self.token_mode);
+        tokens.sort_unstable();
+        tokens.dedup();
+
+        let mut q_chars: Vec<char> = q_sorted.chars().collect();
+        if let Some(limit) = self.normalized_len_limit {
+            q_chars.truncate(limit);
+        }
+        (q_chars, tokens)
+    }
+
+    fn query_phonetic_keys(&self, normalized: &[char]) -> Vec<u64> {
+        if !self.metrics.contains(&Metric::Phonetic) {
+            return vec![];
+        }
+        let text: String = normalized.iter().collect();
+        phonetic_keys(text.split_whitespace())
+    }
+
+    /// Records sharing a phonetic key are candidates regardless of shared trigrams.
+    fn add_phonetic_candidates(
+        &self,
+        keys: &[u64],
+        after: Option<usize>,
+        candidates: &mut FxHashMap<usize, usize>,
+    ) {
+        for key in keys {
+            if let Some(ids) = self.phonetic_index.get(key) {
+                let start = match after {
+                    Some(after) => ids.partition_point(|&id| id <= after),
+                    None => 0,
+                };
+                for &id in &ids[start..] {
+                    candidates.entry(id).or_insert(0);
+                }
+            }
+        }
+    }
+
+    /// Indexed empty records are exact twins of an empty query, only with `EmptyRecordPolicy::Index`.
+    fn empty_twins(&self, accept: impl Fn(usize) -> bool) -> Vec<SearchResult> {
+        if self.empty_record_policy != EmptyRecordPolicy::Index || !self.include_exact {
+            return vec![];
+        }
+
+        self.empty_records
+            .iter()
+            .copied()
+            .filter(|&id| accept(id))
+            .flat_map(|id| {
+                self.metrics.iter().map(move |&metric| SearchResult {
+                    id,
+                    distance: 0,
+                    metric,
+                })
+            })
+            .collect()
+    }
+
+    /// Count shared trigrams per record and keep only records passing `min_trigram_match_ratio`.
+    /// Only ids greater than `after` are counted (all-pairs enumeration visits every pair once).
+    fn collect_candidates(&self, tokens: &[Gram], after: Option<usize>) -> FxHashMap<usize, usize> {
+        let mut candidates: FxHashMap<usize, usize> = FxHashMap::default();
+        for token in tokens {
+            if let Some(postings) = self.index.get(token) {
+                // posting lists are sorted by id, so the skipped part is found by binary search
+                let start = match after {
+                    Some(after) => postings.partition_point(|p| posting_id(p) <= after),
+                    None => 0,
+                };
+                let end = match self.max_postings_per_trigram {
+                    Some(cap) => postings.len().min(start + cap),
+                    None => postings.len(),
+                };
+
+                for id in postings[start..end].iter().map(posting_id) {
+                    *candidates.entry(id).or_insert(0) += 1;
+                }
+            }
+        }
+
+        // grams are deduplicated on both sides, so a count is the number of distinct shared grams
+        let min_matches = (tokens.len() as f64 * self.min_trigram_match_ratio).ceil() as usize;
+        let min_matches = min_matches.max(self.min_distinct_trigrams.min(tokens.len()).max(1));
+
+        candidates.retain(|_, matches| *matches >= min_matches);
+        candidates
+    }
+
+    /// Order of the twins of a query, see `RankBy`.
+    fn rank(&self, results: &mut [SearchResult], q_len: usize) {
+        match self.rank_by {
+            RankBy::Distance => results.sort_unstable_by_key(|r| r.distance),
+            RankBy::Ratio => {
+                let ratio = |r: &SearchResult| {
+                    similarity_ratio(r.distance, q_len, self.normalized_len(r.id).unwrap_or(0))
+                };
+                results.sort_unstable_by(|a, b| {
+                    ratio(b)
+                        .total_cmp(&ratio(a))
+                        .then(a.distance.cmp(&b.distance))
+                        .then(a.id.cmp(&b.id))
+                });
+            }
+        }
+    }
+
+    /// Whether any record other than `query_id` has one of the grams, if not the inverted index can not
+    /// produce candidates for the query at any match ratio.
+    fn shares_grams(&self, tokens: &[Gram], query_id: Option<usize>) -> bool {
+        tokens.iter().any(|token| {
+            self.index
+                .get(token)
+                .is_some_and(|postings| postings.iter().any(|p| Some(posting_id(p)) != query_id))
+        })
+    }
+
+    /// Fallback candidates for a query the inverted index has nothing for: records whose length is within
+    /// the distance bound, closest lengths first. At most `brute_force_limit` candidates are taken,
+    /// walking `by_length` outwards from the query length, so the cost per query is bounded too. Ids not
+    /// greater than `after` are skipped before they count against the limit.
+    fn brute_force_candidates(
+        &self,
+        q_len: usize,
+        max_distance: usize,
+        after: Option<usize>,
+    ) -> FxHashMap<usize, usize> {
+        let mut candidates = FxHashMap::default();
+        // a query without any text has nothing to be compared on
+        if q_len == 0 {
+            return candidates;
+        }
+
+        // length buckets may allow more than max_distance
+        let bound = self
+            .length_buckets
+            .largest()
+            .map_or(max_distance, |b| b.max(max_distance));
+        let mut below = self.by_length.range(..(q_len, 0)).rev().peekable();
+        let mut above = self.by_length.range((q_len, 0)..).peekable();
+
+        while candidates.len() < self.brute_force_limit {
+            let below_diff = below.peek().map(|&&(len, _)| q_len - len);
+            let above_diff = above.peek().map(|&&(len, _)| len - q_len);
+            let (len_diff, next) = match (below_diff, above_diff) {
+                (Some(b), Some(a)) if b < a => (b, below.next()),
+                (_, Some(a)) => (a, above.next()),
+                (Some(b), None) => (b, below.next()),
+                (None, None) => break,
+            };
+            // the closer side is already too far, so is the other one
+            if len_diff > bound {
+                break;
+            }
+            if let Some(&(_, id)) = next
+                && after.is_none_or(|after| id > after)
+            {
+                candidates.insert(id, 0);
+            }
+        }
+
+        candidates
+    }
+
+    fn verify_candidates(
+        &self,
+        query: &QueryText,
+        candidates: FxHashMap<usize, usize>,
+        max_distance: usize,
+        stats: &mut SearchStats,
+    ) -> Vec<SearchResult> {
+        self.with_distance_buffers(|bufs| {
+            self.verify_candidates_with(query, candidates, max_distance, stats, bufs)
+        })
+    }
+
+    /// Runs `f` with the distance buffers of this thread, grown first to the longest normalized record
+    /// so comparisons against indexed records do not reallocate.
+    fn with_distance_buffers<R>(&self, f: impl FnOnce(&mut DistanceBuffers) -> R) -> R {
+        DISTANCE_BUFFERS.with_borrow_mut(|bufs| {
+            bufs.reserve(self.max_normalized_len);
+            f(bufs)
+        })
+    }
+
+    fn verify_candidates_with(
+        &self,
+        query: &QueryText,
+        candidates: FxHashMap<usize, usize>,
+        max_distance: usize,
+        stats: &mut SearchStats,
+        bufs: &mut DistanceBuffers,
+    ) -> Vec<SearchResult> {
+        let mut results = Vec::new();
+        let mut candidate_buf = Vec::new();
+
+        stats.candidates += candidates.len();
+
+        for id in candidates.into_keys() {
+            if let Some(prepared) = self.storage.get(&id) {
+                let max_distance =
+                    self.effective_max_distance(query.len, prepared.normalized_len, max_distance);
+
+                // Fast pre-filter: length difference > max_distance - impossible match,
+                // except for token sets where extra tokens are free
+                let length_fits = query.len.abs_diff(prepared.normalized_len) <= max_distance
+                    && self.lengths_comparable(query.len, prepared.normalized_len);
+                if !length_fits && !self.metrics.contains(&Metric::TokenSet) {
+                    stats.rejected += 1;
+                    continue;
+                }
+
+                let candidate_chars = match self.stored_chars(id, prepared, &mut candidate_buf) {
+                    Some(chars) => chars,
+                    None => continue,
+                };
+
+                // trying to avoid costly calculations
+                // avoid hash collision - very rare but possible, so we double check with actual chars
+                let exact = query.len == prepared.normalized_len
+                    && query.hash == prepared.normalized_hash
+                    && query.chars == candidate_chars;
+
+                // every enabled metric reports its own result, duplicates are collapsed by the caller
+                let mut within_bound = false;
+                for &metric in &self.metrics {
+                    if metric == Metric::TokenSet {
+                        stats.distance_computations += 1;
+                        let (dist, ratio) = token_set_match(query.chars, candidate_chars, bufs);
+                        if ratio >= self.token_set_ratio {
+                            within_bound = true;
+                            if !exact || self.include_exact {
+                                // a token subset costs nothing, distance 0 stays for exact duplicates
+                                let distance = if exact { 0 } else { dist.max(1) };
+                                results.push(SearchResult {
+                                    id,
+                                    distance,
+                                    metric,
+                                });
+                            }
+                        }
+                        continue;
+                    }
+                    if !length_fits {
+                        continue;
+                    }
+                    if metric == Metric::Phonetic
+                        && !query
+                            .phonetic_keys
+                            .iter()
+                            .any(|key| prepared.phonetic_keys.contains(key))
+                    {
+                        continue;
+                    }
+
+                    let dist = if exact {
+                        0
+                    } else {
+                        stats.distance_computations += 1;
+                        self.distance(metric, query.chars, candidate_chars, max_distance, bufs)
+                    };
+
+                    if dist <= max_distance {
+                        within_bound = true;
+                        if dist > 0 || self.include_exact {
+                            results.push(SearchResult {
+                                id,
+                                distance: dist,
+                                metric,
+                            });
+                        }
+                    }
+                }
+
+                if !within_bound {
+                    stats.rejected += 1;
+                }
+            }
+        }
+
+        results
+    }
+
+    fn distance(
+        &self,
+        metric: Metric,
+        a: &[char],
+        b: &[char],
+        max_distance: usize,
+        bufs: &mut DistanceBuffers,
+    ) -> usize {
+        match (metric, self.transpose_cost) {
+            (Metric::Osa, Some(cost)) if cost != 1.0 => {
+                weighted_osa_distance_raw(a, b, max_distance, cost, bufs)
+            }
+            _ => metric.distance(a, b, max_distance, bufs),
+        }
+    }
+
+    fn lengths_comparable(&self, q_len: usize, c_len: usize) -> bool {
+        match self.min_length_ratio {
+            Some(min_ratio) => {
+                let longer = q_len.max(c_len);
+                longer == 0 || q_len.min(c_len) as f64 / longer as f64 >= min_ratio
+            }
+            None => true,
+        }
+    }
+
+    /// Normalized chars of a stored record, fetched from disk into `buf` for indexes made `with_disk_storage`.
+    /// A failed read skips the record and is kept for `take_disk_error`.
+    fn stored_chars<'a>(
+        &self,
+        id: usize,
+        prepared: &'a PreparedText,
+        buf: &'a mut Vec<char>,
+    ) -> Option<&'a [char]> {
+        let disk = match &self.disk {
+            Some(disk) => disk,
+            None => return Some(&prepared.normalized_vec),
+        };
+
+        match disk.read_into(id, buf) {
+            Ok(true) => Some(buf.as_slice()),
+            // inserted after the arena was written, the chars stay in memory
+            Ok(false) => Some(&prepared.normalized_vec),
+            Err(e) => {
+                let e = io::Error::new(
+                    e.kind(),
+                    format!("cannot read record {} from disk storage: {}", id, e),
+                );
+                self.disk_error.lock().unwrap().get_or_insert(e);
+                None
+            }
+        }
+    }
+
+    /// Absolute bound (from the length bucket, if any) tightened by the relative one (if set), computed from
+    /// the longer of the two texts. Smaller bound also narrows the band of `levenshtein_distance_raw`.
+    fn effective_max_distance(&self, q_len: usize, c_len: usize, max_distance: usize) -> usize {
+        let len = q_len.max(c_len);
+        let max_distance = self
+            .length_buckets
+            .max_distance(len)
+            .unwrap_or(max_distance);
+
+        match self.max_distance_ratio {
+            Some(ratio) => {
+                let relative = (ratio * len as f64).ceil() as usize;
+                max_distance.min(relative)
+            }
+            None => max_distance,
+        }
+    }
+
+    /// Score records sharing trigrams with `query_id` by how close the shared trigrams sit
+    /// (relative position in the text). 1.0 means all query trigrams are shared at the same spots,
+    /// bare overlap with scattered positions scores lower. Sorted by score, best first.
+    #[cfg(feature = "positional")]
+    pub fn positional_scores(&self, query_id: usize) -> Vec<(usize, f64)> {
+        let query = match self.storage.get(&query_id) {
+            Some(q) => q,
+            None => return vec![],
+        };
+
+        if query.grams.is_empty() {
+            return vec![];
+        }
+
+        let mut scores: FxHashMap<usize, f64> = FxHashMap::default();
+        for (token, &q_pos) in query.grams.iter().zip(&query.trigram_positions) {
+            if let Some(postings) = self.index.get(token) {
+                for &(id, pos) in postings {
+                    if id != query_id {
+                        *scores.entry(id).or_insert(0.0) += 1.0 - (q_pos - pos).abs() as f64;
+                    }
+                }
+            }
+        }
+
+        let total = query.grams.len() as f64;
+        let mut scores: Vec<(usize, f64)> = scores
+            .into_iter()
+            .map(|(id, score)| (id, score / total))
+            .collect();
+        scores.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        scores
+    }
+}
+
+/// Deduplicated grams with the relative position of their first occurrence.
+#[cfg(feature = "positional")]
+fn positioned_grams(grams: Vec<Gram>) -> (Vec<Gram>, Vec<f32>) {
+    let total = grams.len() as f32;
+
+    let mut positioned: Vec<(Gram, f32)> = grams
+        .into_iter()
+        .enumerate()
+        .map(|(i, t)| (t, i as f32 / total))
+        .collect();
+    positioned.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+    positioned.dedup_by_key(|p| p.0);
+
+    positioned.into_iter().unzip()
+}
+
+/// Index keys of a cleaned text, `token_ranges` are the ranges `normalize_with` left for that text
+/// (in sorted order, they are put back in text order here).
+pub fn grams(text: &str, token_ranges: &mut [(usize, usize)], mode: TokenMode) -> Vec<Gram> {
+    match mode {
+        TokenMode::Trigrams => tokenize(text).iter().map(pack_trigram).collect(),
+        TokenMode::Cjk => tokenize_cjk(text).iter().map(pack_trigram).collect(),
+        TokenMode::Words(n) => {
+            token_ranges.sort_unstable();
+            let words: Vec<&str> = token_ranges.iter().map(|&(s, e)| &text[s..e]).collect();
+
+            // texts shorter than a shingle are one shingle of all their words
+            words
+                .windows(n.clamp(1, words.len().max(1)))
+                .map(|shingle| {
+                    let mut hasher = FxHasher::default();
+                    for word in shingle {
+                        hasher.write(word.as_bytes());
+                        hasher.write_u8(0xff);
+                    }
+                    hasher.finish()
+                })
+                .collect()
+        }
+    }
+}
+
+/// chars are at most 21 bits, so three of them fit a u64 without collisions
+pub fn pack_trigram(trigram: &[char; 3]) -> Gram {
+    ((trigram[0] as u64) << 42) | ((trigram[1] as u64) << 21) | trigram[2] as u64
+}
+
+/// inverse of `pack_trigram`
+pub fn unpack_trigram(gram: Gram) -> [char; 3] {
+    let char_at = |shift: u32| char::from_u32(((gram >> shift) & 0x1F_FFFF) as u32).unwrap_or('\0');
+    [char_at(42), char_at(21), char_at(0)]
+}
+
+pub fn tokenize(text: &str) -> Vec<[char; 3]> {
+    let mut trigrams = Vec::new();
+    let mut window = ['\0'; 3];
+    let mut current_word_len = 0;
+
+    for c in text.chars().flat_map(|c| c.to_lowercase()).take(10_000) {
+        if c.is_alphanumeric() {
+            window[0] = window[1];
+            window[1] = window[2];
+            window[2] = c;
+            current_word_len += 1;
+
+            if current_word_len >= 3 {
+                trigrams.push([window[0], window[1], window[2]]);
+            }
+        } else {
+            current_word_len = 0;
+        }
+    }
+
+    trigrams
+}
+
+/// Keys of `TokenMode::Cjk`: the trigrams of `tokenize` outside of CJK runs, overlapping bigrams inside
+/// them and a unigram for a lone CJK character. Bigrams and unigrams are padded with `'\0'` at the end,
+/// which `tokenize` never emits, so they cannot collide with trigrams. A CJK run ends a Latin word
+/// and the other way around, "ab中文" is "ab" and "中文".
+pub fn tokenize_cjk(text: &str) -> Vec<[char; 3]> {
+    fn flush(word: &mut Vec<char>, grams: &mut Vec<[char; 3]>) {
+        match word.first() {
+            Some(&c) if is_cjk(c) && word.len() == 1 => grams.push([c, '\0', '\0']),
+            Some(&c) if is_cjk(c) => grams.extend(word.windows(2).map(|w| [w[0], w[1], '\0'])),
+            _ => grams.extend(word.windows(3).map(|w| [w[0], w[1], w[2]])),
+        }
+        word.clear();
+    }
+
+    let mut grams = Vec::new();
+    let mut word = Vec::new();
+    for c in text.chars().flat_map(|c| c.to_lowercase()).take(10_000) {
+        if !c.is_alphanumeric() {
+            flush(&mut word, &mut grams);
+            continue;
+        }
+        if word
+            .first()
+            .is_some_and(|&first| is_cjk(first) != is_cjk(c))
+        {
+            flush(&mut word, &mut grams);
+        }
+        word.push(c);
+    }
+    flush(&mut word, &mut grams);
+
+    grams
+}
+
+/// Han ideographs, kana and hangul.
+fn is_cjk(c: char) -> bool {
+    matches!(
+        c,
+        '\u{1100}'..='\u{11FF}'          // hangul jamo
+            | '\u{3040}'..='\u{30FF}'    // hiragana, katakana
+            | '\u{3130}'..='\u{318F}'    // hangul compatibility jamo
+            | '\u{31F0}'..='\u{31FF}'    // katakana phonetic extensions
+            | '\u{3400}'..='\u{4DBF}'    // CJK extension A
+            | '\u{4E00}'..='\u{9FFF}'    // CJK unified ideographs
+            | '\u{AC00}'..='\u{D7AF}'    // hangul syllables
+            | '\u{F900}'..='\u{FAFF}'    // CJK compatibility ideographs
+            | '\u{FF66}'..='\u{FF9F}'    // halfwidth katakana
+            | '\u{20000}'..='\u{3134F}'  // CJK extensions B to G
+    )
+}
+
+/// Insert into a list sorted by id, a push when `item` has the greatest id.
+fn insert_sorted<T>(list: &mut Vec<T>, item: T, id: impl Fn(&T) -> usize) {
+    let item_id = id(&item);
+    if list.last().is_none_or(|last| id(last) < item_id) {
+        list.push(item);
+    } else {
+        let at = list.partition_point(|other| id(other) < item_id);
+        list.insert(at, item);
+    }
+}
+
+/// Hash of the exact-match shortcut, equal hashes are confirmed by comparing the chars. A polynomial
+/// over the code points, `h = h * seed + c` wrapping at 2^64 and starting from 0, with the lowest bit of
+/// the seed always set. The output only depends on the chars and the seed, on every platform and version.
+fn hash_chars(chars: &[char], seed: u64) -> u64 {
+    let multiplier = seed | 1;
+    chars.iter().fold(0u64, |hash, &c| {
+        hash.wrapping_mul(multiplier).wrapping_add(c as u64)
+    })
+}
 
 // tests
 #[cfg(test)]
@@ -283,73 +1866,1059 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_tokenize_trigrams() {
-        let text = "hello world";
-        let trigrams = tokenize(text);
+    fn test_tokenize_trigrams() {
+        let text = "hello world";
+        let trigrams = tokenize(text);
+
+        assert_eq!(
+            trigrams,
+            vec![
+                ['h', 'e', 'l'],
+                ['e', 'l', 'l'],
+                ['l', 'l', 'o'],
+                ['w', 'o', 'r'],
+                ['o', 'r', 'l'],
+                ['r', 'l', 'd']
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_short_words() {
+        let text = "a bc def";
+        let trigrams = tokenize(text);
+
+        assert_eq!(trigrams, vec![['d', 'e', 'f']]);
+    }
+
+    #[test]
+    fn test_tokenize_cjk() {
+        assert_eq!(
+            tokenize_cjk("東京大学 abcd 猫"),
+            vec![
+                ['東', '京', '\0'],
+                ['京', '大', '\0'],
+                ['大', '学', '\0'],
+                ['a', 'b', 'c'],
+                ['b', 'c', 'd'],
+                ['猫', '\0', '\0']
+            ]
+        );
+        // a script change splits words
+        assert_eq!(
+            tokenize_cjk("abc東京"),
+            vec![['a', 'b', 'c'], ['東', '京', '\0']]
+        );
+    }
+
+    #[test]
+    fn test_index_builder_and_search() {
+        let builder = IndexBuilder::new(0.5); // 50% 
+
+        builder.bulk_add(vec![
+            (1, "hello world".to_string()),
+            (2, "hello kitty".to_string()),
+            (3, "something else completely".to_string()),
+        ]);
+
+        let indexer = builder.build();
+
+        let results = indexer.search("hello", 6);
+
+        assert_eq!(results.len(), 2);
+
+        let mut ids: Vec<usize> = results.iter().map(|r| r.id).collect();
+
+        ids.sort_unstable();
+
+        assert_eq!(ids, vec![1, 2]);
+
+        for result in results {
+            assert_eq!(result.distance, 6);
+        }
+    }
+
+    #[test]
+    fn test_char_map_applies_to_trigrams() {
+        let mut options = NormalizeOptions::default();
+        options.char_map.insert('ß', "ss".to_string());
+        let builder = IndexBuilder::new(0.9).with_normalize_options(options);
+
+        builder.bulk_add(vec![(1, "strasse".to_string())]);
+        let indexer = builder.build();
+
+        // the raw text shares only "str" and "tra" of its 4 trigrams with the record
+        let results = indexer.search("Straße", 1);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, 1);
+        assert_eq!(results[0].distance, 0);
+    }
+
+    #[test]
+    fn test_search_by_id() {
+        let builder = IndexBuilder::new(0.5); // 50% 
+
+        builder.bulk_add(vec![
+            (1, "the quick brown fox".to_string()),
+            (2, "the fast brown fox".to_string()),
+            (3, "unrelated text here".to_string()),
+        ]);
+
+        let indexer = builder.build();
+
+        let results = indexer.search_by_id(1, 10);
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, 2);
+    }
+
+    #[test]
+    fn test_max_distance_ratio() {
+        let records = vec![
+            (1, "the quick brown fox jumps over the lazy dog".to_string()),
+            (2, "the quack brawn fox jumpz ovar the lazi dog".to_string()),
+        ];
+
+        let builder = IndexBuilder::new(0.2);
+        builder.bulk_add(records.clone());
+        let indexer = builder.build();
+
+        let results = indexer.search_by_id(1, 8);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].distance, 5);
+
+        // 43 chars * 0.05 -> at most 3 edits, so the pair is excluded despite passing the absolute bound
+        let builder = IndexBuilder::new(0.2).with_max_distance_ratio(0.05);
+        builder.bulk_add(records);
+        let indexer = builder.build();
+
+        assert!(indexer.search_by_id(1, 8).is_empty());
+    }
+
+    #[test]
+    fn test_search_by_id_unsorted_same_results() {
+        let builder = IndexBuilder::new(0.3);
+        builder.bulk_add(vec![
+            (1, "hello world".to_string()),
+            (2, "hello worlds".to_string()),
+            (3, "hallo world".to_string()),
+            (4, "hello wrld".to_string()),
+            (5, "jello worldz".to_string()),
+        ]);
+        let indexer = builder.build();
+
+        let key = |r: &SearchResult| (r.id, r.distance, r.metric);
+        let sorted = indexer.search_by_id(1, 3);
+        assert!(sorted.windows(2).all(|w| w[0].distance <= w[1].distance));
+
+        let mut sorted: Vec<_> = sorted.iter().map(key).collect();
+        let mut unsorted: Vec<_> = indexer
+            .search_by_id_unsorted(1, 3)
+            .iter()
+            .map(key)
+            .collect();
+        sorted.sort_unstable();
+        unsorted.sort_unstable();
+        assert_eq!(sorted.len(), 4);
+        assert_eq!(sorted, unsorted);
+    }
+
+    #[test]
+    fn test_profile_indexing_lists_slowest_records() {
+        let builder = IndexBuilder::new(0.5).with_profile_indexing(2);
+        builder.bulk_add(vec![
+            (1, "hello world".to_string()),
+            (2, "lorem ipsum dolor sit amet ".repeat(2000)),
+            (3, "foo bar".to_string()),
+            (4, String::new()),
+        ]);
+        let indexer = builder.build();
+
+        let slowest = indexer.slowest_records();
+        assert_eq!(slowest.len(), 2);
+        assert_eq!(slowest[0].0, 2);
+        assert!(slowest[0].1 >= slowest[1].1);
+
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(vec![(1, "hello world".to_string())]);
+        assert!(builder.build().slowest_records().is_empty());
+    }
+
+    #[test]
+    fn test_min_distinct_trigrams() {
+        let records = vec![
+            (1, "aaaaaaaaaa".to_string()),   // only "aaa", repeated
+            (2, "aaaa bcd efh".to_string()), // "aaa" and "bcd"
+        ];
+        let query = "aaaaaaa bcd efg";
+
+        let builder = IndexBuilder::new(0.0);
+        builder.bulk_add(records.clone());
+        let mut ids: Vec<usize> = builder
+            .build()
+            .search(query, 20)
+            .iter()
+            .map(|r| r.id)
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2]);
+
+        let builder = IndexBuilder::new(0.0).with_min_distinct_trigrams(2);
+        builder.bulk_add(records);
+        let results = builder.build().search(query, 20);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, 2);
+    }
+
+    #[test]
+    fn test_correct_returns_the_closest_entry() {
+        let builder = IndexBuilder::new(0.2);
+        builder.bulk_add(vec![
+            (1, "receive".to_string()),
+            (2, "recital".to_string()),
+            (3, "deceive".to_string()),
+            (4, "separate".to_string()),
+            (5, "receiver".to_string()),
+        ]);
+        let indexer = builder.build();
+
+        assert_eq!(indexer.correct("recieve", 2), Some((1, 2)));
+        assert_eq!(indexer.correct("seperate", 2), Some((4, 1)));
+        // "receive" and "receiver" are both 1 edit away, the shorter one wins
+        assert_eq!(indexer.correct("receiv", 1), Some((1, 1)));
+        assert_eq!(indexer.correct("xylophone", 2), None);
+    }
+
+    #[test]
+    fn test_brute_force_fallback_on_short_records() {
+        let records = vec![
+            (1, "ab".to_string()),
+            (2, "ac".to_string()),
+            (3, "x y".to_string()),
+            (4, "x z".to_string()),
+            (5, "bb".to_string()),
+        ];
+
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(records.clone());
+        let indexer = builder.build();
+
+        let twins = |id| {
+            let mut ids: Vec<_> = indexer.search_by_id(id, 1).iter().map(|r| r.id).collect();
+            ids.sort_unstable();
+            ids
+        };
+        assert_eq!(twins(1), vec![2, 5]);
+        assert!(twins(2).is_empty());
+        assert_eq!(twins(3), vec![4]);
+        assert!(twins(5).is_empty());
+        assert_eq!(indexer.search("ad", 1).len(), 2);
+
+        // a query that normalizes to nothing is not compared with the short records
+        assert!(indexer.search("!!", 2).is_empty());
+
+        let builder = IndexBuilder::new(0.5).with_brute_force_limit(0);
+        builder.bulk_add(records);
+        assert!(builder.build().search_by_id(1, 1).is_empty());
+    }
+
+    #[test]
+    fn test_brute_force_limit_bounds_candidates() {
+        // every record is shorter than a trigram, so every query takes the fallback
+        let records: Vec<(usize, String)> = (0..2000)
+            .map(|i| {
+                let c = char::from(b'a' + (i % 26) as u8);
+                (i, c.to_string().repeat(1 + i % 2))
+            })
+            .collect();
+        let builder = IndexBuilder::new(0.5).with_brute_force_limit(50);
+        builder.bulk_add(records);
+        let indexer = builder.build();
+
+        let (results, stats) = indexer.search_by_id_with_stats(0, 1, false);
+        assert!(stats.candidates <= 50, "{}", stats.candidates);
+        assert!(!results.is_empty());
+        // records of the query length come first, "a" is found before any of the longer "aa"
+        assert!(
+            results
+                .iter()
+                .all(|r| indexer.normalized_len(r.id) == Some(1))
+        );
+
+        // the 50 smallest ids of the query length are skipped, they do not use up the limit
+        let (results, _) = indexer.search_by_id_with_stats(1990, 1, true);
+        assert!(!results.is_empty());
+        assert!(results.iter().all(|r| r.id > 1990));
+    }
 
+    #[test]
+    fn test_rank_by_ratio() {
+        let records = vec![
+            (0, "hello world".to_string()),
+            (1, "hello worle".to_string()),
+            (2, "hello worlds".to_string()),
+        ];
+
+        let builder = IndexBuilder::new(0.0);
+        builder.bulk_add(records.clone());
+        let indexer = builder.build();
+        let results = indexer.search_by_id(0, 1);
         assert_eq!(
-            trigrams,
+            results.iter().map(|r| r.distance).collect::<Vec<_>>(),
+            vec![1, 1]
+        );
+
+        // at equal distance the longer twin differs in a smaller share of its text
+        let builder = IndexBuilder::new(0.0).with_rank_by(RankBy::Ratio);
+        builder.bulk_add(records);
+        let indexer = builder.build();
+        let ids = |results: Vec<SearchResult>| results.iter().map(|r| r.id).collect::<Vec<_>>();
+        assert_eq!(ids(indexer.search_by_id(0, 1)), vec![2, 1]);
+        assert_eq!(ids(indexer.search("hello world", 1)), vec![0, 2, 1]);
+        assert_eq!(ids(indexer.neighbors(0, 1)), vec![2, 1]);
+
+        assert_eq!("ratio".parse(), Ok(RankBy::Ratio));
+        assert!("length".parse::<RankBy>().is_err());
+    }
+
+    #[test]
+    fn test_search_by_id_with_id_filter() {
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(vec![
+            (1, "hello world".to_string()),
+            (2, "hello worlds".to_string()),
+            (3, "hello worldss".to_string()),
+        ]);
+        let indexer = builder.build();
+        let ids = |results: Vec<SearchResult>| {
+            let mut ids: Vec<usize> = results.iter().map(|r| r.id).collect();
+            ids.sort_unstable();
+            ids
+        };
+
+        assert_eq!(ids(indexer.search_by_id_with(2, 1, true)), vec![3]);
+        assert_eq!(ids(indexer.search_by_id_with(2, 1, false)), vec![1, 3]);
+        assert_eq!(
+            ids(indexer.search_by_id_with(2, 1, true)),
+            ids(indexer.search_by_id(2, 1))
+        );
+        assert_eq!(
+            ids(indexer.search_by_id_with(2, 1, false)),
+            ids(indexer.neighbors(2, 1))
+        );
+    }
+
+    #[test]
+    fn test_hash_collision_is_double_checked() {
+        // the hash is documented, so it can be pinned
+        assert_eq!(hash_chars(&['a', 'b'], 10), 97 * 11 + 98);
+        assert_eq!(hash_chars(&[], DEFAULT_HASH_SEED), 0);
+
+        let records = vec![
+            (1, "hello world".to_string()),
+            (2, "hello wrold".to_string()),
+        ];
+        let seeded = |seed| {
+            let builder = IndexBuilder::new(0.3)
+                .with_include_exact(true)
+                .with_hash_seed(seed);
+            builder.bulk_add(records.clone());
+            builder.build()
+        };
+        let hash = |indexer: &Indexer, id| indexer.storage[&id].normalized_hash;
+
+        let indexer = seeded(DEFAULT_HASH_SEED);
+        assert_ne!(hash(&indexer, 1), hash(&indexer, 2));
+        let results = indexer.search_by_id(1, 2);
+        assert_eq!(
+            (results.len(), results[0].id, results[0].distance),
+            (1, 2, 2)
+        );
+
+        // with seed 1 the hash is the sum of the chars, the two texts are anagrams and collide
+        let indexer = seeded(1);
+        assert_eq!(hash(&indexer, 1), hash(&indexer, 2));
+        let results = indexer.search_by_id(1, 2);
+        assert_eq!(
+            (results.len(), results[0].id, results[0].distance),
+            (1, 2, 2)
+        );
+        assert!(indexer.search_by_id(1, 1).is_empty());
+
+        // queries are hashed with the same seed, only the record with the same chars is exact
+        let exact = indexer.search("hello wrold", 0);
+        assert_eq!((exact.len(), exact[0].id, exact[0].distance), (1, 2, 0));
+    }
+
+    #[test]
+    fn test_shared_trigrams_between() {
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(vec![
+            (1, "hello world".to_string()),
+            (2, "help worlds".to_string()),
+        ]);
+        let indexer = builder.build();
+
+        let mut shared = indexer.shared_trigrams_between(1, 2);
+        shared.sort_unstable();
+        assert_eq!(
+            shared,
             vec![
                 ['h', 'e', 'l'],
-                ['e', 'l', 'l'],
-                ['l', 'l', 'o'],
-                ['w', 'o', 'r'],
                 ['o', 'r', 'l'],
-                ['r', 'l', 'd']
+                ['r', 'l', 'd'],
+                ['w', 'o', 'r']
             ]
         );
+        assert_eq!(indexer.shared_trigrams_between(2, 1).len(), 4);
+        assert!(indexer.shared_trigrams_between(1, 3).is_empty());
+
+        assert_eq!(
+            unpack_trigram(pack_trigram(&['ß', '1', 'é'])),
+            ['ß', '1', 'é']
+        );
     }
 
     #[test]
-    fn test_tokenize_short_words() {
-        let text = "a bc def";
-        let trigrams = tokenize(text);
+    fn test_insert_bridges_clusters() {
+        let builder = IndexBuilder::new(0.3).with_incremental_clusters(2);
+        builder.bulk_add(vec![
+            (1, "abcdefghij".to_string()),
+            (2, "abcdefghijk".to_string()),
+            (3, "abcdefgxyz".to_string()),
+            (4, "abcdefgxyzz".to_string()),
+        ]);
+        let mut indexer = builder.build();
+
+        let cluster = |indexer: &Indexer, id| indexer.cluster_of(id).unwrap();
+        assert_eq!(cluster(&indexer, 1), cluster(&indexer, 2));
+        assert_eq!(cluster(&indexer, 3), cluster(&indexer, 4));
+        assert_ne!(cluster(&indexer, 1), cluster(&indexer, 3));
+        assert_eq!(indexer.cluster_of(5), None);
+
+        // unrelated record, a cluster of its own
+        assert!(indexer.insert(6, "completely different".to_string()));
+        assert_ne!(cluster(&indexer, 6), cluster(&indexer, 1));
+        assert_ne!(cluster(&indexer, 6), cluster(&indexer, 3));
+
+        // the inserted record is searchable like the built ones
+        let twins = indexer.search("completely differant", 1);
+        assert_eq!(twins.len(), 1);
+        assert_eq!(twins[0].id, 6);
+
+        // 1 and 3 are 3 apart, the new record is within 2 of both
+        assert!(indexer.insert(5, "abcdefghyz".to_string()));
+        assert!(!indexer.insert(5, "again".to_string()));
+
+        let merged = cluster(&indexer, 5);
+        for id in [1, 2, 3, 4] {
+            assert_eq!(cluster(&indexer, id), merged, "id {}", id);
+        }
+        assert_ne!(cluster(&indexer, 6), merged);
 
-        assert_eq!(trigrams, vec![['d', 'e', 'f']]);
+        let plain = IndexBuilder::new(0.3);
+        plain.bulk_add(vec![(1, "hello world".to_string())]);
+        assert_eq!(plain.build().cluster_of(1), None);
     }
 
     #[test]
-    fn test_index_builder_and_search() {
-        let builder = IndexBuilder::new(0.5); // 50% 
+    fn test_insert_matches_bulk_build() {
+        let records: Vec<(usize, String)> = [
+            "hello world",
+            "hello worlds",
+            "help world",
+            "ab",
+            "ac",
+            "hello wrld",
+            "foo bar",
+            "foo bars",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(id, text)| (id, text.to_string()))
+        .collect();
+
+        let builder = IndexBuilder::new(0.3);
+        builder.bulk_add(records.clone());
+        let bulk = builder.build();
+
+        // ascending ids are appended, 0 and 3 land in front of indexed ones
+        let builder = IndexBuilder::new(0.3);
+        builder.bulk_add(vec![records[2].clone()]);
+        let mut inserted = builder.build();
+        for &i in &[4, 5, 6, 7, 0, 1, 3] {
+            let (id, text) = records[i].clone();
+            assert!(inserted.insert(id, text));
+        }
+
+        let pairs = |indexer: &Indexer| {
+            let mut pairs: Vec<(usize, usize, usize)> = (0..records.len())
+                .flat_map(|id| {
+                    indexer
+                        .search_by_id(id, 2)
+                        .into_iter()
+                        .map(move |r| (id, r.id, r.distance))
+                })
+                .collect();
+            pairs.sort_unstable();
+            pairs
+        };
+        assert!(!pairs(&bulk).is_empty());
+        assert_eq!(pairs(&inserted), pairs(&bulk));
+    }
+
+    #[test]
+    fn test_length_buckets() {
+        let buckets: LengthBuckets = "0-10=1, 11-30=4,31+=8".parse().unwrap();
+        assert_eq!(buckets.max_distance(5), Some(1));
+        assert_eq!(buckets.max_distance(11), Some(4));
+        assert_eq!(buckets.max_distance(1000), Some(8));
+        assert!("0-10=1,5-20=3".parse::<LengthBuckets>().is_err());
+        assert!("ten=1".parse::<LengthBuckets>().is_err());
+
+        let records = vec![
+            (1, "jon smith".to_string()),
+            (2, "jan smyth".to_string()),
+            (3, "international business machines".to_string()),
+            (4, "internasional busines machine".to_string()),
+        ];
+        let builder = IndexBuilder::new(0.0).with_length_buckets(buckets);
+        builder.bulk_add(records);
+        let indexer = builder.build();
+
+        // 2 edits exceed the tight bound of short records, 3 edits are within the bound of long ones
+        assert!(indexer.search_by_id(1, 8).is_empty());
+        let results = indexer.search_by_id(3, 8);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].distance, 3);
+    }
+
+    #[cfg(feature = "positional")]
+    #[test]
+    fn test_positional_scores() {
+        let builder = IndexBuilder::new(0.5);
 
         builder.bulk_add(vec![
+            (1, "alpha beta gamma delta".to_string()),
+            (2, "alpha beta gamma delta".to_string()),
+            (3, "delta gamma beta alpha".to_string()),
+        ]);
+
+        let indexer = builder.build();
+        let scores = indexer.positional_scores(1);
+
+        // both share every trigram, only the positions differ
+        assert_eq!(scores.len(), 2);
+        assert_eq!(scores[0].0, 2);
+        assert!((scores[0].1 - 1.0).abs() < 1e-6);
+        assert_eq!(scores[1].0, 3);
+        assert!(scores[1].1 < 0.6);
+    }
+
+    #[test]
+    fn test_disk_storage_matches_memory() {
+        let records = vec![
+            (1, "the quick brown fox".to_string()),
+            (2, "the fast brown fox".to_string()),
+            (3, "quick brown fox the".to_string()),
+            (4, "unrelated text here".to_string()),
+            (5, "unrelated texts here".to_string()),
+        ];
+
+        let builder = IndexBuilder::new(0.3);
+        builder.bulk_add(records.clone());
+        let memory = builder.build();
+
+        let path = std::env::temp_dir().join(format!("crony_disk_{}.bin", std::process::id()));
+        let builder = IndexBuilder::new(0.3).with_disk_storage(&path).unwrap();
+        builder.bulk_add(records);
+        // texts go to the file as they are added, not when the index is built
+        assert!(builder.storage.iter().all(|p| p.normalized_vec.is_empty()));
+        let disk = builder.try_build().unwrap();
+
+        let pairs = |indexer: &Indexer| {
+            let mut pairs: Vec<(usize, usize, usize)> = (1..=5)
+                .flat_map(|q| {
+                    indexer
+                        .search_by_id(q, 10)
+                        .into_iter()
+                        .map(move |r| (q, r.id, r.distance))
+                })
+                .collect();
+            pairs.sort_unstable();
+            pairs
+        };
+
+        let expected = pairs(&memory);
+        assert!(!expected.is_empty());
+        assert_eq!(pairs(&disk), expected);
+
+        let text_results = |indexer: &Indexer| {
+            let mut results: Vec<(usize, usize)> = indexer
+                .search("the quick brown fox", 10)
+                .into_iter()
+                .map(|r| (r.id, r.distance))
+                .collect();
+            results.sort_unstable();
+            results
+        };
+        assert_eq!(text_results(&disk), text_results(&memory));
+        assert!(disk.take_disk_error().is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_exclude_exact_twins() {
+        let records = vec![
             (1, "hello world".to_string()),
-            (2, "hello kitty".to_string()),
-            (3, "something else completely".to_string()),
+            (2, "world hello".to_string()),
+            (3, "hello worlds".to_string()),
+        ];
+
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(records.clone());
+        let indexer = builder.build();
+
+        let mut found: Vec<(usize, usize)> = indexer
+            .search_by_id(1, 4)
+            .into_iter()
+            .map(|r| (r.id, r.distance))
+            .collect();
+        found.sort_unstable();
+        assert_eq!(found, vec![(2, 0), (3, 1)]);
+
+        let builder = IndexBuilder::new(0.5).with_include_exact(false);
+        builder.bulk_add(records);
+        let indexer = builder.build();
+
+        let found: Vec<(usize, usize)> = indexer
+            .search_by_id(1, 4)
+            .into_iter()
+            .map(|r| (r.id, r.distance))
+            .collect();
+        assert_eq!(found, vec![(3, 1)]);
+    }
+
+    #[test]
+    fn test_search_against_blocklist() {
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(vec![
+            (100, "free bitcoin giveaway".to_string()),
+            (101, "cheap replica watches".to_string()),
+        ]);
+        let blocklist = builder.build();
+
+        let records = vec![
+            (1, "free bitcoin givaway".to_string()),
+            (2, "weekly team meeting notes".to_string()),
+        ];
+
+        let (flagged, stats) = blocklist.search_against_with_stats(&records, 3);
+
+        assert_eq!(flagged.len(), 1);
+        assert_eq!(flagged[0].0, 1);
+        assert_eq!(flagged[0].1.id, 100);
+        assert_eq!(flagged[0].1.distance, 1);
+        assert_eq!(stats.candidates, 1);
+        assert_eq!(stats.distance_computations, 1);
+        assert_eq!(blocklist.search_against(&records, 3).len(), 1);
+    }
+
+    #[test]
+    fn test_normalize_numbers_phone_formats() {
+        let options = NormalizeOptions {
+            normalize_numbers: true,
+            ..Default::default()
+        };
+        let builder = IndexBuilder::new(0.5).with_normalize_options(options);
+
+        builder.bulk_add(vec![
+            (1, "(555) 123-4567".to_string()),
+            (2, "5551234567".to_string()),
         ]);
 
         let indexer = builder.build();
+        let results = indexer.search_by_id(1, 2);
 
-        let results = indexer.search("hello", 6);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, 2);
+        assert_eq!(results[0].distance, 0);
+    }
 
-        assert_eq!(results.len(), 2);
+    #[test]
+    fn test_search_by_id_instrumented() {
+        let builder = IndexBuilder::new(0.3);
 
-        let mut ids: Vec<usize> = results.iter().map(|r| r.id).collect();
+        builder.bulk_add(vec![
+            (1, "hello world".to_string()),
+            (2, "hello worlds".to_string()),
+            (3, "hello world and many more words".to_string()),
+            (4, "say hello".to_string()),
+            (5, "nothing in common".to_string()),
+        ]);
 
-        ids.sort_unstable();
+        let indexer = builder.build();
+        let (results, stats) = indexer.search_by_id_instrumented(1, 2);
 
-        assert_eq!(ids, vec![1, 2]);
+        // 2, 3 and 4 share enough trigrams, only 2 is close enough
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, 2);
+        assert_eq!(stats.candidates, 3);
+        assert_eq!(stats.rejected, 2);
+        // 3 is rejected by the length prefilter before any distance is computed
+        assert_eq!(stats.distance_computations, 2);
+    }
 
-        for result in results {
-            assert_eq!(result.distance, 6);
+    #[test]
+    fn test_empty_record_policies() {
+        let records = vec![
+            (1, "hello world".to_string()),
+            (2, "".to_string()),
+            (3, "   ".to_string()),
+            (4, "hello worlds".to_string()),
+        ];
+
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(records.clone());
+        let indexer = builder.build();
+        assert!(indexer.empty_records().is_empty());
+
+        let builder = IndexBuilder::new(0.5).with_empty_record_policy(EmptyRecordPolicy::Report);
+        builder.bulk_add(records.clone());
+        let indexer = builder.build();
+        assert_eq!(indexer.empty_records(), &[2, 3]);
+        assert!(indexer.search_by_id(2, 2).is_empty());
+        // reported records are not searchable
+        assert_eq!(indexer.ids(), vec![1, 4]);
+
+        let builder = IndexBuilder::new(0.5).with_empty_record_policy(EmptyRecordPolicy::Index);
+        builder.bulk_add(records.clone());
+        let indexer = builder.build();
+        assert_eq!(indexer.ids(), vec![1, 2, 3, 4]);
+        let results = indexer.search_by_id(2, 2);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, 3);
+        assert_eq!(results[0].distance, 0);
+
+        let builder = IndexBuilder::new(0.5).with_empty_record_policy(EmptyRecordPolicy::Error);
+        builder.bulk_add(records);
+        match builder.try_build() {
+            Err(BuildError::EmptyRecords(ids)) => assert_eq!(ids, vec![2, 3]),
+            _ => panic!("empty records must fail the build"),
         }
     }
 
     #[test]
-    fn test_search_by_id() {
-        let builder = IndexBuilder::new(0.5); // 50% 
+    fn test_search_prefix() {
+        let builder = IndexBuilder::new(0.5);
 
         builder.bulk_add(vec![
-            (1, "the quick brown fox".to_string()),
-            (2, "the fast brown fox".to_string()),
-            (3, "unrelated text here".to_string()),
+            (1, "hello world".to_string()),
+            (2, "helpdesk support".to_string()),
+            (3, "goodbye world".to_string()),
+            // normalized text is token sorted ("desk help"), so its prefix is "des"
+            (4, "help desk".to_string()),
         ]);
 
         let indexer = builder.build();
 
-        let results = indexer.search_by_id(1, 10);
+        let results = indexer.search_prefix("hel", 0);
+        let mut ids: Vec<usize> = results.iter().map(|r| r.id).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2]);
+
+        // fuzzy: one substitution in the typed prefix
+        let results = indexer.search_prefix("hellp", 1);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, 1);
+        assert_eq!(results[0].distance, 1);
+
+        // whole string search does not match such a short query
+        assert!(indexer.search("hel", 1).is_empty());
+
+        // token set and phonetic metrics are not used on prefixes, the cut off "hel" would pass
+        // either, only the edit distance of the prefix is reported
+        let builder = IndexBuilder::new(0.5).with_metrics(vec![Metric::TokenSet, Metric::Phonetic]);
+        builder.bulk_add(vec![
+            (1, "hello world".to_string()),
+            (3, "goodbye world".to_string()),
+        ]);
+        let results = builder.build().search_prefix("hel", 0);
+        let found: Vec<(usize, Metric)> = results.iter().map(|r| (r.id, r.metric)).collect();
+        assert_eq!(found, vec![(1, Metric::Levenshtein)]);
+    }
+
+    #[test]
+    fn test_min_length_ratio() {
+        let records = vec![(1, "abcd".to_string()), (2, "abcdefgh".to_string())];
+
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(records.clone());
+        let indexer = builder.build();
+
+        let results = indexer.search_by_id(1, 8);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].distance, 4);
+
+        // 4 / 8 = 0.5 is below the threshold
+        let builder = IndexBuilder::new(0.5).with_min_length_ratio(0.6);
+        builder.bulk_add(records);
+        let indexer = builder.build();
+
+        assert!(indexer.search_by_id(1, 8).is_empty());
+    }
+
+    #[test]
+    fn test_raw_mode_keeps_text_verbatim() {
+        let options = NormalizeOptions {
+            raw: true,
+            ..Default::default()
+        };
+        let builder = IndexBuilder::new(0.5).with_normalize_options(options);
+
+        builder.bulk_add(vec![
+            (1, "World Hello".to_string()),
+            (2, "hello world".to_string()),
+        ]);
+
+        let indexer = builder.build();
+
+        let stored: String = indexer.storage[&1].normalized_vec.iter().collect();
+        assert_eq!(stored, "World Hello");
+
+        // no lowercasing or token sorting, so these are far apart
+        let results = indexer.search_by_id(1, 11);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].distance, 8);
+
+        let results = indexer.search("World Hello", 0);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, 1);
+    }
+
+    #[test]
+    fn test_max_postings_per_trigram() {
+        let records: Vec<(usize, String)> =
+            (0..10_000).map(|id| (id, "common".to_string())).collect();
+
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(records.clone());
+        let indexer = builder.build();
+        let (_, stats) = indexer.search_by_id_instrumented(0, 2);
+        assert_eq!(stats.candidates, 9_999);
+
+        let builder = IndexBuilder::new(0.5).with_max_postings_per_trigram(100);
+        builder.bulk_add(records);
+        let indexer = builder.build();
+
+        let (results, stats) = indexer.search_by_id_instrumented(0, 2);
+        assert_eq!(stats.candidates, 100);
+        assert_eq!(results.len(), 100);
+        assert!(results.iter().all(|r| r.id >= 1 && r.id <= 100));
+
+        let (_, stats) = indexer.search_by_id_instrumented(5_000, 2);
+        assert!(stats.candidates <= 100);
+    }
+
+    #[test]
+    fn test_word_shingles_share_phrase() {
+        let builder = IndexBuilder::new(0.1).with_token_mode(TokenMode::Words(2));
+
+        builder.bulk_add(vec![
+            (1, "The quick brown fox jumps over the lazy dog".to_string()),
+            (2, "a quick brown fox sleeps".to_string()),
+            (3, "brown, quick... fox".to_string()),
+        ]);
+
+        let indexer = builder.build();
+        let query = &indexer.storage[&1];
+        assert_eq!(query.grams.len(), 8);
+
+        // 2 shares "quick brown" and "brown fox", 3 has the same words in a different order
+        let candidates = indexer.collect_candidates(&query.grams, None);
+        assert!(candidates.contains_key(&2));
+        assert!(!candidates.contains_key(&3));
+
+        // texts shorter than a shingle still get one key
+        let mut ranges = vec![(0, 5)];
+        assert_eq!(grams("hello", &mut ranges, TokenMode::Words(3)).len(), 1);
+    }
+
+    #[test]
+    fn test_cjk_near_duplicate() {
+        let records = vec![
+            (1, "北京大学".to_string()),
+            (2, "北京大學".to_string()),
+            (3, "上海交通大学".to_string()),
+        ];
+        let twins = |mode: TokenMode| {
+            let builder = IndexBuilder::new(0.6).with_token_mode(mode);
+            builder.bulk_add(records.clone());
+            let indexer = builder.build();
+            let ids: Vec<usize> = indexer.neighbors(1, 2).iter().map(|r| r.id).collect();
+            ids
+        };
+
+        // 2 of 3 bigrams are shared, but only 1 of 2 trigrams
+        assert_eq!(twins(TokenMode::Cjk), vec![2]);
+        assert!(twins(TokenMode::Trigrams).is_empty());
+    }
+
+    #[test]
+    fn test_transpose_cost_changes_matches() {
+        let records = vec![(1, "abcdefgh".to_string()), (2, "badcefgx".to_string())];
+
+        // two swaps and one substitution, 3 edits at the standard cost
+        let builder = IndexBuilder::new(0.0).with_metrics(vec![Metric::Osa]);
+        builder.bulk_add(records.clone());
+        assert!(builder.build().search_by_id(1, 2).is_empty());
+
+        let builder = IndexBuilder::new(0.0)
+            .with_metrics(vec![Metric::Osa])
+            .with_transpose_cost(0.5);
+        builder.bulk_add(records);
+        let results = builder.build().search_by_id(1, 2);
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, 2);
+        assert_eq!(results[0].distance, 2);
+    }
+
+    #[test]
+    fn test_token_set_or_levenshtein() {
+        let records = vec![
+            (1, "john smith".to_string()),
+            (2, "dr john smith phd".to_string()),
+            (3, "john smiht".to_string()),
+        ];
+        let builder = IndexBuilder::new(0.3)
+            .with_metrics(vec![Metric::Levenshtein, Metric::TokenSet])
+            .with_token_set_ratio(0.95);
+        builder.bulk_add(records.clone());
+        let results = builder.build().search_by_id(1, 2);
+
+        // 2 is 7 edits away but has every token of 1, 3 only passes the distance
+        let found: Vec<(usize, Metric)> = results.iter().map(|r| (r.id, r.metric)).collect();
+        assert_eq!(found, vec![(2, Metric::TokenSet), (3, Metric::Levenshtein)]);
+        // a token subset is not reported as an exact duplicate
+        assert_eq!(results[0].distance, 1);
+
+        let builder = IndexBuilder::new(0.3);
+        builder.bulk_add(records);
+        let results = builder.build().search_by_id(1, 2);
+        assert_eq!(results.iter().map(|r| r.id).collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn test_max_normalized_len_keeps_prefix() {
+        let records = vec![
+            (1, "abcdefgh tail one".to_string()),
+            (2, "abcdefgh other ending".to_string()),
+            (3, "abcd".to_string()),
+        ];
+        let builder = IndexBuilder::new(0.0).with_max_normalized_len(8);
+        builder.bulk_add(records);
+        let indexer = builder.build();
+
+        assert_eq!(indexer.truncated_records(), 2);
+        assert_eq!(indexer.normalized_len(1), Some(8));
+
+        // only "abcdefgh" is left of 1 and 2, the tails do not count
+        let results = indexer.search_by_id(1, 0);
+        assert_eq!(results.iter().map(|r| r.id).collect::<Vec<_>>(), vec![2]);
+
+        // the query is cut the same way
+        let mut ids: Vec<usize> = indexer
+            .search("abcdefgh something else entirely", 0)
+            .iter()
+            .map(|r| r.id)
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_distance_buffers_sized_to_longest_record() {
+        let long_a = "lorem ipsum dolor sit amet ".repeat(20);
+        let long_b = long_a.replacen("dolor", "dolar", 1);
+        let builder = IndexBuilder::new(0.5);
+        builder.bulk_add(vec![(1, long_a), (2, long_b), (3, "short".to_string())]);
+        let indexer = builder.build();
+
+        let longest = indexer.normalized_len(1).unwrap();
+        assert!(longest > 256);
+        DISTANCE_BUFFERS.with_borrow(|bufs| assert!(bufs.cache.capacity() <= longest));
+
+        // a search that compares nothing long still sizes the buffers of this thread
+        assert!(indexer.search("qqqq", 0).is_empty());
+        DISTANCE_BUFFERS.with_borrow(|bufs| {
+            assert!(bufs.cache.capacity() > longest);
+            assert!(bufs.char_b.capacity() >= longest);
+        });
+
+        let results = indexer.search_by_id(1, 2);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, 2);
+        assert_eq!(results[0].distance, 1);
+    }
+
+    #[test]
+    fn test_add_receiver_from_producer_thread() {
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let producer = std::thread::spawn(move || {
+            for (id, text) in [(1, "hello world"), (2, "hello worlds"), (3, "foo bar")] {
+                tx.send((id, text.to_string())).unwrap();
+            }
+        });
+
+        let builder = IndexBuilder::new(0.5);
+        builder.add_receiver(rx);
+        producer.join().unwrap();
+
+        let indexer = builder.build();
+        let results = indexer.search("hello world", 1);
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].id, 1);
+        assert_eq!(results[1].id, 2);
+    }
+
+    #[test]
+    fn test_phonetic_metric_candidates() {
+        let records = vec![
+            (1, "Knight".to_string()),
+            (2, "Nite".to_string()),
+            (3, "Knightsbridge".to_string()),
+        ];
+
+        // 1 and 2 share no trigram, 1 shares trigrams with 3 (too far away) so the brute force
+        // fallback does not run either, the trigram index never pairs 1 and 2
+        let builder = IndexBuilder::new(0.3);
+        builder.bulk_add(records.clone());
+        let plain = builder.build();
+        assert!(
+            plain.storage[&1]
+                .grams
+                .iter()
+                .all(|gram| !plain.storage[&2].grams.contains(gram))
+        );
+        assert!(plain.search_by_id(1, 4).is_empty());
+
+        let builder = IndexBuilder::new(0.3).with_metrics(vec![Metric::Phonetic]);
+        builder.bulk_add(records);
+        let indexer = builder.build();
+        assert_eq!(
+            indexer.storage[&1].phonetic_keys,
+            indexer.storage[&2].phonetic_keys
+        );
 
+        let results = indexer.search_by_id(1, 4);
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].id, 2);
+        assert_eq!(results[0].metric, Metric::Phonetic);
+        // verified with edit distance on the normalized text
+        assert_eq!(results[0].distance, 4);
     }
 }
@@ -0,0 +1,390 @@
+use crate::disk::read_exact_at;
+use crate::index::OriginalSource;
+use rustc_hash::FxHashMap;
+use std::error::Error;
+use std::fs::File;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Csv,
+    Parquet,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "parquet" => Ok(InputFormat::Parquet),
+            other => Err(format!(
+                "unknown input format '{}', expected csv or parquet",
+                other
+            )),
+        }
+    }
+}
+
+/// Columns holding the id and the text, by name. Without a name the first and the second
+/// column are used.
+#[derive(Debug, Clone, Default)]
+pub struct Columns {
+    pub id_column: Option<String>,
+    pub text_column: Option<String>,
+}
+
+pub fn load_records(
+    file_path: &str,
+    format: InputFormat,
+    columns: &Columns,
+) -> Result<Vec<(usize, String)>, Box<dyn Error>> {
+    match format {
+        InputFormat::Csv => load_data_from_csv(file_path, columns),
+        #[cfg(feature = "parquet")]
+        InputFormat::Parquet => load_data_from_parquet(file_path, columns),
+        #[cfg(not(feature = "parquet"))]
+        InputFormat::Parquet => Err("parquet input requires the `parquet` cargo feature".into()),
+    }
+}
+
+// there will be problem when id is not a number
+/// load data from csv
+pub fn load_data_from_csv(
+    file_path: &str,
+    columns: &Columns,
+) -> Result<Vec<(usize, String)>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut rdr = csv::Reader::from_reader(file);
+    let (id_idx, text_idx) = csv_columns(&mut rdr, file_path, columns)?;
+
+    let mut records = Vec::new();
+
+    for result in rdr.records() {
+        let record = result?;
+
+        let id: usize = record[id_idx].trim().parse()?; // just to be sure that id is a number, trim any whitespace
+        let text: String = record[text_idx].to_string();
+
+        records.push((id, text));
+    }
+
+    Ok(records)
+}
+
+/// (id, text) column indices of a CSV file
+fn csv_columns(
+    rdr: &mut csv::Reader<File>,
+    file_path: &str,
+    columns: &Columns,
+) -> Result<(usize, usize), Box<dyn Error>> {
+    let headers = rdr.headers()?.clone();
+    let position = |name: &Option<String>, default: usize| match name {
+        Some(name) => headers
+            .iter()
+            .position(|h| h.trim() == name)
+            .ok_or_else(|| format!("column '{}' not found in {}", name, file_path)),
+        None => Ok(default),
+    };
+    Ok((
+        position(&columns.id_column, 0)?,
+        position(&columns.text_column, 1)?,
+    ))
+}
+
+/// Texts of a CSV file read back by id, only the byte range of every record is kept in memory.
+/// Original source of a low memory index, see `IndexBuilder::with_original_source`.
+#[derive(Debug)]
+pub struct CsvTexts {
+    file: File,
+    text_idx: usize,
+    records: FxHashMap<usize, (u64, usize)>, // id -> (byte offset, byte length) of the raw record
+}
+
+impl CsvTexts {
+    /// Scan `file_path` once for the byte ranges of its records.
+    pub fn open(file_path: &str, columns: &Columns) -> Result<Self, Box<dyn Error>> {
+        Self::scan(file_path, columns, |_, _| {})
+    }
+
+    /// Like `open`, and every record is handed to `each` during the scan, e.g. to index a file
+    /// without loading it as a whole.
+    pub fn scan<F>(file_path: &str, columns: &Columns, mut each: F) -> Result<Self, Box<dyn Error>>
+    where
+        F: FnMut(usize, &str),
+    {
+        let mut rdr = csv::Reader::from_reader(File::open(file_path)?);
+        let (id_idx, text_idx) = csv_columns(&mut rdr, file_path, columns)?;
+
+        let mut records = FxHashMap::default();
+        let mut record = csv::StringRecord::new();
+        loop {
+            let start = rdr.position().byte();
+            if !rdr.read_record(&mut record)? {
+                break;
+            }
+            // the reader now stands at the next record, the line terminator belongs to this one
+            let len = (rdr.position().byte() - start) as usize;
+            let id = record[id_idx].trim().parse()?;
+            records.insert(id, (start, len));
+            each(id, &record[text_idx]);
+        }
+
+        Ok(Self {
+            file: rdr.into_inner(),
+            text_idx,
+            records,
+        })
+    }
+
+    /// Text of `id`, None when the file has no such record. Reads are positional, so threads do not
+    /// wait for each other.
+    pub fn text(&self, id: usize) -> Result<Option<String>, Box<dyn Error>> {
+        let Some(&(offset, len)) = self.records.get(&id) else {
+            return Ok(None);
+        };
+
+        let mut bytes = vec![0; len];
+        read_exact_at(&self.file, &mut bytes, offset)?;
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(bytes.as_slice());
+        let mut record = csv::StringRecord::new();
+        if !rdr.read_record(&mut record)? {
+            return Ok(None);
+        }
+        Ok(record.get(self.text_idx).map(str::to_string))
+    }
+}
+
+impl OriginalSource for CsvTexts {
+    fn original(&self, id: usize) -> Option<String> {
+        self.text(id).ok().flatten()
+    }
+}
+
+/// load data from parquet, ids may be any integer column or a string column of numbers,
+/// null texts are read as empty
+#[cfg(feature = "parquet")]
+pub fn load_data_from_parquet(
+    file_path: &str,
+    columns: &Columns,
+) -> Result<Vec<(usize, String)>, Box<dyn Error>> {
+    use parquet::arrow::ProjectionMask;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let file = File::open(file_path)?;
+    let builder = ParquetRecordBatchReaderBuilder::try_new(file)?;
+
+    let schema = builder.schema().clone();
+    let position = |name: &Option<String>, default: usize| match name {
+        Some(name) => schema
+            .index_of(name)
+            .map_err(|_| format!("column '{}' not found in {}", name, file_path)),
+        None => Ok(default),
+    };
+    let id_idx = position(&columns.id_column, 0)?;
+    let text_idx = position(&columns.text_column, 1)?;
+
+    // only decode the two columns we need, projected columns keep their file order
+    let mask = ProjectionMask::roots(builder.parquet_schema(), [id_idx, text_idx]);
+    let (id_idx, text_idx) = if id_idx < text_idx { (0, 1) } else { (1, 0) };
+    let reader = builder.with_projection(mask).build()?;
+
+    let mut records = Vec::new();
+
+    for batch in reader {
+        let batch = batch?;
+        let ids = parquet_ids(batch.column(id_idx))?;
+        let texts = batch.column(text_idx);
+
+        for (row, id) in ids.into_iter().enumerate() {
+            records.push((id, parquet_text(texts, row)?));
+        }
+    }
+
+    Ok(records)
+}
+
+#[cfg(feature = "parquet")]
+fn parquet_ids(column: &arrow_array::ArrayRef) -> Result<Vec<usize>, Box<dyn Error>> {
+    use arrow_array::Array;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Int32Type, Int64Type, UInt32Type, UInt64Type};
+
+    if column.null_count() > 0 {
+        return Err("id column contains nulls".into());
+    }
+
+    let ids: Result<Vec<usize>, std::num::TryFromIntError> =
+        if let Some(a) = column.as_primitive_opt::<Int64Type>() {
+            a.values().iter().map(|&v| usize::try_from(v)).collect()
+        } else if let Some(a) = column.as_primitive_opt::<UInt64Type>() {
+            a.values().iter().map(|&v| usize::try_from(v)).collect()
+        } else if let Some(a) = column.as_primitive_opt::<Int32Type>() {
+            a.values().iter().map(|&v| usize::try_from(v)).collect()
+        } else if let Some(a) = column.as_primitive_opt::<UInt32Type>() {
+            a.values().iter().map(|&v| usize::try_from(v)).collect()
+        } else if let Some(a) = column.as_string_opt::<i32>() {
+            return Ok(a
+                .iter()
+                .map(|v| v.unwrap_or_default().trim().parse())
+                .collect::<Result<_, _>>()?);
+        } else {
+            return Err(format!("unsupported id column type {}", column.data_type()).into());
+        };
+
+    Ok(ids?)
+}
+
+#[cfg(feature = "parquet")]
+fn parquet_text(column: &arrow_array::ArrayRef, row: usize) -> Result<String, Box<dyn Error>> {
+    use arrow_array::Array;
+    use arrow_array::cast::AsArray;
+
+    if column.is_null(row) {
+        return Ok(String::new());
+    }
+
+    if let Some(a) = column.as_string_opt::<i32>() {
+        Ok(a.value(row).to_string())
+    } else if let Some(a) = column.as_string_opt::<i64>() {
+        Ok(a.value(row).to_string())
+    } else {
+        Err(format!("unsupported text column type {}", column.data_type()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("crony_input_{}_{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_csv_columns_by_name() {
+        let path = temp_path("named.csv");
+        std::fs::write(&path, "text,source,id\nhello world,a, 7\nfoo,b,8\n").unwrap();
+
+        let columns = Columns {
+            id_column: Some("id".to_string()),
+            text_column: Some("text".to_string()),
+        };
+        let records = load_records(&path, InputFormat::Csv, &columns).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            records,
+            vec![(7, "hello world".to_string()), (8, "foo".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_low_memory_index_reads_originals_from_csv() {
+        use crate::batch::search_all;
+        use crate::index::IndexBuilder;
+        use rayon::prelude::*;
+
+        let path = temp_path("low_memory.csv");
+        std::fs::write(
+            &path,
+            "id,text\n1,Hello World\n2,\"hello, worlds\"\n3,foo bar\n4,Foo Bars\n5,unrelated\n",
+        )
+        .unwrap();
+        let columns = Columns::default();
+        let records = load_records(&path, InputFormat::Csv, &columns).unwrap();
+        let ids: Vec<usize> = records.iter().map(|(id, _)| *id).collect();
+
+        let builder = IndexBuilder::new(0.3);
+        builder.bulk_add(records.clone());
+        let full = builder.build();
+
+        // records are indexed while the file is scanned, as --low-memory does
+        let builder = IndexBuilder::new(0.3).with_low_memory();
+        let source = CsvTexts::scan(&path, &columns, |id, text| {
+            builder.bulk_add(vec![(id, text.to_string())]);
+        })
+        .unwrap();
+        let low_memory = builder.with_original_source(Box::new(source)).build();
+
+        // texts are read from several threads at once
+        let found = ids
+            .par_iter()
+            .filter(|&&id| low_memory.original(id).is_some())
+            .count();
+        assert_eq!(found, ids.len());
+
+        let pairs = |indexer| {
+            let mut pairs: Vec<_> = search_all(indexer, &ids, 2, None)
+                .results
+                .into_iter()
+                .map(|r| (r.query_id, r.twin_id, r.distance))
+                .collect();
+            pairs.sort_unstable();
+            pairs
+        };
+        assert_eq!(pairs(&low_memory), pairs(&full));
+        assert!(!pairs(&full).is_empty());
+
+        assert_eq!(low_memory.original(2).as_deref(), Some("hello, worlds"));
+        assert_eq!(low_memory.original(4), full.original(4));
+        assert_eq!(low_memory.original(9), None);
+
+        let builder = IndexBuilder::new(0.3).with_low_memory();
+        builder.bulk_add(vec![(1, "hello".to_string())]);
+        assert_eq!(builder.build().original(1), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_load_data_from_parquet() {
+        use arrow_array::{ArrayRef, Int64Array, RecordBatch, StringArray};
+        use parquet::arrow::ArrowWriter;
+        use std::sync::Arc;
+
+        let path = temp_path("fixture.parquet");
+        let batch = RecordBatch::try_from_iter([
+            (
+                "name",
+                Arc::new(StringArray::from(vec![
+                    Some("hello world"),
+                    None,
+                    Some("foo"),
+                ])) as ArrayRef,
+            ),
+            (
+                "row_id",
+                Arc::new(Int64Array::from(vec![3, 1, 2])) as ArrayRef,
+            ),
+        ])
+        .unwrap();
+
+        let mut writer =
+            ArrowWriter::try_new(File::create(&path).unwrap(), batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let columns = Columns {
+            id_column: Some("row_id".to_string()),
+            text_column: Some("name".to_string()),
+        };
+        let records = load_records(&path, InputFormat::Parquet, &columns).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            records,
+            vec![
+                (3, "hello world".to_string()),
+                (1, String::new()),
+                (2, "foo".to_string()),
+            ]
+        );
+    }
+}
@@ -1,2 +1,13 @@
+pub mod batch;
+pub mod cluster;
+pub mod disk;
 pub mod distance;
 pub mod index;
+pub mod input;
+pub mod lsh;
+pub mod manifest;
+pub mod phonetic;
+pub mod results;
+#[cfg(feature = "serve")]
+pub mod server;
+pub mod window;
@@ -0,0 +1,157 @@
+use crate::index::Gram;
+use rustc_hash::{FxHashMap, FxHasher};
+use std::hash::Hasher;
+
+/// MinHash over gram (trigram or shingle) sets, `num_hashes` independent hash functions derived from fixed seeds,
+/// so signatures are stable between runs.
+#[derive(Debug)]
+pub struct MinHasher {
+    seeds: Vec<u64>,
+}
+
+impl MinHasher {
+    pub fn new(num_hashes: usize) -> Self {
+        let mut state = 0x9E37_79B9_7F4A_7C15;
+        let seeds = (0..num_hashes)
+            .map(|_| {
+                state = splitmix64(state);
+                state
+            })
+            .collect();
+        Self { seeds }
+    }
+
+    /// Minimum of every hash function over the grams, the share of equal positions in two
+    /// signatures estimates the Jaccard similarity of the gram sets.
+    pub fn signature(&self, grams: &[Gram]) -> Vec<u64> {
+        let mut signature = vec![u64::MAX; self.seeds.len()];
+
+        for &gram in grams {
+            for (min, &seed) in signature.iter_mut().zip(&self.seeds) {
+                *min = (*min).min(splitmix64(gram ^ seed));
+            }
+        }
+
+        signature
+    }
+}
+
+/// Banded LSH table, signatures are cut into `bands` bands and records sharing any whole band
+/// hash land in the same bucket. More bands (fewer rows each) means higher recall and more candidates.
+#[derive(Debug)]
+pub struct LshIndex {
+    hasher: MinHasher,
+    rows_per_band: usize,
+    buckets: Vec<FxHashMap<u64, Vec<usize>>>,
+}
+
+impl LshIndex {
+    /// `num_hashes` is rounded down to a multiple of `bands`.
+    pub fn new(num_hashes: usize, bands: usize) -> Self {
+        let bands = bands.max(1);
+        let rows_per_band = (num_hashes / bands).max(1);
+
+        Self {
+            hasher: MinHasher::new(rows_per_band * bands),
+            rows_per_band,
+            buckets: (0..bands).map(|_| FxHashMap::default()).collect(),
+        }
+    }
+
+    pub fn signature(&self, grams: &[Gram]) -> Vec<u64> {
+        self.hasher.signature(grams)
+    }
+
+    pub fn insert(&mut self, id: usize, signature: &[u64]) {
+        for (band, bucket) in signature
+            .chunks(self.rows_per_band)
+            .zip(self.buckets.iter_mut())
+        {
+            bucket.entry(hash_band(band)).or_default().push(id);
+        }
+    }
+
+    /// Probable candidates for a signature, sorted and deduplicated.
+    pub fn candidates(&self, signature: &[u64]) -> Vec<usize> {
+        let mut candidates = Vec::new();
+
+        for (band, bucket) in signature.chunks(self.rows_per_band).zip(&self.buckets) {
+            if let Some(ids) = bucket.get(&hash_band(band)) {
+                candidates.extend_from_slice(ids);
+            }
+        }
+
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+}
+
+fn hash_band(band: &[u64]) -> u64 {
+    let mut hasher = FxHasher::default();
+    for &v in band {
+        hasher.write_u64(v);
+    }
+    hasher.finish()
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexBuilder;
+
+    #[test]
+    fn test_identical_sets_share_signature() {
+        let hasher = MinHasher::new(16);
+        let a = hasher.signature(&[1, 2, 3]);
+        let b = hasher.signature(&[3, 1, 2]);
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 16);
+    }
+
+    #[test]
+    fn test_lsh_recall_on_near_duplicates() {
+        let words = [
+            "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", "hotel", "india",
+            "juliet", "kilo", "lima", "mike", "november", "oscar", "papa",
+        ];
+        let mut records = Vec::new();
+        for i in 0..100 {
+            let text = format!(
+                "{} {} {} {} street {}",
+                words[i % 16],
+                words[(i / 16) % 16],
+                words[(i * 7) % 16],
+                words[(i * 3 + 1) % 16],
+                i
+            );
+            // near duplicate: one character dropped in the middle
+            let mut twin = text.clone();
+            twin.remove(text.len() / 2);
+
+            records.push((i * 2, text));
+            records.push((i * 2 + 1, twin));
+        }
+
+        let builder = IndexBuilder::new(0.5).with_lsh(128, 32);
+        builder.bulk_add(records.clone());
+        let indexer = builder.build();
+
+        let found = records
+            .iter()
+            .filter(|(id, text)| id % 2 == 0 && indexer.lsh_candidates(text).contains(&(id + 1)))
+            .count();
+        assert!(found >= 95, "recall {} of 100", found);
+
+        // verified results come from the band candidates
+        let results = indexer.search_lsh(&records[0].1, 2);
+        assert!(results.iter().any(|r| r.id == 1 && r.distance == 1));
+    }
+}
@@ -1,10 +1,40 @@
-use crony_researcher::index::IndexBuilder;
-use rayon::prelude::*;
-use std::error::Error;
-use std::fs::File;
+use crony_researcher::batch::{RunMetrics, search_all, search_all_checkpointed, search_subset};
+use crony_researcher::cluster::{
+    CanonicalPolicy, canonical_ids, clusters, clusters_within_hops, retain_min_size,
+    save_canonical_ids_to_csv, save_singletons_to_csv, singletons,
+};
+use crony_researcher::distance::{CollationKey, Metric, NormalizeOptions, fold_diacritics};
+use crony_researcher::index::{
+    EmptyRecordPolicy, IndexBuilder, LengthBuckets, RankBy, SearchStats, TokenMode,
+};
+use crony_researcher::input::{Columns, CsvTexts, InputFormat, load_records};
+use crony_researcher::manifest::{ManifestParameters, RunManifest};
+use crony_researcher::results::{
+    CsvAppender, DistanceSummary, EdgeWeight, LineTerminator, OutputFormat, OutputOptions,
+    QualityReport, SimilarityResult, SortOrder, add_output_columns, compare_results,
+    dedup_directed_pairs, dedup_pairs, load_ground_truth, load_results_from_csv, save_results,
+    save_results_per_query, sort_results, sort_results_by, sort_results_by_score,
+    sort_results_by_similarity, suggest_max_distance,
+};
+use crony_researcher::window::{WindowIndex, WindowOptions};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
 use std::time::Instant;
 use structopt::StructOpt;
 
+/// how many records --profile-indexing reports
+const PROFILED_RECORDS: usize = 10;
+
+/// how many records --suggest-threshold samples
+const THRESHOLD_SAMPLE: usize = 1000;
+
+/// file count above which --per-query-dir warns, many filesystems slow down with huge directories
+const PER_QUERY_FILE_WARNING: usize = 100_000;
+
+/// records --low-memory reads from the csv file before indexing them
+const LOW_MEMORY_CHUNK: usize = 10_000;
+
 #[derive(Debug, StructOpt)]
 #[structopt()]
 /// SCAN OF ANY TWINS IN DATASETS
@@ -19,12 +49,207 @@ struct Opt {
     /// max distance is the maximum distance between two strings that are considered similar
     #[structopt(short = "d", long = "max-distance", default_value = "8")]
     max_distance: usize,
+    /// max distance ratio additionally limits the distance to this fraction of the longer text, e.g. 0.15
+    #[structopt(long = "max-distance-ratio")]
+    max_distance_ratio: Option<f64>,
+    /// limit distance per length bucket sets max distance per length of the longer text, e.g.
+    /// "0-10=2,11-30=4,31+=8", or a file with one bucket per line; other lengths keep max distance
+    #[structopt(long = "limit-distance-per-length-bucket")]
+    length_buckets: Option<String>,
+    /// min length ratio drops pairs whose length ratio (shorter / longer) is below this value, e.g. 0.5
+    #[structopt(long = "min-length-ratio")]
+    min_length_ratio: Option<f64>,
+    /// max postings per trigram caps how many records a single trigram contributes as candidates,
+    /// bounds per query work on dirty data at the cost of recall
+    #[structopt(long = "max-postings-per-trigram")]
+    max_postings_per_trigram: Option<usize>,
+    /// min distinct trigrams requires candidates to share at least this many different trigrams with the query
+    #[structopt(long = "min-distinct-trigrams")]
+    min_distinct_trigrams: Option<usize>,
+    /// brute force limit caps how many records of similar length a query is compared with directly when
+    /// no other record shares a trigram with it (e.g. records shorter than a trigram), 0 disables the fallback
+    #[structopt(long = "brute-force-limit")]
+    brute_force_limit: Option<usize>,
+    /// query ids limits the search to these ids (a file with one id per line or a comma separated list),
+    /// their twins are searched in the whole index, for cheap incremental runs
+    #[structopt(long = "query-ids")]
+    query_ids: Option<String>,
+    /// max pairs stops searching once this many pairs were found in total, for bounded exploratory runs
+    #[structopt(long = "max-pairs")]
+    max_pairs: Option<usize>,
+    /// word shingles indexes shingles of this many consecutive words instead of character trigrams,
+    /// for long documents
+    #[structopt(long = "word-shingles")]
+    word_shingles: Option<usize>,
+    /// cjk indexes character bigrams inside Chinese, Japanese and Korean text (trigrams elsewhere),
+    /// ignored with --word-shingles
+    #[structopt(long = "cjk")]
+    cjk: bool,
+    /// include distance zero controls whether exact twins (distance 0) are reported
+    #[structopt(
+        long = "include-distance-zero",
+        default_value = "true",
+        parse(try_from_str)
+    )]
+    include_distance_zero: bool,
+    /// metric is a comma separated list of distance metrics (levenshtein, osa, phonetic, token-set), with more than
+    /// one metric each pair is reported once with the metric that gave the lowest distance
+    #[structopt(long = "metric", default_value = "levenshtein", use_delimiter = true)]
+    metrics: Vec<Metric>,
+    /// transpose cost is the cost of swapping two adjacent characters for the osa metric (default 1, up to 2),
+    /// e.g. 0.5 tolerates swaps more than substitutions
+    #[structopt(long = "transpose-cost")]
+    transpose_cost: Option<f64>,
+    /// token set ratio is the min similarity ratio (0..1, default 0.9) of the token-set metric, which ignores
+    /// the max distance, the length prefilter and the min length ratio; subsets get distance 1, not 0
+    #[structopt(long = "token-set-ratio")]
+    token_set_ratio: Option<f64>,
+    /// max normalized len cuts normalized texts (records and queries) to their first this many chars, bounding
+    /// the cost of one distance computation on pathological fields
+    #[structopt(long = "max-normalized-len")]
+    max_normalized_len: Option<usize>,
     /// data path is the path to the CSV file that contains the data
     #[structopt(short = "f", long = "data-path", default_value = "data.csv")]
     data_path: String,
+    /// input format of the data (and blocklist) file: csv or parquet (requires the parquet feature)
+    #[structopt(long = "input-format", default_value = "csv")]
+    input_format: InputFormat,
+    /// id column is the name of the column with record ids, the first column by default
+    #[structopt(long = "id-column")]
+    id_column: Option<String>,
+    /// text column is the name of the column with texts, the second column by default
+    #[structopt(long = "text-column")]
+    text_column: Option<String>,
+    /// weight as selects the edge weight column: distance (raw edit distance) or ratio (similarity in 0..1,
+    /// written as "weight" for graph tools)
+    #[structopt(long = "weight-as", default_value = "distance")]
+    weight_as: EdgeWeight,
+    /// blocklist path is a CSV (same schema as data) of known bad texts, each record is matched against it
+    /// and twin_id in the results is the id of the blocklist entry
+    #[structopt(long = "blocklist-path")]
+    blocklist_path: Option<String>,
     /// results path is the path to the CSV file that will contain the results
     #[structopt(short = "o", long = "results-path", default_value = "results.csv")]
     results_path: String,
+    /// output format of the results file: csv, json (flat list of pairs), json-nested (twins grouped by query),
+    /// json-adjacency (every id mapped to its direct twins, both directions)
+    /// or parquet (requires the parquet feature)
+    #[structopt(long = "output-format", default_value = "csv")]
+    output_format: OutputFormat,
+    /// per query dir writes one results file per record with twins into this directory (e.g. 1.csv, in the
+    /// output format) instead of the results path, every pair is in the files of both its ids
+    #[structopt(long = "per-query-dir")]
+    per_query_dir: Option<String>,
+    /// always quote quotes every field of the csv results file, not only those that need it
+    #[structopt(long = "always-quote")]
+    always_quote: bool,
+    /// line terminator of the csv results file: lf or crlf
+    #[structopt(long = "line-terminator", default_value = "lf")]
+    line_terminator: LineTerminator,
+    /// flush every appends the pairs found so far to the (csv) results file every this many queries,
+    /// so a crash loses at most the last part, ignored with --sort-output which needs all pairs to sort
+    #[structopt(long = "flush-every", alias = "checkpoint-every")]
+    flush_every: Option<usize>,
+    /// rarity weight adds a score column, the similarity ratio blended with how rare the shared trigrams are
+    /// (0 only similarity, 1 fully weighted by rarity), twins of a query are ordered by it
+    #[structopt(long = "rarity-weight")]
+    rarity_weight: Option<f64>,
+    /// sort output sorts the results file lexicographically by these columns, e.g. query,twin,distance for a
+    /// merge join, instead of the per query ranking of --rank-by and --rarity-weight
+    #[structopt(long = "sort-output")]
+    sort_output: Option<SortOrder>,
+    /// low memory does not keep original texts in memory, they are read back from the (csv) input file
+    /// when output needs them
+    #[structopt(long = "low-memory")]
+    low_memory: bool,
+    /// suggest threshold searches a sample of the records in the index, prints a max distance suggested from their
+    /// nearest neighbor distances and exits without searching
+    #[structopt(long = "suggest-threshold")]
+    suggest_threshold: bool,
+    /// window size switches to long document mode: documents are cut into overlapping windows of this many
+    /// characters, --max-distance applies to windows and documents sharing enough similar windows are reported
+    /// (with the distance of their closest windows)
+    #[structopt(long = "window-size")]
+    window_size: Option<usize>,
+    /// min matching windows is how many similar windows two documents need to share in --window-size mode
+    #[structopt(long = "min-matching-windows", default_value = "2")]
+    min_matching_windows: usize,
+    /// rank by orders the twins of a query: distance (closest first) or ratio (highest similarity ratio
+    /// first, a few edits in a long text rank above the same edits in a short one)
+    #[structopt(long = "rank-by", default_value = "distance")]
+    rank_by: RankBy,
+    /// debug columns adds query_normalized and twin_normalized, the token sorted texts that were compared
+    #[structopt(long = "debug-columns")]
+    debug_columns: bool,
+    /// explain adds the trigrams each pair shares, why it became a candidate
+    #[structopt(long = "explain")]
+    explain: bool,
+    /// original distance adds the distance between the original, not normalized texts of each pair
+    #[structopt(long = "original-distance")]
+    original_distance: bool,
+    /// empty records controls records with empty text: skip, report (print their ids), index (empty records
+    /// are twins of each other) or error (stop when any are found)
+    #[structopt(long = "empty-records", default_value = "skip")]
+    empty_records: EmptyRecordPolicy,
+    /// disk storage is the path of a file where normalized texts are kept instead of memory
+    #[structopt(long = "disk-storage")]
+    disk_storage: Option<String>,
+    /// serve keeps the index resident and answers POST /search queries over HTTP/JSON at this address
+    /// (e.g. :8080 for localhost, 0.0.0.0:8080 for all interfaces) instead of searching all records, requires the serve feature
+    #[structopt(long = "serve")]
+    serve: Option<String>,
+    /// profile indexing times normalization and tokenization of every record and prints the slowest ones
+    #[structopt(long = "profile-indexing")]
+    profile_indexing: bool,
+    /// metrics prints a last line of key=value counters (records, candidates, distance computations, pairs,
+    /// pairs per record) for monitoring
+    #[structopt(long = "metrics")]
+    report_metrics: bool,
+    /// manifest path is the path of a JSON file recording the parameters, input file hash, record count
+    /// and timings of the run
+    #[structopt(long = "manifest-path")]
+    manifest_path: Option<String>,
+    /// compare is the path to results of a previous run, added, removed and changed pairs are reported
+    #[structopt(long = "compare")]
+    compare_path: Option<String>,
+    /// ground truth is the path to a CSV of labeled true duplicate pairs (two id columns), precision, recall
+    /// and F1 of the found pairs are reported
+    #[structopt(long = "ground-truth")]
+    ground_truth: Option<String>,
+    /// canonical output is the path of a CSV mapping every record to a canonical id of its cluster
+    #[structopt(long = "canonical-output")]
+    canonical_output: Option<String>,
+    /// canonical policy picks the canonical record of a cluster: smallest-id or longest-text
+    #[structopt(long = "canonical-policy", default_value = "smallest-id")]
+    canonical_policy: CanonicalPolicy,
+    /// min cluster size leaves clusters with fewer records (singletons are clusters of one) out of --canonical-output
+    #[structopt(long = "min-cluster-size")]
+    min_cluster_size: Option<usize>,
+    /// max cluster hops grows the clusters of --canonical-output at most this many links away from their
+    /// smallest id, so long chains of weak links do not merge into one mega cluster
+    #[structopt(long = "max-cluster-hops")]
+    max_cluster_hops: Option<usize>,
+    /// singletons path is the path of a CSV with ids of records that have no twins
+    #[structopt(long = "singletons-path")]
+    singletons_path: Option<String>,
+    /// singletons with text adds the original text of every singleton to --singletons-path
+    #[structopt(long = "singletons-with-text")]
+    singletons_with_text: bool,
+    /// char map is a comma separated list of replacements applied before normalization, e.g. "&=and,ß=ss"
+    #[structopt(long = "char-map", parse(try_from_str = parse_char_map))]
+    char_map: Option<BTreeMap<char, String>>,
+    /// normalize numbers joins digit groups separated by '-', '.', '/' or parentheses, so number formatting does not affect matching
+    #[structopt(long = "normalize-numbers")]
+    normalize_numbers: bool,
+    /// squeeze repeats collapses runs of the same letter longer than this to this length, e.g. 2: "helllo" -> "hello"
+    #[structopt(long = "squeeze-repeats")]
+    squeeze_repeats: Option<usize>,
+    /// fold diacritics sort sorts tokens ignoring accents, so "Émile Zola" keeps the order of "Emile Zola"
+    #[structopt(long = "fold-diacritics-sort")]
+    fold_diacritics_sort: bool,
+    /// raw uses texts verbatim, without lowercasing, stripping or token sorting (for pre-normalized data)
+    #[structopt(long = "raw")]
+    raw: bool,
 }
 
 fn main() {
@@ -42,116 +267,727 @@ fn main() {
     let max_distance = opt.max_distance;
     let data_path = opt.data_path;
     let results_path = opt.results_path;
+    let normalize_options = NormalizeOptions {
+        char_map: opt.char_map.unwrap_or_default(),
+        normalize_numbers: opt.normalize_numbers,
+        squeeze_repeats: opt.squeeze_repeats,
+        raw: opt.raw,
+        collation_key: opt
+            .fold_diacritics_sort
+            .then_some(fold_diacritics as CollationKey),
+    };
 
     let start = Instant::now();
 
     println!("Loading data from CSV...");
-    let data = match load_data_from_csv(&data_path) {
-        Ok(d) => d,
-        Err(e) => {
-            eprintln!("Error reading CSV: {}", e);
-            return;
+    let columns = Columns {
+        id_column: opt.id_column.clone(),
+        text_column: opt.text_column.clone(),
+    };
+    // with --low-memory the indexed csv file is streamed into the index, it is never loaded as a whole
+    let stream_indexed = opt.low_memory && opt.input_format == InputFormat::Csv;
+    let data = if stream_indexed && opt.blocklist_path.is_none() {
+        Vec::new()
+    } else {
+        match load_records(&data_path, opt.input_format, &columns) {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!("Error reading data: {}", e);
+                return;
+            }
         }
     };
     println!("Data loaded successfully.\n");
+    let mut input_record_count = data.len();
 
-    let query_ids: Vec<usize> = data.iter().map(|(id, _)| *id).collect();
+    // with a blocklist, the blocklist is indexed and every input record is searched against it
+    let indexed_path = opt
+        .blocklist_path
+        .as_deref()
+        .unwrap_or(&data_path)
+        .to_string();
+    let (data, blocklist_queries) = match &opt.blocklist_path {
+        Some(_) if stream_indexed => (Vec::new(), Some(data)),
+        Some(blocklist_path) => match load_records(blocklist_path, opt.input_format, &columns) {
+            Ok(blocklist) => (blocklist, Some(data)),
+            Err(e) => {
+                eprintln!("Error reading blocklist: {}", e);
+                return;
+            }
+        },
+        None => (data, None),
+    };
 
-    println!("Indexing {} records...", data.len());
+    if stream_indexed {
+        println!("Indexing records of {}...", indexed_path);
+    } else {
+        println!("Indexing {} records...", data.len());
+    }
+    let index_start = Instant::now();
 
-    let builder = IndexBuilder::new(fuzz_filter);
+    let token_mode = match opt.word_shingles {
+        Some(n) => TokenMode::Words(n),
+        None if opt.cjk => TokenMode::Cjk,
+        None => TokenMode::Trigrams,
+    };
+    let length_buckets = match opt.length_buckets.as_deref().map(parse_length_buckets) {
+        Some(Ok(buckets)) => Some(buckets),
+        Some(Err(e)) => {
+            eprintln!("Error parsing length buckets: {}", e);
+            return;
+        }
+        None => None,
+    };
+    let manifest_parameters = ManifestParameters {
+        fuzz_filter,
+        max_distance,
+        max_distance_ratio: opt.max_distance_ratio,
+        length_buckets: length_buckets.clone(),
+        min_length_ratio: opt.min_length_ratio,
+        max_postings_per_trigram: opt.max_postings_per_trigram,
+        min_distinct_trigrams: opt.min_distinct_trigrams,
+        brute_force_limit: opt.brute_force_limit,
+        include_distance_zero: opt.include_distance_zero,
+        metrics: opt.metrics.clone(),
+        transpose_cost: opt.transpose_cost,
+        token_set_ratio: opt.token_set_ratio,
+        max_normalized_len: opt.max_normalized_len,
+        token_mode,
+        normalization: normalize_options.clone(),
+    };
 
-    builder.bulk_add(data);
+    let mut builder = IndexBuilder::new(fuzz_filter)
+        .with_normalize_options(normalize_options)
+        .with_include_exact(opt.include_distance_zero)
+        .with_metrics(opt.metrics.clone())
+        .with_token_mode(token_mode)
+        .with_empty_record_policy(opt.empty_records)
+        .with_rank_by(opt.rank_by);
+    if let Some(ratio) = opt.max_distance_ratio {
+        builder = builder.with_max_distance_ratio(ratio);
+    }
+    if let Some(buckets) = length_buckets {
+        builder = builder.with_length_buckets(buckets);
+    }
+    if let Some(ratio) = opt.min_length_ratio {
+        if opt.metrics.contains(&Metric::TokenSet) {
+            println!("--min-length-ratio is ignored by the token-set metric");
+        }
+        builder = builder.with_min_length_ratio(ratio);
+    }
+    if let Some(cost) = opt.transpose_cost {
+        builder = builder.with_transpose_cost(cost);
+    }
+    if let Some(ratio) = opt.token_set_ratio {
+        builder = builder.with_token_set_ratio(ratio);
+    }
+    if let Some(max_len) = opt.max_normalized_len {
+        builder = builder.with_max_normalized_len(max_len);
+    }
+    if let Some(limit) = opt.brute_force_limit {
+        builder = builder.with_brute_force_limit(limit);
+    }
+    if let Some(k) = opt.min_distinct_trigrams {
+        builder = builder.with_min_distinct_trigrams(k);
+    }
+    if opt.profile_indexing {
+        builder = builder.with_profile_indexing(PROFILED_RECORDS);
+    }
+    if let Some(cap) = opt.max_postings_per_trigram {
+        builder = builder.with_max_postings_per_trigram(cap);
+    }
+    if opt.low_memory {
+        builder = match opt.input_format {
+            // the csv file becomes the source of original texts once it is streamed in
+            InputFormat::Csv => builder.with_low_memory(),
+            InputFormat::Parquet => {
+                println!(
+                    "--low-memory with parquet input: original texts are not available for output"
+                );
+                builder.with_low_memory()
+            }
+        };
+    }
+
+    if let Some(size) = opt.window_size {
+        // window mode only saves the document pairs, options working on the record pairs do not apply
+        let unsupported = [
+            ("--blocklist-path", blocklist_queries.is_some()),
+            ("--low-memory", opt.low_memory),
+            ("--query-ids", opt.query_ids.is_some()),
+            ("--per-query-dir", opt.per_query_dir.is_some()),
+            ("--flush-every", opt.flush_every.is_some()),
+            ("--sort-output", opt.sort_output.is_some()),
+            ("--canonical-output", opt.canonical_output.is_some()),
+            ("--singletons-path", opt.singletons_path.is_some()),
+            ("--manifest-path", opt.manifest_path.is_some()),
+            ("--metrics", opt.report_metrics),
+            ("--compare", opt.compare_path.is_some()),
+            ("--ground-truth", opt.ground_truth.is_some()),
+            ("--serve", opt.serve.is_some()),
+        ];
+        if let Some((flag, _)) = unsupported.iter().find(|(_, set)| *set) {
+            eprintln!("--window-size cannot be combined with {}", flag);
+            std::process::exit(1);
+        }
+        let options = WindowOptions::new(size, opt.min_matching_windows);
+        let output_options = OutputOptions {
+            always_quote: opt.always_quote,
+            line_terminator: opt.line_terminator,
+            ..Default::default()
+        };
+        search_windows(
+            builder,
+            data,
+            options,
+            max_distance,
+            &results_path,
+            opt.output_format,
+            &output_options,
+        );
+        return;
+    }
 
-    let indexer = builder.build();
+    if let Some(path) = &opt.disk_storage {
+        builder = match builder.with_disk_storage(Path::new(path)) {
+            Ok(builder) => builder,
+            Err(e) => {
+                eprintln!("Error creating disk storage: {}", e);
+                return;
+            }
+        };
+    }
+    if stream_indexed {
+        let mut chunk = Vec::with_capacity(LOW_MEMORY_CHUNK);
+        let mut rows = 0;
+        let texts = CsvTexts::scan(&indexed_path, &columns, |id, text| {
+            chunk.push((id, text.to_string()));
+            rows += 1;
+            if chunk.len() == LOW_MEMORY_CHUNK {
+                builder.bulk_add(std::mem::take(&mut chunk));
+            }
+        });
+        builder.bulk_add(chunk);
+        match texts {
+            Ok(texts) => builder = builder.with_original_source(Box::new(texts)),
+            Err(e) => {
+                eprintln!("Error reading data: {}", e);
+                return;
+            }
+        }
+        if blocklist_queries.is_none() {
+            input_record_count = rows;
+        }
+    } else {
+        builder.bulk_add(data);
+    }
+
+    let indexer = match builder.try_build() {
+        Ok(indexer) => indexer,
+        Err(e) => {
+            eprintln!("Error building the index: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let duration_index = index_start.elapsed();
+    // records dropped while indexing (empty texts) have no twins and are left out of every search
+    let indexed_ids = indexer.ids();
+    if let Some(max_len) = opt.max_normalized_len {
+        let truncated = indexer.truncated_records();
+        if truncated > 0 {
+            println!(
+                "Truncated {} normalized texts to {} chars",
+                truncated, max_len
+            );
+        }
+    }
     println!("Indexing completed\n");
 
+    if opt.profile_indexing {
+        println!("Slowest records to preprocess:");
+        for (id, elapsed) in indexer.slowest_records() {
+            let chars = indexer.original(*id).map_or(0, |text| text.chars().count());
+            println!("  id {}: {:?} ({} chars)", id, elapsed, chars);
+        }
+        println!();
+    }
+
+    let empty_records = indexer.empty_records();
+    match opt.empty_records {
+        EmptyRecordPolicy::Report | EmptyRecordPolicy::Index if !empty_records.is_empty() => {
+            println!(
+                "Found {} empty records: {:?}\n",
+                empty_records.len(),
+                empty_records
+            );
+        }
+        _ => {}
+    }
+
+    if opt.suggest_threshold {
+        // evenly spaced over the input, so sorted inputs are not only sampled at the start
+        let step = indexed_ids.len().div_ceil(THRESHOLD_SAMPLE).max(1);
+        let sample: Vec<usize> = indexed_ids.iter().copied().step_by(step).collect();
+        match suggest_max_distance(&indexer, &sample) {
+            Some(suggestion) => {
+                if let Some(summary) = DistanceSummary::from_distances(&suggestion.nearest) {
+                    println!("Nearest neighbor distances - {}", summary);
+                }
+                println!("{}", suggestion);
+            }
+            None => println!("Not enough records to suggest a max distance"),
+        }
+        return;
+    }
+
+    if let Some(addr) = &opt.serve {
+        serve(&indexer, addr, max_distance);
+        return;
+    }
+
+    let multiple_metrics = opt.metrics.len() > 1;
+    // blocklist queries and twins are not both in the index, their texts and lengths are not known
+    let output_options = OutputOptions {
+        metric_column: multiple_metrics,
+        debug_columns: opt.debug_columns && blocklist_queries.is_none(),
+        weight_as: match blocklist_queries {
+            Some(_) => EdgeWeight::Distance,
+            None => opt.weight_as,
+        },
+        original_distance: opt.original_distance && blocklist_queries.is_none(),
+        rarity_weight: opt.rarity_weight.filter(|_| blocklist_queries.is_none()),
+        explain: opt.explain && blocklist_queries.is_none(),
+        always_quote: opt.always_quote,
+        line_terminator: opt.line_terminator,
+        rank_by: match blocklist_queries {
+            Some(_) => RankBy::Distance,
+            None => opt.rank_by,
+        },
+    };
+    let sort = |results: &mut Vec<SimilarityResult>| {
+        if let Some(order) = &opt.sort_output {
+            return sort_results_by(results, order);
+        }
+        match (output_options.rarity_weight, output_options.rank_by) {
+            (Some(_), _) => sort_results_by_score(results),
+            (None, RankBy::Ratio) => sort_results_by_similarity(results),
+            (None, RankBy::Distance) => sort_results(results),
+        }
+    };
+
+    let flush_every = match opt.flush_every {
+        Some(_) if opt.output_format != OutputFormat::Csv => {
+            println!("--flush-every only applies to csv output, results are saved at the end");
+            None
+        }
+        Some(_) if opt.per_query_dir.is_some() => {
+            println!("--flush-every is ignored with --per-query-dir");
+            None
+        }
+        // parts could only be sorted one by one, the file would not be sorted as a whole
+        Some(_) if opt.sort_output.is_some() => {
+            println!(
+                "--flush-every is ignored with --sort-output, results are saved sorted at the end"
+            );
+            None
+        }
+        Some(_) if blocklist_queries.is_some() || opt.query_ids.is_some() => {
+            println!("--flush-every is ignored with --blocklist-path and --query-ids");
+            None
+        }
+        every => every,
+    };
+
+    let query_subset = match opt.query_ids.as_deref().map(parse_query_ids) {
+        Some(Ok(subset)) => Some(subset),
+        Some(Err(e)) => {
+            eprintln!("Error reading query ids: {}", e);
+            return;
+        }
+        None => None,
+    };
+
     println!("Starting to search for twins...");
     let search_start = Instant::now();
 
-    let mut saved_results: Vec<SimilarityResult> = query_ids
-        .into_par_iter()
-        .flat_map_iter(|query_id| {
-            indexer
-                .search_by_id(query_id, max_distance)
+    let search_stats: SearchStats;
+    let mut pairs_truncated = false;
+    let mut saved_results: Vec<SimilarityResult> = match &blocklist_queries {
+        Some(records) => {
+            let (flagged, stats) = indexer.search_against_with_stats(records, max_distance);
+            search_stats = stats;
+            let mut results: Vec<SimilarityResult> = flagged
                 .into_iter()
-                .map(move |a| SimilarityResult {
-                    query_id,
-                    twin_id: a.id,
-                    distance: a.distance,
-                })
-        })
-        .collect();
+                .map(|(query_id, a)| SimilarityResult::new(query_id, a.id, a.distance, a.metric))
+                .collect();
+            if let Some(limit) = opt.max_pairs {
+                pairs_truncated = results.len() > limit;
+                results.truncate(limit);
+            }
+            results
+        }
+        None => {
+            let output = match (&query_subset, flush_every) {
+                (Some(subset), _) => search_subset(&indexer, subset, max_distance, opt.max_pairs),
+                (None, Some(every)) => {
+                    // every part is finished and appended to the results file as it completes
+                    let output = CsvAppender::create(&results_path, &output_options).and_then(
+                        |mut appender| {
+                            search_all_checkpointed(
+                                &indexer,
+                                &indexed_ids,
+                                max_distance,
+                                opt.max_pairs,
+                                every,
+                                |part| {
+                                    if multiple_metrics {
+                                        *part = dedup_pairs(std::mem::take(part));
+                                    }
+                                    add_output_columns(part, &indexer, &output_options);
+                                    sort(part);
+                                    appender.append(part)
+                                },
+                            )
+                        },
+                    );
+                    match output {
+                        Ok(output) => output,
+                        Err(e) => {
+                            eprintln!("Error saving results: {}", e);
+                            return;
+                        }
+                    }
+                }
+                (None, None) => search_all(&indexer, &indexed_ids, max_distance, opt.max_pairs),
+            };
+            if !output.failed_queries.is_empty() {
+                eprintln!(
+                    "Search failed for {} queries, they were skipped: {:?}",
+                    output.failed_queries.len(),
+                    output.failed_queries
+                );
+            }
+            search_stats = output.stats;
+            pairs_truncated = output.truncated;
+            output.results
+        }
+    };
+
+    if pairs_truncated {
+        println!("Reached --max-pairs limit, remaining queries were skipped");
+    }
+
+    // with a query subset a pair of two queried ids is found from both sides,
+    // checkpointed parts are already deduplicated and sorted
+    if flush_every.is_none() {
+        // blocklist twin ids are a separate id space, (a, b) and (b, a) are different hits
+        if blocklist_queries.is_some() {
+            if multiple_metrics {
+                saved_results = dedup_directed_pairs(saved_results);
+            }
+        } else if multiple_metrics || opt.query_ids.is_some() {
+            saved_results = dedup_pairs(saved_results);
+        }
 
-    // there must be something to replace unstable sort
-    saved_results.sort_unstable_by_key(|r| r.query_id);
+        // one global sort, per query results come unsorted from the batch search
+        add_output_columns(&mut saved_results, &indexer, &output_options);
+        sort(&mut saved_results);
+    }
 
     let duration_search = search_start.elapsed();
 
+    // records that could not be read were skipped, the results would be incomplete
+    if let Some(e) = indexer.take_disk_error() {
+        eprintln!("Error reading disk storage: {}", e);
+        std::process::exit(1);
+    }
+
     println!("--------------------------------------------------");
 
-    println!(
-        "Time elapsed on search: {:?}",
-        duration_search
-    );
+    println!("Time elapsed on search: {:?}", duration_search);
 
     println!("Total unique twins found: {}", saved_results.len());
 
+    if let Some(summary) = DistanceSummary::from_results(&saved_results) {
+        println!("Distances - {}", summary);
+    }
+
     let duration = start.elapsed();
 
-    match save_results_to_csv(&saved_results, &results_path) {
-        Ok(_) => println!("Results saved to results.csv"),
-        Err(e) => eprintln!("Error saving results: {}", e),
+    if let Some(dir) = &opt.per_query_dir {
+        let files = saved_results
+            .iter()
+            .flat_map(|r| [r.query_id, r.twin_id])
+            .collect::<HashSet<usize>>()
+            .len();
+        if files > PER_QUERY_FILE_WARNING {
+            println!(
+                "Warning: --per-query-dir writes {} files into one directory, some filesystems get slow or run out of inodes",
+                files
+            );
+        }
+        match save_results_per_query(&saved_results, dir, opt.output_format, &output_options) {
+            Ok(files) => println!("Results of {} records saved to {}", files, dir),
+            Err(e) => eprintln!("Error saving results: {}", e),
+        }
+    } else if flush_every.is_some() {
+        println!("Results saved to {}", results_path);
+    } else {
+        match save_results(
+            &saved_results,
+            &results_path,
+            opt.output_format,
+            &output_options,
+        ) {
+            Ok(_) => println!("Results saved to {}", results_path),
+            Err(e) => eprintln!("Error saving results: {}", e),
+        }
+    }
+
+    if opt.canonical_output.is_some() && blocklist_queries.is_some() {
+        println!(
+            "--canonical-output is ignored with --blocklist-path, pairs link records of two files"
+        );
+    } else if opt.canonical_output.is_some() && pairs_truncated {
+        println!(
+            "--canonical-output is skipped, --max-pairs cut the pairs so clusters would be incomplete"
+        );
+    } else if let Some(canonical_path) = &opt.canonical_output {
+        let mut clusters = match opt.max_cluster_hops {
+            Some(hops) => clusters_within_hops(&indexed_ids, &saved_results, hops),
+            None => clusters(&indexed_ids, &saved_results),
+        };
+        if let Some(min_size) = opt.min_cluster_size {
+            retain_min_size(&mut clusters, min_size);
+        }
+        let mapping = canonical_ids(&clusters, opt.canonical_policy, |id| {
+            indexer.original(id).map_or(0, |text| text.chars().count())
+        });
+
+        match save_canonical_ids_to_csv(&mapping, canonical_path) {
+            Ok(_) => println!(
+                "Canonical ids of {} clusters saved to {}",
+                clusters.len(),
+                canonical_path
+            ),
+            Err(e) => eprintln!("Error saving canonical ids: {}", e),
+        }
+    }
+
+    if opt.singletons_path.is_some() && pairs_truncated {
+        println!(
+            "--singletons-path is skipped, --max-pairs cut the pairs so records with twins would be listed"
+        );
+    } else if let Some(singletons_path) = &opt.singletons_path {
+        // with a blocklist only input records are reported, twin ids belong to the blocklist
+        let (lonely, texts) = match &blocklist_queries {
+            Some(records) => {
+                let ids: Vec<usize> = records.iter().map(|(id, _)| *id).collect();
+                let lonely = singletons(&ids, saved_results.iter().map(|r| r.query_id));
+                let by_id: HashMap<usize, &str> = records
+                    .iter()
+                    .map(|(id, text)| (*id, text.as_str()))
+                    .collect();
+                let texts: Vec<Cow<str>> =
+                    lonely.iter().map(|id| Cow::Borrowed(by_id[id])).collect();
+                (lonely, texts)
+            }
+            None => {
+                // only queried records were searched, the others are not known to be lonely
+                let lonely = singletons(
+                    query_subset.as_deref().unwrap_or(&indexed_ids),
+                    saved_results.iter().flat_map(|r| [r.query_id, r.twin_id]),
+                );
+                let texts = lonely
+                    .iter()
+                    .map(|&id| indexer.original(id).unwrap_or_default())
+                    .collect();
+                (lonely, texts)
+            }
+        };
+
+        let texts: Vec<&str> = texts.iter().map(|text| text.as_ref()).collect();
+        let texts = opt.singletons_with_text.then_some(texts.as_slice());
+        match save_singletons_to_csv(&lonely, texts, singletons_path) {
+            Ok(_) => println!(
+                "{} records without twins saved to {}",
+                lonely.len(),
+                singletons_path
+            ),
+            Err(e) => eprintln!("Error saving singletons: {}", e),
+        }
+    }
+
+    if let Some(compare_path) = opt.compare_path {
+        match load_results_from_csv(&compare_path) {
+            Ok(baseline) => {
+                let diff = compare_results(&baseline, &saved_results);
+                println!(
+                    "Compared with {}: {} added, {} removed, {} distance changed",
+                    compare_path,
+                    diff.added.len(),
+                    diff.removed.len(),
+                    diff.changed.len()
+                );
+            }
+            Err(e) => eprintln!("Error reading baseline results: {}", e),
+        }
+    }
+
+    if let Some(ground_truth_path) = &opt.ground_truth {
+        match load_ground_truth(ground_truth_path) {
+            Ok(ground_truth) => println!(
+                "Quality against {}: {}",
+                ground_truth_path,
+                QualityReport::evaluate(&saved_results, &ground_truth)
+            ),
+            Err(e) => eprintln!("Error reading ground truth: {}", e),
+        }
+    }
+
+    if let Some(manifest_path) = &opt.manifest_path {
+        let manifest =
+            RunManifest::new(manifest_parameters, &data_path, input_record_count).map(|m| {
+                m.with_results(
+                    saved_results.len(),
+                    duration_index,
+                    duration_search,
+                    duration,
+                )
+            });
+
+        match manifest
+            .map_err(Into::into)
+            .and_then(|m| m.save(manifest_path))
+        {
+            Ok(_) => println!("Run manifest saved to {}", manifest_path),
+            Err(e) => eprintln!("Error saving run manifest: {}", e),
+        }
     }
 
     println!("\nProgram execution time: {:?}", duration);
+
+    if opt.report_metrics {
+        println!(
+            "{}",
+            RunMetrics::new(indexer.len(), &search_stats, saved_results.len())
+        );
+    }
 }
 
-#[derive(Debug)]
-pub struct SimilarityResult {
-    pub query_id: usize,
-    pub twin_id: usize,
-    pub distance: usize,
+/// ids from a file (one id per line, or the first column of a CSV with a header) or a comma separated list
+fn parse_query_ids(arg: &str) -> Result<Vec<usize>, String> {
+    let content = match std::fs::read_to_string(arg) {
+        Ok(content) => content,
+        Err(_) if !Path::new(arg).exists() => arg.replace(',', "\n"),
+        Err(e) => return Err(format!("cannot read {}: {}", arg, e)),
+    };
+
+    let mut ids = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let field = line.split(',').next().unwrap_or_default().trim();
+        if field.is_empty() {
+            continue;
+        }
+        match field.parse() {
+            Ok(id) => ids.push(id),
+            // header line of a CSV
+            Err(_) if i == 0 => continue,
+            Err(_) => return Err(format!("invalid id '{}'", field)),
+        }
+    }
+
+    Ok(ids)
 }
 
-// there will be problem when id is not a number
-/// load data from csv
-fn load_data_from_csv(file_path: &str) -> Result<Vec<(usize, String)>, Box<dyn Error>> {
-    let file = File::open(file_path)?;
-    let mut rdr = csv::Reader::from_reader(file);
-    let mut records = Vec::new();
+/// --window-size mode, pairs of documents sharing similar windows
+fn search_windows(
+    builder: IndexBuilder,
+    data: Vec<(usize, String)>,
+    options: WindowOptions,
+    max_distance: usize,
+    results_path: &str,
+    output_format: OutputFormat,
+    output_options: &OutputOptions,
+) {
+    let index_start = Instant::now();
+    let windows = WindowIndex::build(builder, data, options);
+    println!(
+        "Indexed {} windows in {:?}\n",
+        windows.window_count(),
+        index_start.elapsed()
+    );
+
+    let search_start = Instant::now();
+    let results: Vec<SimilarityResult> = windows
+        .document_matches(max_distance)
+        .into_iter()
+        .map(|m| SimilarityResult::new(m.query_id, m.twin_id, m.distance, m.metric))
+        .collect();
+    println!("Time elapsed on search: {:?}", search_start.elapsed());
+    println!("Documents sharing windows: {}", results.len());
 
-    for result in rdr.records() {
-        let record = result?;
+    match save_results(&results, results_path, output_format, output_options) {
+        Ok(_) => println!("Results saved to {}", results_path),
+        Err(e) => eprintln!("Error saving results: {}", e),
+    }
+}
 
-        let id: usize = record[0].trim().parse()?; // just to be sure that id is a number, trim any whitespace
-        let text: String = record[1].to_string();
+#[cfg(feature = "serve")]
+fn serve(indexer: &crony_researcher::index::Indexer, addr: &str, max_distance: usize) {
+    use crony_researcher::server::SearchServer;
 
-        records.push((id, text));
+    match SearchServer::bind(addr) {
+        Ok(server) => {
+            println!("Serving POST /search on {}", addr);
+            server.run(indexer, max_distance);
+        }
+        Err(e) => {
+            eprintln!("Error starting server on {}: {}", addr, e);
+            std::process::exit(1);
+        }
     }
+}
 
-    Ok(records)
+#[cfg(not(feature = "serve"))]
+fn serve(_indexer: &crony_researcher::index::Indexer, _addr: &str, _max_distance: usize) {
+    eprintln!("--serve requires the `serve` cargo feature");
+    std::process::exit(1);
 }
 
-/// export data to csv
-fn save_results_to_csv(
-    results: &Vec<SimilarityResult>,
-    file_path: &str,
-) -> Result<(), Box<dyn Error>> {
-    let file = File::create(file_path)?;
-    let mut wtr = csv::Writer::from_writer(file);
+/// length buckets inline or from a file with one bucket per line
+fn parse_length_buckets(arg: &str) -> Result<LengthBuckets, String> {
+    if Path::new(arg).is_file() {
+        let content =
+            std::fs::read_to_string(arg).map_err(|e| format!("cannot read {}: {}", arg, e))?;
+        content.parse()
+    } else {
+        arg.parse()
+    }
+}
 
-    // headers
-    wtr.write_record(&["query_id", "twin_id", "distance"])?;
+/// parse "from=to,from=to" pairs, "from" must be a single character
+fn parse_char_map(s: &str) -> Result<BTreeMap<char, String>, String> {
+    let mut map = BTreeMap::new();
 
-    for result in results {
-        wtr.write_record(&[
-            result.query_id.to_string(),
-            result.twin_id.to_string(),
-            result.distance.to_string(),
-        ])?;
+    for pair in s.split(',').filter(|p| !p.is_empty()) {
+        let (from, to) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("invalid char map entry '{}', expected from=to", pair))?;
+
+        let mut chars = from.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => {
+                map.insert(c, to.to_string());
+            }
+            _ => {
+                return Err(format!(
+                    "char map key '{}' must be a single character",
+                    from
+                ));
+            }
+        }
     }
 
-    wtr.flush()?;
-    Ok(())
+    Ok(map)
 }
@@ -0,0 +1,220 @@
+use crate::distance::{Metric, NormalizeOptions};
+use crate::index::{LengthBuckets, TokenMode};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Read};
+use std::time::Duration;
+
+/// Settings a run was made with, everything that affects which pairs are found.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestParameters {
+    pub fuzz_filter: f64,
+    pub max_distance: usize,
+    pub max_distance_ratio: Option<f64>,
+    pub length_buckets: Option<LengthBuckets>,
+    pub min_length_ratio: Option<f64>,
+    pub max_postings_per_trigram: Option<usize>,
+    pub min_distinct_trigrams: Option<usize>,
+    pub brute_force_limit: Option<usize>,
+    pub include_distance_zero: bool,
+    pub metrics: Vec<Metric>,
+    pub transpose_cost: Option<f64>,
+    pub token_set_ratio: Option<f64>,
+    pub max_normalized_len: Option<usize>,
+    pub token_mode: TokenMode,
+    pub normalization: NormalizeOptions,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestInput {
+    pub path: String,
+    /// hex encoded sha256 of the file content
+    pub sha256: String,
+    pub record_count: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ManifestTimings {
+    pub index_ms: u128,
+    pub search_ms: u128,
+    pub total_ms: u128,
+}
+
+/// Record of a run written by `--manifest-path`, makes results traceable to the exact settings and input.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunManifest {
+    pub version: &'static str,
+    pub parameters: ManifestParameters,
+    pub input: ManifestInput,
+    pub pairs: usize,
+    pub timings: ManifestTimings,
+}
+
+impl RunManifest {
+    /// Hashes the input file, `record_count` is the number of records loaded from it.
+    pub fn new(
+        parameters: ManifestParameters,
+        input_path: &str,
+        record_count: usize,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            version: env!("CARGO_PKG_VERSION"),
+            parameters,
+            input: ManifestInput {
+                path: input_path.to_string(),
+                sha256: sha256_file(input_path)?,
+                record_count,
+            },
+            pairs: 0,
+            timings: ManifestTimings::default(),
+        })
+    }
+
+    pub fn with_results(
+        mut self,
+        pairs: usize,
+        index: Duration,
+        search: Duration,
+        total: Duration,
+    ) -> Self {
+        self.pairs = pairs;
+        self.timings = ManifestTimings {
+            index_ms: index.as_millis(),
+            search_ms: search.as_millis(),
+            total_ms: total.as_millis(),
+        };
+        self
+    }
+
+    pub fn save(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let writer = BufWriter::new(File::create(file_path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+fn sha256_file(path: &str) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{Columns, InputFormat, load_records};
+
+    fn parameters(normalization: NormalizeOptions) -> ManifestParameters {
+        ManifestParameters {
+            fuzz_filter: 0.7,
+            max_distance: 3,
+            max_distance_ratio: None,
+            length_buckets: None,
+            min_length_ratio: Some(0.5),
+            max_postings_per_trigram: None,
+            min_distinct_trigrams: None,
+            brute_force_limit: None,
+            include_distance_zero: true,
+            metrics: vec![Metric::Levenshtein, Metric::Osa],
+            transpose_cost: None,
+            token_set_ratio: None,
+            max_normalized_len: None,
+            token_mode: TokenMode::Words(2),
+            normalization,
+        }
+    }
+
+    #[test]
+    fn test_manifest_records_parameters() {
+        let data_path = std::env::temp_dir()
+            .join(format!("crony_manifest_data_{}.csv", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let manifest_path = format!("{}.json", data_path);
+        std::fs::write(
+            &data_path,
+            "id,text\n1,hello world\n2,hello worlds\n3,foo\n",
+        )
+        .unwrap();
+
+        let records = load_records(&data_path, InputFormat::Csv, &Columns::default()).unwrap();
+        let parameters = parameters(NormalizeOptions {
+            normalize_numbers: true,
+            ..Default::default()
+        });
+        RunManifest::new(parameters, &data_path, records.len())
+            .unwrap()
+            .with_results(
+                1,
+                Duration::from_millis(5),
+                Duration::from_millis(7),
+                Duration::from_millis(20),
+            )
+            .save(&manifest_path)
+            .unwrap();
+
+        let content = std::fs::read_to_string(&manifest_path).unwrap();
+        std::fs::remove_file(&manifest_path).unwrap();
+        std::fs::remove_file(&data_path).unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(&content).unwrap();
+
+        assert_eq!(manifest["input"]["record_count"], 3);
+        assert_eq!(manifest["input"]["sha256"].as_str().unwrap().len(), 64);
+        assert_eq!(manifest["parameters"]["fuzz_filter"], 0.7);
+        assert_eq!(manifest["parameters"]["max_distance"], 3);
+        assert_eq!(manifest["parameters"]["min_length_ratio"], 0.5);
+        assert_eq!(
+            manifest["parameters"]["metrics"],
+            serde_json::json!(["levenshtein", "osa"])
+        );
+        assert_eq!(
+            manifest["parameters"]["token_mode"],
+            serde_json::json!({"words": 2})
+        );
+        assert_eq!(
+            manifest["parameters"]["normalization"]["normalize_numbers"],
+            true
+        );
+        assert_eq!(manifest["timings"]["search_ms"], 7);
+        assert_eq!(manifest["pairs"], 1);
+    }
+
+    #[test]
+    fn test_manifest_char_map_is_stable() {
+        let rules = [
+            ('&', "and"),
+            ('ß', "ss"),
+            ('@', "at"),
+            ('+', "plus"),
+            ('é', "e"),
+        ];
+        let manifest = |rules: &mut dyn Iterator<Item = &(char, &str)>| {
+            let mut normalization = NormalizeOptions::default();
+            for &(c, replacement) in rules {
+                normalization.char_map.insert(c, replacement.to_string());
+            }
+            serde_json::to_string_pretty(&parameters(normalization)).unwrap()
+        };
+
+        // the order rules were given in does not change the output
+        let first = manifest(&mut rules.iter());
+        assert_eq!(first, manifest(&mut rules.iter().rev()));
+        assert!(first.find("\"&\"").unwrap() < first.find("\"ß\"").unwrap());
+    }
+}
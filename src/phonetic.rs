@@ -0,0 +1,619 @@
+use crate::distance::fold_diacritics;
+use rustc_hash::FxHasher;
+use std::hash::Hasher;
+
+/// Codes are cut to this length, as in the original algorithm.
+const MAX_CODE_LEN: usize = 4;
+
+/// Double Metaphone (Lawrence Philips) of a single word, returns (primary, secondary) codes.
+/// The secondary code differs only for words with an alternative pronunciation.
+pub fn double_metaphone(word: &str) -> (String, String) {
+    let chars: Vec<char> = fold_diacritics(&word.to_lowercase())
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .flat_map(|c| c.to_uppercase())
+        .collect();
+
+    let mut m = Metaphone {
+        word: chars,
+        primary: String::new(),
+        secondary: String::new(),
+    };
+    m.encode();
+
+    m.primary.truncate(MAX_CODE_LEN);
+    m.secondary.truncate(MAX_CODE_LEN);
+    (m.primary, m.secondary)
+}
+
+/// Blocking keys of a normalized text, one for the primary and one for the secondary codes of all
+/// its tokens (sorted, so token order does not matter). Tokens without a code (numbers) are skipped.
+pub fn phonetic_keys<'a>(tokens: impl Iterator<Item = &'a str>) -> Vec<u64> {
+    let (mut primary, mut secondary): (Vec<String>, Vec<String>) = tokens
+        .map(double_metaphone)
+        .filter(|(p, s)| !p.is_empty() || !s.is_empty())
+        .unzip();
+
+    if primary.is_empty() {
+        return vec![];
+    }
+
+    primary.sort_unstable();
+    secondary.sort_unstable();
+
+    let mut keys = vec![hash_codes(&primary), hash_codes(&secondary)];
+    keys.dedup();
+    keys
+}
+
+fn hash_codes(codes: &[String]) -> u64 {
+    let mut hasher = FxHasher::default();
+    for code in codes {
+        hasher.write(code.as_bytes());
+        hasher.write_u8(0xff);
+    }
+    hasher.finish()
+}
+
+struct Metaphone {
+    word: Vec<char>,
+    primary: String,
+    secondary: String,
+}
+
+impl Metaphone {
+    fn at(&self, i: isize) -> char {
+        if i < 0 {
+            return '\0';
+        }
+        self.word.get(i as usize).copied().unwrap_or('\0')
+    }
+
+    /// substring of `len` chars starting at `start` is one of `options`
+    fn string_at(&self, start: isize, len: usize, options: &[&str]) -> bool {
+        if start < 0 || start as usize + len > self.word.len() {
+            return false;
+        }
+        let start = start as usize;
+        let sub: String = self.word[start..start + len].iter().collect();
+        options.contains(&sub.as_str())
+    }
+
+    fn is_vowel(&self, i: isize) -> bool {
+        matches!(self.at(i), 'A' | 'E' | 'I' | 'O' | 'U' | 'Y')
+    }
+
+    fn slavo_germanic(&self) -> bool {
+        let word: String = self.word.iter().collect();
+        word.contains('W') || word.contains('K') || word.contains("CZ") || word.contains("WITZ")
+    }
+
+    fn add(&mut self, code: &str) {
+        self.primary.push_str(code);
+        self.secondary.push_str(code);
+    }
+
+    fn add_alt(&mut self, primary: &str, secondary: &str) {
+        self.primary.push_str(primary);
+        self.secondary.push_str(secondary);
+    }
+
+    fn encode(&mut self) {
+        let len = self.word.len() as isize;
+        if len == 0 {
+            return;
+        }
+        let last = len - 1;
+        let slavo = self.slavo_germanic();
+        let mut c: isize = 0;
+
+        // silent first letter
+        if self.string_at(0, 2, &["GN", "KN", "PN", "WR", "PS"]) {
+            c += 1;
+        }
+        if self.at(0) == 'X' {
+            self.add("S");
+            c += 1;
+        }
+
+        while c < len && (self.primary.len() < MAX_CODE_LEN || self.secondary.len() < MAX_CODE_LEN)
+        {
+            match self.at(c) {
+                'A' | 'E' | 'I' | 'O' | 'U' | 'Y' => {
+                    if c == 0 {
+                        self.add("A");
+                    }
+                    c += 1;
+                }
+                'B' => {
+                    self.add("P");
+                    c += if self.at(c + 1) == 'B' { 2 } else { 1 };
+                }
+                'C' => c = self.encode_c(c),
+                'D' => {
+                    if self.string_at(c, 2, &["DG"]) {
+                        if self.string_at(c + 2, 1, &["I", "E", "Y"]) {
+                            self.add("J");
+                            c += 3;
+                        } else {
+                            self.add("TK");
+                            c += 2;
+                        }
+                    } else if self.string_at(c, 2, &["DT", "DD"]) {
+                        self.add("T");
+                        c += 2;
+                    } else {
+                        self.add("T");
+                        c += 1;
+                    }
+                }
+                'F' => {
+                    self.add("F");
+                    c += if self.at(c + 1) == 'F' { 2 } else { 1 };
+                }
+                'G' => c = self.encode_g(c, slavo),
+                'H' => {
+                    if (c == 0 || self.is_vowel(c - 1)) && self.is_vowel(c + 1) {
+                        self.add("H");
+                        c += 2;
+                    } else {
+                        c += 1;
+                    }
+                }
+                'J' => c = self.encode_j(c, last, slavo),
+                'K' => {
+                    self.add("K");
+                    c += if self.at(c + 1) == 'K' { 2 } else { 1 };
+                }
+                'L' => {
+                    if self.at(c + 1) == 'L' {
+                        if (c == len - 3 && self.string_at(c - 1, 4, &["ILLO", "ILLA", "ALLE"]))
+                            || ((self.string_at(last - 1, 2, &["AS", "OS"])
+                                || self.string_at(last, 1, &["A", "O"]))
+                                && self.string_at(c - 1, 4, &["ALLE"]))
+                        {
+                            self.add_alt("L", "");
+                        } else {
+                            self.add("L");
+                        }
+                        c += 2;
+                    } else {
+                        self.add("L");
+                        c += 1;
+                    }
+                }
+                'M' => {
+                    self.add("M");
+                    if (self.string_at(c - 1, 3, &["UMB"])
+                        && (c + 1 == last || self.string_at(c + 2, 2, &["ER"])))
+                        || self.at(c + 1) == 'M'
+                    {
+                        c += 2;
+                    } else {
+                        c += 1;
+                    }
+                }
+                'N' => {
+                    self.add("N");
+                    c += if self.at(c + 1) == 'N' { 2 } else { 1 };
+                }
+                'P' => {
+                    if self.at(c + 1) == 'H' {
+                        self.add("F");
+                        c += 2;
+                    } else {
+                        self.add("P");
+                        c += if self.string_at(c + 1, 1, &["P", "B"]) {
+                            2
+                        } else {
+                            1
+                        };
+                    }
+                }
+                'Q' => {
+                    self.add("K");
+                    c += if self.at(c + 1) == 'Q' { 2 } else { 1 };
+                }
+                'R' => {
+                    // french final "ier" is silent in the primary code
+                    if c == last
+                        && !slavo
+                        && self.string_at(c - 2, 2, &["IE"])
+                        && !self.string_at(c - 4, 2, &["ME", "MA"])
+                    {
+                        self.add_alt("", "R");
+                    } else {
+                        self.add("R");
+                    }
+                    c += if self.at(c + 1) == 'R' { 2 } else { 1 };
+                }
+                'S' => c = self.encode_s(c, last, slavo),
+                'T' => {
+                    if self.string_at(c, 4, &["TION"]) || self.string_at(c, 3, &["TIA", "TCH"]) {
+                        self.add("X");
+                        c += 3;
+                    } else if self.string_at(c, 2, &["TH"]) || self.string_at(c, 3, &["TTH"]) {
+                        if self.string_at(c + 2, 2, &["OM", "AM"])
+                            || self.string_at(0, 4, &["VAN ", "VON "])
+                            || self.string_at(0, 3, &["SCH"])
+                        {
+                            self.add("T");
+                        } else {
+                            self.add_alt("0", "T");
+                        }
+                        c += 2;
+                    } else {
+                        self.add("T");
+                        c += if self.string_at(c + 1, 1, &["T", "D"]) {
+                            2
+                        } else {
+                            1
+                        };
+                    }
+                }
+                'V' => {
+                    self.add("F");
+                    c += if self.at(c + 1) == 'V' { 2 } else { 1 };
+                }
+                'W' => {
+                    if self.string_at(c, 2, &["WR"]) {
+                        self.add("R");
+                        c += 2;
+                        continue;
+                    }
+                    if c == 0 && (self.is_vowel(c + 1) || self.string_at(c, 2, &["WH"])) {
+                        if self.is_vowel(c + 1) {
+                            self.add_alt("A", "F");
+                        } else {
+                            self.add("A");
+                        }
+                    }
+                    if (c == last && self.is_vowel(c - 1))
+                        || self.string_at(c - 1, 5, &["EWSKI", "EWSKY", "OWSKI", "OWSKY"])
+                        || self.string_at(0, 3, &["SCH"])
+                    {
+                        self.add_alt("", "F");
+                        c += 1;
+                    } else if self.string_at(c, 4, &["WICZ", "WITZ"]) {
+                        self.add_alt("TS", "FX");
+                        c += 4;
+                    } else {
+                        c += 1;
+                    }
+                }
+                'X' => {
+                    if !(c == last
+                        && (self.string_at(c - 3, 3, &["IAU", "EAU"])
+                            || self.string_at(c - 2, 2, &["AU", "OU"])))
+                    {
+                        self.add("KS");
+                    }
+                    c += if self.string_at(c + 1, 1, &["C", "X"]) {
+                        2
+                    } else {
+                        1
+                    };
+                }
+                'Z' => {
+                    if self.at(c + 1) == 'H' {
+                        self.add("J");
+                        c += 2;
+                    } else {
+                        if self.string_at(c + 1, 2, &["ZO", "ZI", "ZA"])
+                            || (slavo && c > 0 && self.at(c - 1) != 'T')
+                        {
+                            self.add_alt("S", "TS");
+                        } else {
+                            self.add("S");
+                        }
+                        c += if self.at(c + 1) == 'Z' { 2 } else { 1 };
+                    }
+                }
+                _ => c += 1,
+            }
+        }
+    }
+
+    fn encode_c(&mut self, c: isize) -> isize {
+        // various germanic
+        if c > 1
+            && !self.is_vowel(c - 2)
+            && self.string_at(c - 1, 3, &["ACH"])
+            && self.at(c + 2) != 'I'
+            && (self.at(c + 2) != 'E' || self.string_at(c - 2, 6, &["BACHER", "MACHER"]))
+        {
+            self.add("K");
+            return c + 2;
+        }
+        if c == 0 && self.string_at(c, 6, &["CAESAR"]) {
+            self.add("S");
+            return c + 2;
+        }
+        if self.string_at(c, 4, &["CHIA"]) {
+            self.add("K");
+            return c + 2;
+        }
+        if self.string_at(c, 2, &["CH"]) {
+            if c > 0 && self.string_at(c, 4, &["CHAE"]) {
+                self.add_alt("K", "X");
+                return c + 2;
+            }
+            // greek roots, e.g. "chemistry", "chorus"
+            if c == 0
+                && (self.string_at(c + 1, 5, &["HARAC", "HARIS"])
+                    || self.string_at(c + 1, 3, &["HOR", "HYM", "HIA", "HEM"]))
+                && !self.string_at(0, 5, &["CHORE"])
+            {
+                self.add("K");
+                return c + 2;
+            }
+            if self.string_at(0, 4, &["VAN ", "VON "])
+                || self.string_at(0, 3, &["SCH"])
+                || self.string_at(c - 2, 6, &["ORCHES", "ARCHIT", "ORCHID"])
+                || self.string_at(c + 2, 1, &["T", "S"])
+                || ((self.string_at(c - 1, 1, &["A", "O", "U", "E"]) || c == 0)
+                    && self.string_at(
+                        c + 2,
+                        1,
+                        &["L", "R", "N", "M", "B", "H", "F", "V", "W", " "],
+                    ))
+            {
+                self.add("K");
+            } else if c > 0 {
+                if self.string_at(0, 2, &["MC"]) {
+                    self.add("K");
+                } else {
+                    self.add_alt("X", "K");
+                }
+            } else {
+                self.add("X");
+            }
+            return c + 2;
+        }
+        if self.string_at(c, 2, &["CZ"]) && !self.string_at(c - 2, 4, &["WICZ"]) {
+            self.add_alt("S", "X");
+            return c + 2;
+        }
+        if self.string_at(c + 1, 3, &["CIA"]) {
+            self.add("X");
+            return c + 3;
+        }
+        if self.string_at(c, 2, &["CC"]) && !(c == 1 && self.at(0) == 'M') {
+            if self.string_at(c + 2, 1, &["I", "E", "H"]) && !self.string_at(c + 2, 2, &["HU"]) {
+                if (c == 1 && self.at(c - 1) == 'A')
+                    || self.string_at(c - 1, 5, &["UCCEE", "UCCES"])
+                {
+                    self.add("KS");
+                } else {
+                    self.add("X");
+                }
+                return c + 3;
+            }
+            self.add("K");
+            return c + 2;
+        }
+        if self.string_at(c, 2, &["CK", "CG", "CQ"]) {
+            self.add("K");
+            return c + 2;
+        }
+        if self.string_at(c, 2, &["CI", "CE", "CY"]) {
+            if self.string_at(c, 3, &["CIO", "CIE", "CIA"]) {
+                self.add_alt("S", "X");
+            } else {
+                self.add("S");
+            }
+            return c + 2;
+        }
+
+        self.add("K");
+        if self.string_at(c + 1, 2, &[" C", " Q", " G"]) {
+            c + 3
+        } else if self.string_at(c + 1, 1, &["C", "K", "Q"])
+            && !self.string_at(c + 1, 2, &["CE", "CI"])
+        {
+            c + 2
+        } else {
+            c + 1
+        }
+    }
+
+    fn encode_g(&mut self, c: isize, slavo: bool) -> isize {
+        if self.at(c + 1) == 'H' {
+            if c > 0 && !self.is_vowel(c - 1) {
+                self.add("K");
+                return c + 2;
+            }
+            if c == 0 {
+                if self.at(c + 2) == 'I' {
+                    self.add("J");
+                } else {
+                    self.add("K");
+                }
+                return c + 2;
+            }
+            // silent, e.g. "hugh", "bough", "broughton"
+            if (c > 1 && self.string_at(c - 2, 1, &["B", "H", "D"]))
+                || (c > 2 && self.string_at(c - 3, 1, &["B", "H", "D"]))
+                || (c > 3 && self.string_at(c - 4, 1, &["B", "H"]))
+            {
+                return c + 2;
+            }
+            if c > 2
+                && self.at(c - 1) == 'U'
+                && self.string_at(c - 3, 1, &["C", "G", "L", "R", "T"])
+            {
+                self.add("F");
+            } else if c > 0 && self.at(c - 1) != 'I' {
+                self.add("K");
+            }
+            return c + 2;
+        }
+
+        if self.at(c + 1) == 'N' {
+            if c == 1 && self.is_vowel(0) && !slavo {
+                self.add_alt("KN", "N");
+            } else if !self.string_at(c + 2, 2, &["EY"]) && self.at(c + 1) != 'Y' && !slavo {
+                self.add_alt("N", "KN");
+            } else {
+                self.add("KN");
+            }
+            return c + 2;
+        }
+        if self.string_at(c + 1, 2, &["LI"]) && !slavo {
+            self.add_alt("KL", "L");
+            return c + 2;
+        }
+        if c == 0
+            && (self.at(c + 1) == 'Y'
+                || self.string_at(
+                    c + 1,
+                    2,
+                    &[
+                        "ES", "EP", "EB", "EL", "EY", "IB", "IL", "IN", "IE", "EI", "ER",
+                    ],
+                ))
+        {
+            self.add_alt("K", "J");
+            return c + 2;
+        }
+        if (self.string_at(c + 1, 2, &["ER"]) || self.at(c + 1) == 'Y')
+            && !self.string_at(0, 6, &["DANGER", "RANGER", "MANGER"])
+            && !self.string_at(c - 1, 1, &["E", "I"])
+            && !self.string_at(c - 1, 3, &["RGY", "OGY"])
+        {
+            self.add_alt("K", "J");
+            return c + 2;
+        }
+        if self.string_at(c + 1, 1, &["E", "I", "Y"]) || self.string_at(c - 1, 4, &["AGGI", "OGGI"])
+        {
+            if self.string_at(0, 4, &["VAN ", "VON "])
+                || self.string_at(0, 3, &["SCH"])
+                || self.string_at(c + 1, 2, &["ET"])
+            {
+                self.add("K");
+            } else if self.string_at(c + 1, 4, &["IER "]) {
+                self.add("J");
+            } else {
+                self.add_alt("J", "K");
+            }
+            return c + 2;
+        }
+
+        self.add("K");
+        if self.at(c + 1) == 'G' { c + 2 } else { c + 1 }
+    }
+
+    fn encode_j(&mut self, c: isize, last: isize, slavo: bool) -> isize {
+        // spanish "jose", "san jacinto"
+        if self.string_at(c, 4, &["JOSE"]) || self.string_at(0, 4, &["SAN "]) {
+            if (c == 0 && self.at(c + 4) == ' ') || self.string_at(0, 4, &["SAN "]) {
+                self.add("H");
+            } else {
+                self.add_alt("J", "H");
+            }
+            return c + 1;
+        }
+
+        if c == 0 {
+            self.add_alt("J", "A");
+        } else if self.is_vowel(c - 1) && !slavo && matches!(self.at(c + 1), 'A' | 'O') {
+            self.add_alt("J", "H");
+        } else if c == last {
+            self.add_alt("J", "");
+        } else if !self.string_at(c + 1, 1, &["L", "T", "K", "S", "N", "M", "B", "Z"])
+            && !self.string_at(c - 1, 1, &["S", "K", "L"])
+        {
+            self.add("J");
+        }
+
+        if self.at(c + 1) == 'J' { c + 2 } else { c + 1 }
+    }
+
+    fn encode_s(&mut self, c: isize, last: isize, slavo: bool) -> isize {
+        // silent in "island", "carlysle"
+        if self.string_at(c - 1, 3, &["ISL", "YSL"]) {
+            return c + 1;
+        }
+        if c == 0 && self.string_at(c, 5, &["SUGAR"]) {
+            self.add_alt("X", "S");
+            return c + 1;
+        }
+        if self.string_at(c, 2, &["SH"]) {
+            if self.string_at(c + 1, 4, &["HEIM", "HOEK", "HOLM", "HOLZ"]) {
+                self.add("S");
+            } else {
+                self.add("X");
+            }
+            return c + 2;
+        }
+        if self.string_at(c, 3, &["SIO", "SIA"]) || self.string_at(c, 4, &["SIAN"]) {
+            if slavo {
+                self.add("S");
+            } else {
+                self.add_alt("S", "X");
+            }
+            return c + 3;
+        }
+        if (c == 0 && self.string_at(c + 1, 1, &["M", "N", "L", "W"]))
+            || self.string_at(c + 1, 1, &["Z"])
+        {
+            self.add_alt("S", "X");
+            return if self.string_at(c + 1, 1, &["Z"]) {
+                c + 2
+            } else {
+                c + 1
+            };
+        }
+        if self.string_at(c, 2, &["SC"]) {
+            if self.at(c + 2) == 'H' {
+                if self.string_at(c + 3, 2, &["OO", "ER", "EN", "UY", "ED", "EM"]) {
+                    if self.string_at(c + 3, 2, &["ER", "EN"]) {
+                        self.add_alt("X", "SK");
+                    } else {
+                        self.add("SK");
+                    }
+                } else if c == 0 && !self.is_vowel(3) && self.at(3) != 'W' {
+                    self.add_alt("X", "S");
+                } else {
+                    self.add("X");
+                }
+            } else if self.string_at(c + 2, 1, &["I", "E", "Y"]) {
+                self.add("S");
+            } else {
+                self.add("SK");
+            }
+            return c + 3;
+        }
+
+        // french final "ais", "ois"
+        if c == last && self.string_at(c - 2, 2, &["AI", "OI"]) {
+            self.add_alt("", "S");
+        } else {
+            self.add("S");
+        }
+        if self.string_at(c + 1, 1, &["S", "Z"]) {
+            c + 2
+        } else {
+            c + 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_double_metaphone_codes() {
+        assert_eq!(
+            double_metaphone("Catherine"),
+            ("K0RN".into(), "KTRN".into())
+        );
+        assert_eq!(double_metaphone("Kathryn"), ("K0RN".into(), "KTRN".into()));
+        assert_eq!(double_metaphone("Smith"), ("SM0".into(), "XMT".into()));
+        assert_eq!(double_metaphone("Schmidt"), ("XMT".into(), "SMT".into()));
+        assert_eq!(double_metaphone("Knight").0, "NT");
+        assert_eq!(double_metaphone("Philip").0, "FLP");
+        assert_eq!(double_metaphone(""), (String::new(), String::new()));
+    }
+}
@@ -0,0 +1,1823 @@
+use crate::distance::{DistanceBuffers, Metric, similarity_ratio};
+use crate::index::{Indexer, RankBy};
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SimilarityResult {
+    pub query_id: usize,
+    pub twin_id: usize,
+    pub distance: usize,
+    pub metric: Metric,
+    /// normalized (token sorted) texts that were compared, only filled for --debug-columns
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_normalized: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub twin_normalized: Option<String>,
+    /// distance scaled to [0, 1] by the longer normalized text, only filled for --weight-as ratio
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub similarity: Option<f64>,
+    /// distance between the original (not normalized) texts, only filled for --original-distance
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_distance: Option<usize>,
+    /// similarity blended with the rarity of the shared grams, only filled for --rarity-weight
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// space separated trigrams both texts share, only filled for --explain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared_trigrams: Option<String>,
+}
+
+impl SimilarityResult {
+    pub fn new(query_id: usize, twin_id: usize, distance: usize, metric: Metric) -> Self {
+        Self {
+            query_id,
+            twin_id,
+            distance,
+            metric,
+            query_normalized: None,
+            twin_normalized: None,
+            similarity: None,
+            original_distance: None,
+            score: None,
+            shared_trigrams: None,
+        }
+    }
+}
+
+/// Optional columns of the results file.
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// adds the metric that produced the pair, useful when more than one metric is enabled
+    pub metric_column: bool,
+    /// adds query_normalized and twin_normalized, see `add_normalized_columns`
+    pub debug_columns: bool,
+    /// what the third column holds, see `add_similarity_ratios`
+    pub weight_as: EdgeWeight,
+    /// adds original_distance, see `add_original_distances`
+    pub original_distance: bool,
+    /// adds score with this rarity weight, see `add_rarity_scores`
+    pub rarity_weight: Option<f64>,
+    /// adds shared_trigrams, see `add_shared_trigrams`
+    pub explain: bool,
+    /// quote every CSV field, not only those that need it
+    pub always_quote: bool,
+    pub line_terminator: LineTerminator,
+    /// order of the twins of a query, `RankBy::Ratio` needs the similarity ratios (filled but only written
+    /// with `EdgeWeight::Ratio`)
+    pub rank_by: RankBy,
+}
+
+/// Edge weight of a pair in the results file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EdgeWeight {
+    /// raw edit distance, the `distance` column
+    #[default]
+    Distance,
+    /// similarity ratio in [0, 1] (1.0 identical), the `weight` column, for graph tools
+    Ratio,
+}
+
+impl FromStr for EdgeWeight {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "distance" => Ok(Self::Distance),
+            "ratio" => Ok(Self::Ratio),
+            _ => Err(format!(
+                "unknown edge weight '{}', expected distance or ratio",
+                s
+            )),
+        }
+    }
+}
+
+/// Line ending of the CSV results file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineTerminator {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+impl FromStr for LineTerminator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lf" | "\\n" => Ok(Self::Lf),
+            "crlf" | "\\r\\n" => Ok(Self::Crlf),
+            _ => Err(format!(
+                "unknown line terminator '{}', expected lf or crlf",
+                s
+            )),
+        }
+    }
+}
+
+/// CSV writer of the results file, quoting and line endings as `options` ask.
+fn csv_writer(file: File, options: &OutputOptions) -> csv::Writer<File> {
+    csv::WriterBuilder::new()
+        .quote_style(if options.always_quote {
+            csv::QuoteStyle::Always
+        } else {
+            csv::QuoteStyle::Necessary
+        })
+        .terminator(match options.line_terminator {
+            LineTerminator::Lf => csv::Terminator::Any(b'\n'),
+            LineTerminator::Crlf => csv::Terminator::CRLF,
+        })
+        .from_writer(file)
+}
+
+/// Pair whose distance differs between the baseline and the current run.
+#[derive(Debug, PartialEq)]
+pub struct DistanceChange {
+    pub query_id: usize,
+    pub twin_id: usize,
+    pub baseline_distance: usize,
+    pub current_distance: usize,
+}
+
+/// Difference between two result sets, pairs are keyed by (query_id, twin_id) and sorted by that key.
+#[derive(Debug, Default)]
+pub struct ResultsDiff {
+    pub added: Vec<SimilarityResult>,
+    pub removed: Vec<SimilarityResult>,
+    pub changed: Vec<DistanceChange>,
+}
+
+/// Summary statistics of distances in a result set, percentiles use the nearest-rank method.
+#[derive(Debug, PartialEq)]
+pub struct DistanceSummary {
+    pub min: usize,
+    pub median: usize,
+    pub p90: usize,
+    pub max: usize,
+    pub mean: f64,
+}
+
+impl DistanceSummary {
+    /// None for an empty result set
+    pub fn from_results(results: &[SimilarityResult]) -> Option<Self> {
+        let distances: Vec<usize> = results.iter().map(|r| r.distance).collect();
+        Self::from_distances(&distances)
+    }
+
+    /// None for no distances
+    pub fn from_distances(distances: &[usize]) -> Option<Self> {
+        if distances.is_empty() {
+            return None;
+        }
+
+        let mut distances = distances.to_vec();
+        distances.sort_unstable();
+
+        let percentile = |p: f64| {
+            let rank = (p * distances.len() as f64).ceil() as usize;
+            distances[rank.saturating_sub(1)]
+        };
+
+        Some(Self {
+            min: distances[0],
+            median: percentile(0.5),
+            p90: percentile(0.9),
+            max: distances[distances.len() - 1],
+            mean: distances.iter().sum::<usize>() as f64 / distances.len() as f64,
+        })
+    }
+}
+
+impl fmt::Display for DistanceSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "min: {}, median: {}, p90: {}, max: {}, mean: {:.2}",
+            self.min, self.median, self.p90, self.max, self.mean
+        )
+    }
+}
+
+/// Max distance suggested from the nearest neighbor distances of a sample, see `suggest_max_distance`.
+#[derive(Debug, PartialEq)]
+pub struct ThresholdSuggestion {
+    pub max_distance: usize,
+    /// nearest neighbor distance of every sampled record, ascending
+    pub nearest: Vec<usize>,
+}
+
+/// Nearest neighbor distance of every record in `ids`, searched in the whole index (`Indexer::neighbors`
+/// with the normalized length as max distance), and a max distance at the knee of their distribution:
+/// the largest jump between consecutive sorted distances separates records with near duplicates from
+/// the rest, the suggestion is the distance just below it. A record without any candidate counts as its
+/// normalized length. None when no id is indexed or the index has fewer than two records.
+pub fn suggest_max_distance(indexer: &Indexer, ids: &[usize]) -> Option<ThresholdSuggestion> {
+    if indexer.len() < 2 {
+        return None;
+    }
+
+    let mut nearest: Vec<usize> = ids
+        .par_iter()
+        .filter_map(|&id| {
+            let len = indexer.normalized_len(id)?;
+            let closest = indexer.neighbors(id, len).iter().map(|r| r.distance).min();
+            Some(closest.unwrap_or(len))
+        })
+        .collect();
+    if nearest.is_empty() {
+        return None;
+    }
+    nearest.sort_unstable();
+
+    // first of the largest jumps, all equal distances suggest that distance
+    let knee = nearest
+        .windows(2)
+        .enumerate()
+        .max_by_key(|&(i, w)| (w[1] - w[0], std::cmp::Reverse(i)))
+        .filter(|(_, w)| w[1] > w[0])
+        .map_or(nearest.len() - 1, |(i, _)| i);
+
+    Some(ThresholdSuggestion {
+        max_distance: nearest[knee],
+        nearest,
+    })
+}
+
+impl fmt::Display for ThresholdSuggestion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let within = self
+            .nearest
+            .iter()
+            .filter(|&&d| d <= self.max_distance)
+            .count();
+        write!(
+            f,
+            "suggested max distance: {} ({} of {} sampled records have a twin within it)",
+            self.max_distance,
+            within,
+            self.nearest.len()
+        )
+    }
+}
+
+/// Match quality against labeled true duplicate pairs, pairs are unordered ((a, b) equals (b, a)).
+/// Precision, recall and F1 are 0 when undefined (nothing found or nothing labeled).
+#[derive(Debug, PartialEq)]
+pub struct QualityReport {
+    pub true_positives: usize,
+    pub false_positives: usize,
+    pub false_negatives: usize,
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+}
+
+impl QualityReport {
+    pub fn evaluate(results: &[SimilarityResult], ground_truth: &[(usize, usize)]) -> Self {
+        let key = |a: usize, b: usize| (a.min(b), a.max(b));
+        let truth: FxHashSet<(usize, usize)> =
+            ground_truth.iter().map(|&(a, b)| key(a, b)).collect();
+        let found: FxHashSet<(usize, usize)> =
+            results.iter().map(|r| key(r.query_id, r.twin_id)).collect();
+
+        let true_positives = found.intersection(&truth).count();
+        let false_positives = found.len() - true_positives;
+        let false_negatives = truth.len() - true_positives;
+
+        let ratio = |part: usize, total: usize| {
+            if total == 0 {
+                0.0
+            } else {
+                part as f64 / total as f64
+            }
+        };
+        let precision = ratio(true_positives, found.len());
+        let recall = ratio(true_positives, truth.len());
+        let f1 = if precision + recall == 0.0 {
+            0.0
+        } else {
+            2.0 * precision * recall / (precision + recall)
+        };
+
+        Self {
+            true_positives,
+            false_positives,
+            false_negatives,
+            precision,
+            recall,
+            f1,
+        }
+    }
+}
+
+impl fmt::Display for QualityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "true positives: {}, false positives: {}, false negatives: {}, precision: {:.4}, recall: {:.4}, f1: {:.4}",
+            self.true_positives,
+            self.false_positives,
+            self.false_negatives,
+            self.precision,
+            self.recall,
+            self.f1
+        )
+    }
+}
+
+/// Shape of the results file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    /// flat array of pairs
+    Json,
+    /// one entry per query with its twins nested beneath
+    JsonNested,
+    /// object mapping every matched id to its direct neighbors, both directions of each pair
+    JsonAdjacency,
+    /// columnar file, requires the parquet feature
+    Parquet,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "json-nested" => Ok(Self::JsonNested),
+            "json-adjacency" => Ok(Self::JsonAdjacency),
+            "parquet" => Ok(Self::Parquet),
+            _ => Err(format!(
+                "unknown output format '{}', expected csv, json, json-nested, json-adjacency or parquet",
+                s
+            )),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// File extension of the format, for files named by the program.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Json | Self::JsonNested | Self::JsonAdjacency => "json",
+            Self::Parquet => "parquet",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct QueryTwins {
+    query_id: usize,
+    twins: Vec<Twin>,
+}
+
+#[derive(Debug, Serialize)]
+struct Twin {
+    id: usize,
+    distance: usize,
+    metric: Metric,
+}
+
+pub fn save_results(
+    results: &[SimilarityResult],
+    file_path: &str,
+    format: OutputFormat,
+    options: &OutputOptions,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        OutputFormat::Csv => save_results_to_csv(results, file_path, options),
+        OutputFormat::Json => save_results_to_json(results, file_path),
+        OutputFormat::JsonNested => save_results_to_nested_json(results, file_path),
+        OutputFormat::JsonAdjacency => save_results_to_adjacency_json(results, file_path),
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => save_results_to_parquet(results, file_path, options),
+        #[cfg(not(feature = "parquet"))]
+        OutputFormat::Parquet => Err("parquet output requires the `parquet` cargo feature".into()),
+    }
+}
+
+/// One results file per record id in `dir` (created if missing), e.g. `1.csv`, with the twins of that
+/// record in the order of `results`. Like `adjacency_lists` every pair goes to the files of both its
+/// ids, in the file of its twin with the ids (and normalized texts) swapped, a twin listed twice
+/// for the same metric keeps its first row. Records without twins get no file. Files are written
+/// in parallel, returns how many were written.
+pub fn save_results_per_query(
+    results: &[SimilarityResult],
+    dir: &str,
+    format: OutputFormat,
+    options: &OutputOptions,
+) -> Result<usize, Box<dyn Error>> {
+    std::fs::create_dir_all(dir)?;
+
+    let mut by_query: FxHashMap<usize, Vec<SimilarityResult>> = FxHashMap::default();
+    for result in results {
+        by_query
+            .entry(result.query_id)
+            .or_default()
+            .push(result.clone());
+        by_query
+            .entry(result.twin_id)
+            .or_default()
+            .push(swapped(result));
+    }
+    for results in by_query.values_mut() {
+        let mut seen = FxHashSet::default();
+        results.retain(|r| seen.insert((r.twin_id, r.metric)));
+    }
+
+    by_query.par_iter().try_for_each(|(query_id, results)| {
+        let path = std::path::Path::new(dir).join(format!("{}.{}", query_id, format.extension()));
+        save_results(results, &path.to_string_lossy(), format, options).map_err(|e| e.to_string())
+    })?;
+
+    Ok(by_query.len())
+}
+
+/// `result` seen from its twin
+fn swapped(result: &SimilarityResult) -> SimilarityResult {
+    SimilarityResult {
+        query_id: result.twin_id,
+        twin_id: result.query_id,
+        query_normalized: result.twin_normalized.clone(),
+        twin_normalized: result.query_normalized.clone(),
+        ..result.clone()
+    }
+}
+
+/// export data to csv
+pub fn save_results_to_csv(
+    results: &[SimilarityResult],
+    file_path: &str,
+    options: &OutputOptions,
+) -> Result<(), Box<dyn Error>> {
+    let file = File::create(file_path)?;
+    let mut wtr = csv_writer(file, options);
+
+    // headers
+    wtr.write_record(csv_headers(options))?;
+
+    for result in results {
+        wtr.write_record(csv_record(result, options))?;
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+/// CSV results file written in parts, the header goes out on `create` and every `append` is flushed,
+/// so the file on disk is always valid and holds everything appended so far.
+pub struct CsvAppender {
+    wtr: csv::Writer<File>,
+    options: OutputOptions,
+}
+
+impl CsvAppender {
+    pub fn create(file_path: &str, options: &OutputOptions) -> Result<Self, Box<dyn Error>> {
+        let mut wtr = csv_writer(File::create(file_path)?, options);
+        wtr.write_record(csv_headers(options))?;
+        wtr.flush()?;
+
+        Ok(Self {
+            wtr,
+            options: options.clone(),
+        })
+    }
+
+    pub fn append(&mut self, results: &[SimilarityResult]) -> Result<(), Box<dyn Error>> {
+        for result in results {
+            self.wtr.write_record(csv_record(result, &self.options))?;
+        }
+        self.wtr.flush()?;
+        Ok(())
+    }
+}
+
+fn csv_headers(options: &OutputOptions) -> Vec<&'static str> {
+    let mut headers = vec!["query_id", "twin_id"];
+    headers.push(match options.weight_as {
+        EdgeWeight::Distance => "distance",
+        EdgeWeight::Ratio => "weight",
+    });
+    if options.metric_column {
+        headers.push("metric");
+    }
+    if options.debug_columns {
+        headers.extend(["query_normalized", "twin_normalized"]);
+    }
+    if options.original_distance {
+        headers.push("original_distance");
+    }
+    if options.rarity_weight.is_some() {
+        headers.push("score");
+    }
+    if options.explain {
+        headers.push("shared_trigrams");
+    }
+    headers
+}
+
+fn csv_record(result: &SimilarityResult, options: &OutputOptions) -> Vec<String> {
+    let mut record = vec![
+        result.query_id.to_string(),
+        result.twin_id.to_string(),
+        match options.weight_as {
+            EdgeWeight::Distance => result.distance.to_string(),
+            EdgeWeight::Ratio => result.similarity.unwrap_or_default().to_string(),
+        },
+    ];
+    if options.metric_column {
+        record.push(result.metric.to_string());
+    }
+    if options.debug_columns {
+        record.push(result.query_normalized.clone().unwrap_or_default());
+        record.push(result.twin_normalized.clone().unwrap_or_default());
+    }
+    if options.original_distance {
+        record.push(
+            result
+                .original_distance
+                .map(|d| d.to_string())
+                .unwrap_or_default(),
+        );
+    }
+    if options.rarity_weight.is_some() {
+        record.push(result.score.map(|s| s.to_string()).unwrap_or_default());
+    }
+    if options.explain {
+        record.push(result.shared_trigrams.clone().unwrap_or_default());
+    }
+    record
+}
+
+/// rows per record batch, the writer never holds more than one batch of converted rows
+#[cfg(feature = "parquet")]
+const PARQUET_BATCH_ROWS: usize = 64 * 1024;
+
+/// export data to parquet, with the same optional columns as csv
+#[cfg(feature = "parquet")]
+pub fn save_results_to_parquet(
+    results: &[SimilarityResult],
+    file_path: &str,
+    options: &OutputOptions,
+) -> Result<(), Box<dyn Error>> {
+    use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, UInt64Array};
+    use arrow_schema::{DataType, Field, Schema};
+    use parquet::arrow::ArrowWriter;
+    use std::sync::Arc;
+
+    let mut fields = vec![
+        Field::new("query_id", DataType::UInt64, false),
+        Field::new("twin_id", DataType::UInt64, false),
+        match options.weight_as {
+            EdgeWeight::Distance => Field::new("distance", DataType::UInt64, false),
+            EdgeWeight::Ratio => Field::new("weight", DataType::Float64, false),
+        },
+    ];
+    if options.metric_column {
+        fields.push(Field::new("metric", DataType::Utf8, false));
+    }
+    if options.debug_columns {
+        fields.push(Field::new("query_normalized", DataType::Utf8, true));
+        fields.push(Field::new("twin_normalized", DataType::Utf8, true));
+    }
+    if options.original_distance {
+        fields.push(Field::new("original_distance", DataType::UInt64, true));
+    }
+    if options.rarity_weight.is_some() {
+        fields.push(Field::new("score", DataType::Float64, true));
+    }
+    if options.explain {
+        fields.push(Field::new("shared_trigrams", DataType::Utf8, true));
+    }
+    let schema = Arc::new(Schema::new(fields));
+
+    let file = File::create(file_path)?;
+    let mut writer = ArrowWriter::try_new(file, schema.clone(), None)?;
+
+    for chunk in results.chunks(PARQUET_BATCH_ROWS) {
+        let column = |f: fn(&SimilarityResult) -> usize| -> ArrayRef {
+            Arc::new(UInt64Array::from_iter_values(
+                chunk.iter().map(|r| f(r) as u64),
+            ))
+        };
+        let mut columns = vec![
+            column(|r| r.query_id),
+            column(|r| r.twin_id),
+            match options.weight_as {
+                EdgeWeight::Distance => column(|r| r.distance),
+                EdgeWeight::Ratio => Arc::new(Float64Array::from_iter_values(
+                    chunk.iter().map(|r| r.similarity.unwrap_or_default()),
+                )),
+            },
+        ];
+        if options.metric_column {
+            columns.push(Arc::new(StringArray::from_iter_values(
+                chunk.iter().map(|r| r.metric.to_string()),
+            )));
+        }
+        if options.debug_columns {
+            columns.push(Arc::new(StringArray::from_iter(
+                chunk.iter().map(|r| r.query_normalized.as_deref()),
+            )));
+            columns.push(Arc::new(StringArray::from_iter(
+                chunk.iter().map(|r| r.twin_normalized.as_deref()),
+            )));
+        }
+        if options.original_distance {
+            columns.push(Arc::new(UInt64Array::from_iter(
+                chunk.iter().map(|r| r.original_distance.map(|d| d as u64)),
+            )));
+        }
+        if options.rarity_weight.is_some() {
+            columns.push(Arc::new(Float64Array::from_iter(
+                chunk.iter().map(|r| r.score),
+            )));
+        }
+        if options.explain {
+            columns.push(Arc::new(StringArray::from_iter(
+                chunk.iter().map(|r| r.shared_trigrams.as_deref()),
+            )));
+        }
+
+        writer.write(&RecordBatch::try_new(schema.clone(), columns)?)?;
+    }
+
+    writer.close()?;
+    Ok(())
+}
+
+/// read results written by `save_results_to_parquet`
+#[cfg(feature = "parquet")]
+pub fn load_results_from_parquet(file_path: &str) -> Result<Vec<SimilarityResult>, Box<dyn Error>> {
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, UInt64Type};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(file_path)?)?.build()?;
+    let mut results = Vec::new();
+
+    for batch in reader {
+        let batch = batch?;
+        let ids = |name: &str| -> Result<Vec<usize>, Box<dyn Error>> {
+            let column = batch
+                .column_by_name(name)
+                .and_then(|c| c.as_primitive_opt::<UInt64Type>())
+                .ok_or_else(|| format!("missing {} column", name))?;
+            Ok(column.values().iter().map(|&v| v as usize).collect())
+        };
+        let text = |name: &str| {
+            batch
+                .column_by_name(name)
+                .and_then(|c| c.as_string_opt::<i32>())
+        };
+
+        let (query_ids, twin_ids) = (ids("query_id")?, ids("twin_id")?);
+        // files saved with `EdgeWeight::Ratio` have a weight instead of a distance column
+        let weights = batch
+            .column_by_name("weight")
+            .and_then(|c| c.as_primitive_opt::<Float64Type>());
+        let distances = match weights {
+            Some(_) => vec![0; batch.num_rows()],
+            None => ids("distance")?,
+        };
+        let metrics = text("metric");
+        let query_normalized = text("query_normalized");
+        let twin_normalized = text("twin_normalized");
+        let shared_trigrams = text("shared_trigrams");
+        let original_distances = batch
+            .column_by_name("original_distance")
+            .and_then(|c| c.as_primitive_opt::<UInt64Type>());
+        let scores = batch
+            .column_by_name("score")
+            .and_then(|c| c.as_primitive_opt::<Float64Type>());
+
+        for row in 0..batch.num_rows() {
+            let metric = match metrics {
+                Some(m) => m.value(row).parse()?,
+                None => Metric::Levenshtein,
+            };
+            let mut result =
+                SimilarityResult::new(query_ids[row], twin_ids[row], distances[row], metric);
+            result.similarity = weights.map(|c| c.value(row));
+            result.query_normalized = query_normalized.and_then(|c| {
+                arrow_array::Array::is_valid(c, row).then(|| c.value(row).to_string())
+            });
+            result.twin_normalized = twin_normalized.and_then(|c| {
+                arrow_array::Array::is_valid(c, row).then(|| c.value(row).to_string())
+            });
+            result.original_distance = original_distances
+                .and_then(|c| arrow_array::Array::is_valid(c, row).then(|| c.value(row) as usize));
+            result.score =
+                scores.and_then(|c| arrow_array::Array::is_valid(c, row).then(|| c.value(row)));
+            result.shared_trigrams = shared_trigrams.and_then(|c| {
+                arrow_array::Array::is_valid(c, row).then(|| c.value(row).to_string())
+            });
+            results.push(result);
+        }
+    }
+
+    Ok(results)
+}
+
+/// export data as a flat json array of pairs
+pub fn save_results_to_json(
+    results: &[SimilarityResult],
+    file_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let writer = BufWriter::new(File::create(file_path)?);
+    serde_json::to_writer(writer, results)?;
+    Ok(())
+}
+
+/// export data as json grouped by query, one object per query_id in ascending order, twins keep the
+/// order of `results` (e.g. of `--sort-output`)
+pub fn save_results_to_nested_json(
+    results: &[SimilarityResult],
+    file_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let grouped = group_by_query(results);
+    let writer = BufWriter::new(File::create(file_path)?);
+    serde_json::to_writer(writer, &grouped)?;
+    Ok(())
+}
+
+fn group_by_query(results: &[SimilarityResult]) -> Vec<QueryTwins> {
+    let mut grouped: BTreeMap<usize, Vec<Twin>> = BTreeMap::new();
+
+    for result in results {
+        grouped.entry(result.query_id).or_default().push(Twin {
+            id: result.twin_id,
+            distance: result.distance,
+            metric: result.metric,
+        });
+    }
+
+    grouped
+        .into_iter()
+        .map(|(query_id, twins)| QueryTwins { query_id, twins })
+        .collect()
+}
+
+/// Direct twin of a record in the adjacency output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Neighbor {
+    pub id: usize,
+    pub distance: usize,
+}
+
+/// Adjacency lists of the twin graph: every pair is an edge in both directions, unlike `clusters`
+/// only direct twins are listed. Neighbors are sorted by distance then id, a pair found by more
+/// than one metric keeps its smallest distance.
+pub fn adjacency_lists(results: &[SimilarityResult]) -> BTreeMap<usize, Vec<Neighbor>> {
+    let mut adjacency: BTreeMap<usize, Vec<Neighbor>> = BTreeMap::new();
+
+    for result in results {
+        let distance = result.distance;
+        adjacency
+            .entry(result.query_id)
+            .or_default()
+            .push(Neighbor {
+                id: result.twin_id,
+                distance,
+            });
+        adjacency.entry(result.twin_id).or_default().push(Neighbor {
+            id: result.query_id,
+            distance,
+        });
+    }
+
+    for neighbors in adjacency.values_mut() {
+        neighbors.sort_unstable_by_key(|n| (n.id, n.distance));
+        neighbors.dedup_by_key(|n| n.id);
+        neighbors.sort_unstable_by_key(|n| (n.distance, n.id));
+    }
+
+    adjacency
+}
+
+/// export `adjacency_lists` as `{"<id>": [{"id": .., "distance": ..}]}`
+pub fn save_results_to_adjacency_json(
+    results: &[SimilarityResult],
+    file_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let writer = BufWriter::new(File::create(file_path)?);
+    serde_json::to_writer(writer, &adjacency_lists(results))?;
+    Ok(())
+}
+
+/// load results previously written by `save_results_to_csv`, with either edge weight: a `weight`
+/// column fills `similarity` and leaves `distance` at 0
+pub fn load_results_from_csv(file_path: &str) -> Result<Vec<SimilarityResult>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut rdr = csv::Reader::from_reader(file);
+    let headers = rdr.headers()?.clone();
+    let weight_column = headers.get(2).is_some_and(|h| h.trim() == "weight");
+    let metric_column = headers.iter().position(|h| h.trim() == "metric");
+    let mut results = Vec::new();
+
+    for record in rdr.records() {
+        let record = record?;
+
+        // extra columns are ignored, apart from an optional metric
+        let metric = match metric_column.and_then(|i| record.get(i)) {
+            Some(metric) => metric.trim().parse().map_err(|e| {
+                let line = record.position().map_or(0, |p| p.line());
+                format!("line {}: invalid metric '{}': {}", line, metric, e)
+            })?,
+            None => Metric::Levenshtein,
+        };
+
+        let mut result = SimilarityResult::new(
+            record[0].trim().parse()?,
+            record[1].trim().parse()?,
+            0,
+            metric,
+        );
+        if weight_column {
+            result.similarity = Some(record[2].trim().parse()?);
+        } else {
+            result.distance = record[2].trim().parse()?;
+        }
+        results.push(result);
+    }
+
+    Ok(results)
+}
+
+/// load labeled duplicate pairs, the first two columns of a CSV with a header are the ids of a pair
+pub fn load_ground_truth(file_path: &str) -> Result<Vec<(usize, usize)>, Box<dyn Error>> {
+    let file = File::open(file_path)?;
+    let mut rdr = csv::Reader::from_reader(file);
+    let mut pairs = Vec::new();
+
+    for record in rdr.records() {
+        let record = record?;
+        pairs.push((ground_truth_id(&record, 0)?, ground_truth_id(&record, 1)?));
+    }
+
+    Ok(pairs)
+}
+
+fn ground_truth_id(record: &csv::StringRecord, column: usize) -> Result<usize, Box<dyn Error>> {
+    let line = record.position().map_or(0, |p| p.line());
+    let field = record
+        .get(column)
+        .ok_or_else(|| format!("line {}: expected two id columns", line))?;
+    field
+        .trim()
+        .parse()
+        .map_err(|e| format!("line {}: invalid id '{}': {}", line, field, e).into())
+}
+
+/// Fill every optional column `options` asks for.
+pub fn add_output_columns(
+    results: &mut [SimilarityResult],
+    indexer: &Indexer,
+    options: &OutputOptions,
+) {
+    if options.debug_columns {
+        add_normalized_columns(results, indexer);
+    }
+    if options.original_distance {
+        add_original_distances(results, indexer);
+    }
+    if options.weight_as == EdgeWeight::Ratio || options.rank_by == RankBy::Ratio {
+        add_similarity_ratios(results, indexer);
+    }
+    if let Some(weight) = options.rarity_weight {
+        add_rarity_scores(results, indexer, weight);
+    }
+    if options.explain {
+        add_shared_trigrams(results, indexer);
+    }
+}
+
+/// Order of the results file, by query, closest twins first.
+pub fn sort_results(results: &mut [SimilarityResult]) {
+    results.sort_unstable_by_key(|r| (r.query_id, r.distance, r.twin_id));
+}
+
+/// Order by query, highest score first, see `add_rarity_scores`.
+pub fn sort_results_by_score(results: &mut [SimilarityResult]) {
+    results.sort_unstable_by(|a, b| {
+        let score = |r: &SimilarityResult| r.score.unwrap_or_default();
+        a.query_id
+            .cmp(&b.query_id)
+            .then(score(b).total_cmp(&score(a)))
+            .then(a.twin_id.cmp(&b.twin_id))
+    });
+}
+
+/// Column of a results sort order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortField {
+    Query,
+    Twin,
+    Distance,
+}
+
+/// Lexicographic order of the results file given as "query,twin,distance". Columns left out break
+/// the remaining ties in that default order (then metric), so the output is always fully sorted,
+/// e.g. for a merge join against another sorted dataset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortOrder {
+    fields: Vec<SortField>,
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = Vec::new();
+        for name in s.split(',').map(str::trim) {
+            let field = match name {
+                "query" | "query_id" => SortField::Query,
+                "twin" | "twin_id" => SortField::Twin,
+                "distance" => SortField::Distance,
+                _ => {
+                    return Err(format!(
+                        "unknown sort column '{}', expected query, twin or distance",
+                        name
+                    ));
+                }
+            };
+            if fields.contains(&field) {
+                return Err(format!("sort column '{}' given twice", name));
+            }
+            fields.push(field);
+        }
+
+        for field in [SortField::Query, SortField::Twin, SortField::Distance] {
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+        Ok(Self { fields })
+    }
+}
+
+/// Sort by `order`, see `SortOrder`.
+pub fn sort_results_by(results: &mut [SimilarityResult], order: &SortOrder) {
+    results.sort_unstable_by(|a, b| {
+        order
+            .fields
+            .iter()
+            .map(|field| match field {
+                SortField::Query => a.query_id.cmp(&b.query_id),
+                SortField::Twin => a.twin_id.cmp(&b.twin_id),
+                SortField::Distance => a.distance.cmp(&b.distance),
+            })
+            .fold(std::cmp::Ordering::Equal, std::cmp::Ordering::then)
+            .then(a.metric.cmp(&b.metric))
+    });
+}
+
+/// Order by query, highest similarity ratio first, see `add_similarity_ratios`.
+pub fn sort_results_by_similarity(results: &mut [SimilarityResult]) {
+    results.sort_unstable_by(|a, b| {
+        let similarity = |r: &SimilarityResult| r.similarity.unwrap_or_default();
+        a.query_id
+            .cmp(&b.query_id)
+            .then(similarity(b).total_cmp(&similarity(a)))
+            .then(a.distance.cmp(&b.distance))
+            .then(a.twin_id.cmp(&b.twin_id))
+    });
+}
+
+/// Fill the normalized texts of both sides from the index, this is what the distance was computed on.
+pub fn add_normalized_columns(results: &mut [SimilarityResult], indexer: &Indexer) {
+    results.par_iter_mut().for_each(|result| {
+        result.query_normalized = indexer.normalized_text(result.query_id);
+        result.twin_normalized = indexer.normalized_text(result.twin_id);
+    });
+}
+
+/// Fill the similarity ratio of each pair from the normalized lengths in the index.
+pub fn add_similarity_ratios(results: &mut [SimilarityResult], indexer: &Indexer) {
+    results.par_iter_mut().for_each(|result| {
+        result.similarity = indexer
+            .normalized_len(result.query_id)
+            .zip(indexer.normalized_len(result.twin_id))
+            .map(|(a, b)| similarity_ratio(result.distance, a, b));
+    });
+}
+
+/// Fill the trigrams each pair shares (`Indexer::shared_trigrams_between`), why it became a candidate.
+pub fn add_shared_trigrams(results: &mut [SimilarityResult], indexer: &Indexer) {
+    results.par_iter_mut().for_each(|result| {
+        let shared: Vec<String> = indexer
+            .shared_trigrams_between(result.query_id, result.twin_id)
+            .iter()
+            .map(|trigram| trigram.iter().filter(|&&c| c != '\0').collect())
+            .collect();
+        result.shared_trigrams = Some(shared.join(" "));
+    });
+}
+
+/// Fill the distance between the original texts of each pair, with the metric of the pair and no bound.
+/// Shows how much of a match is owed to normalization (casing, punctuation, word order).
+pub fn add_original_distances(results: &mut [SimilarityResult], indexer: &Indexer) {
+    results
+        .par_iter_mut()
+        .for_each_init(DistanceBuffers::new, |bufs, result| {
+            let (Some(a), Some(b)) = (
+                indexer.original(result.query_id),
+                indexer.original(result.twin_id),
+            ) else {
+                return;
+            };
+            let a: Vec<char> = a.chars().collect();
+            let b: Vec<char> = b.chars().collect();
+            let bound = a.len().max(b.len());
+            result.original_distance = Some(result.metric.distance(&a, &b, bound, bufs));
+        });
+}
+
+/// Fill the score of each pair, the similarity ratio scaled by the rarity of the shared grams
+/// (`Indexer::overlap_rarity`): `similarity * (1 - weight + weight * rarity)`. With weight 0 the score is
+/// the similarity, with 1 a pair sharing only common grams scores close to 0.
+pub fn add_rarity_scores(results: &mut [SimilarityResult], indexer: &Indexer, weight: f64) {
+    let weight = weight.clamp(0.0, 1.0);
+    results.par_iter_mut().for_each(|result| {
+        let similarity = indexer
+            .normalized_len(result.query_id)
+            .zip(indexer.normalized_len(result.twin_id))
+            .map(|(a, b)| similarity_ratio(result.distance, a, b));
+        let rarity = indexer.overlap_rarity(result.query_id, result.twin_id);
+
+        result.score = similarity
+            .zip(rarity)
+            .map(|(similarity, rarity)| similarity * (1.0 - weight + weight * rarity));
+    });
+}
+
+/// Collapse pairs reported more than once (e.g. by several metrics, or as both (a, b) and (b, a)),
+/// keeping the row with the lowest distance, ties go to the metric listed first in `Metric`.
+/// Order of the remaining rows is not preserved.
+pub fn dedup_pairs(results: Vec<SimilarityResult>) -> Vec<SimilarityResult> {
+    dedup_by_key(results, |r| {
+        (r.query_id.min(r.twin_id), r.query_id.max(r.twin_id))
+    })
+}
+
+/// `dedup_pairs` for pairs whose ids are from two id spaces (e.g. input records and blocklist entries),
+/// (a, b) and (b, a) are different pairs and both kept.
+pub fn dedup_directed_pairs(results: Vec<SimilarityResult>) -> Vec<SimilarityResult> {
+    dedup_by_key(results, |r| (r.query_id, r.twin_id))
+}
+
+fn dedup_by_key(
+    results: Vec<SimilarityResult>,
+    key: impl Fn(&SimilarityResult) -> (usize, usize),
+) -> Vec<SimilarityResult> {
+    let mut best: FxHashMap<(usize, usize), SimilarityResult> = FxHashMap::default();
+
+    for result in results {
+        let key = key(&result);
+
+        match best.get_mut(&key) {
+            Some(kept) if (result.distance, result.metric) < (kept.distance, kept.metric) => {
+                *kept = result;
+            }
+            Some(_) => {}
+            None => {
+                best.insert(key, result);
+            }
+        }
+    }
+
+    best.into_values().collect()
+}
+
+/// compare current results against a baseline run, a baseline saved with `EdgeWeight::Ratio` has no
+/// distances, so only added and removed pairs are reported for it
+pub fn compare_results(baseline: &[SimilarityResult], current: &[SimilarityResult]) -> ResultsDiff {
+    let baseline_map: FxHashMap<(usize, usize), &SimilarityResult> = baseline
+        .iter()
+        .map(|r| ((r.query_id, r.twin_id), r))
+        .collect();
+    let current_map: FxHashMap<(usize, usize), &SimilarityResult> = current
+        .iter()
+        .map(|r| ((r.query_id, r.twin_id), r))
+        .collect();
+
+    let mut diff = ResultsDiff::default();
+
+    for (key, &result) in &current_map {
+        match baseline_map.get(key) {
+            None => diff.added.push(result.clone()),
+            Some(old) if old.similarity.is_none() && old.distance != result.distance => {
+                diff.changed.push(DistanceChange {
+                    query_id: result.query_id,
+                    twin_id: result.twin_id,
+                    baseline_distance: old.distance,
+                    current_distance: result.distance,
+                })
+            }
+            Some(_) => {}
+        }
+    }
+
+    for (key, &result) in &baseline_map {
+        if !current_map.contains_key(key) {
+            diff.removed.push(result.clone());
+        }
+    }
+
+    diff.added.sort_unstable_by_key(|r| (r.query_id, r.twin_id));
+    diff.removed
+        .sort_unstable_by_key(|r| (r.query_id, r.twin_id));
+    diff.changed
+        .sort_unstable_by_key(|c| (c.query_id, c.twin_id));
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(query_id: usize, twin_id: usize, distance: usize) -> SimilarityResult {
+        SimilarityResult::new(query_id, twin_id, distance, Metric::Levenshtein)
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("crony_{}_{}.csv", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_compare_results_from_csv() {
+        let baseline_path = temp_path("baseline");
+        let current_path = temp_path("current");
+
+        save_results_to_csv(
+            &[result(1, 2, 1), result(1, 3, 2), result(4, 5, 0)],
+            &baseline_path,
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        save_results_to_csv(
+            &[result(1, 2, 1), result(1, 3, 4), result(6, 7, 3)],
+            &current_path,
+            &OutputOptions::default(),
+        )
+        .unwrap();
+
+        let baseline = load_results_from_csv(&baseline_path).unwrap();
+        let current = load_results_from_csv(&current_path).unwrap();
+        std::fs::remove_file(&baseline_path).unwrap();
+        std::fs::remove_file(&current_path).unwrap();
+
+        let diff = compare_results(&baseline, &current);
+
+        assert_eq!(diff.added, vec![result(6, 7, 3)]);
+        assert_eq!(diff.removed, vec![result(4, 5, 0)]);
+        assert_eq!(
+            diff.changed,
+            vec![DistanceChange {
+                query_id: 1,
+                twin_id: 3,
+                baseline_distance: 2,
+                current_distance: 4,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_quality_report_against_ground_truth() {
+        let path = temp_path("ground_truth");
+        std::fs::write(&path, "id_a,id_b\n1,2\n4,3\n5,6\n7,8\n").unwrap();
+        let ground_truth = load_ground_truth(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // (2, 1) and (3, 4) match labels in the other order, (1, 9) is not labeled
+        let results = [result(2, 1, 1), result(3, 4, 0), result(1, 9, 2)];
+        let report = QualityReport::evaluate(&results, &ground_truth);
+
+        assert_eq!(report.true_positives, 2);
+        assert_eq!(report.false_positives, 1);
+        assert_eq!(report.false_negatives, 2);
+        assert!((report.precision - 2.0 / 3.0).abs() < 1e-9);
+        assert!((report.recall - 0.5).abs() < 1e-9);
+        assert!((report.f1 - 4.0 / 7.0).abs() < 1e-9);
+
+        let empty = QualityReport::evaluate(&[], &ground_truth);
+        assert_eq!(empty.f1, 0.0);
+
+        // malformed files are errors naming the line, not panics
+        for (content, message) in [
+            ("id\n1\n2\n", "line 2: expected two id columns"),
+            ("id_a,id_b\n1,2\n3,x\n", "line 3: invalid id 'x'"),
+        ] {
+            std::fs::write(&path, content).unwrap();
+            let error = load_ground_truth(&path).unwrap_err().to_string();
+            assert!(error.starts_with(message), "{}", error);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_nested_json_output() {
+        let path = temp_path("nested_json");
+
+        save_results(
+            &[result(1, 2, 3), result(1, 5, 4), result(2, 7, 0)],
+            &path,
+            OutputFormat::JsonNested,
+            &OutputOptions::default(),
+        )
+        .unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&content).unwrap();
+
+        assert_eq!(
+            parsed,
+            serde_json::json!([
+                { "query_id": 1, "twins": [{ "id": 2, "distance": 3, "metric": "levenshtein" }, { "id": 5, "distance": 4, "metric": "levenshtein" }] },
+                { "query_id": 2, "twins": [{ "id": 7, "distance": 0, "metric": "levenshtein" }] }
+            ])
+        );
+
+        // sorted by distance, every query still gets one object
+        save_results(
+            &[
+                result(2, 7, 0),
+                result(1, 2, 3),
+                result(2, 9, 3),
+                result(1, 5, 4),
+            ],
+            &path,
+            OutputFormat::JsonNested,
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&content).unwrap();
+
+        let twins = |query: usize| -> Vec<u64> {
+            parsed[query]["twins"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|twin| twin["id"].as_u64().unwrap())
+                .collect()
+        };
+        assert_eq!(parsed.as_array().unwrap().len(), 2);
+        assert_eq!(
+            (parsed[0]["query_id"].as_u64(), twins(0)),
+            (Some(1), vec![2, 5])
+        );
+        assert_eq!(
+            (parsed[1]["query_id"].as_u64(), twins(1)),
+            (Some(2), vec![7, 9])
+        );
+    }
+
+    #[test]
+    fn test_per_query_files() {
+        let dir = temp_path("per_query");
+
+        let count = save_results_per_query(
+            &[result(1, 2, 3), result(3, 4, 1), result(1, 5, 2)],
+            &dir,
+            OutputFormat::Csv,
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(count, 5);
+
+        let read = |dir: &str, query_id: usize| {
+            let mut rdr = csv::Reader::from_path(format!("{}/{}.csv", dir, query_id)).unwrap();
+            rdr.records()
+                .map(|record| {
+                    let record = record.unwrap();
+                    (record[0].parse().unwrap(), record[1].parse().unwrap())
+                })
+                .collect::<Vec<(usize, usize)>>()
+        };
+        assert_eq!(read(&dir, 1), vec![(1, 2), (1, 5)]);
+        assert_eq!(read(&dir, 3), vec![(3, 4)]);
+        // pairs are in the files of both ids, swapped in the file of the twin
+        assert_eq!(read(&dir, 2), vec![(2, 1)]);
+        assert_eq!(read(&dir, 4), vec![(4, 3)]);
+        assert_eq!(read(&dir, 5), vec![(5, 1)]);
+
+        let mut files: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["1.csv", "2.csv", "3.csv", "4.csv", "5.csv"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // 2 has a smaller and a greater twin, a pair reported from both sides is written once
+        let dir = temp_path("per_query_both_sides");
+        save_results_per_query(
+            &[result(1, 2, 1), result(2, 3, 2), result(3, 2, 2)],
+            &dir,
+            OutputFormat::Csv,
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(read(&dir, 1), vec![(1, 2)]);
+        assert_eq!(read(&dir, 2), vec![(2, 1), (2, 3)]);
+        assert_eq!(read(&dir, 3), vec![(3, 2)]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_adjacency_json_output() {
+        let path = temp_path("adjacency_json");
+
+        save_results(
+            &[result(1, 2, 3), result(1, 5, 4), result(2, 5, 1)],
+            &path,
+            OutputFormat::JsonAdjacency,
+            &OutputOptions::default(),
+        )
+        .unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&content).unwrap();
+
+        // 2 and 5 list 1 although it was the query of both pairs
+        assert_eq!(
+            parsed,
+            serde_json::json!({
+                "1": [{ "id": 2, "distance": 3 }, { "id": 5, "distance": 4 }],
+                "2": [{ "id": 5, "distance": 1 }, { "id": 1, "distance": 3 }],
+                "5": [{ "id": 2, "distance": 1 }, { "id": 1, "distance": 4 }]
+            })
+        );
+
+        // the same pair from two metrics is one edge
+        let mut other_metric = result(2, 1, 2);
+        other_metric.metric = Metric::Osa;
+        let adjacency = adjacency_lists(&[result(1, 2, 3), other_metric]);
+        assert_eq!(adjacency[&1], vec![Neighbor { id: 2, distance: 2 }]);
+        assert_eq!(adjacency[&2], vec![Neighbor { id: 1, distance: 2 }]);
+    }
+
+    #[test]
+    fn test_sort_output() {
+        let path = temp_path("sort_output");
+        let mut results = vec![
+            result(2, 9, 1),
+            result(1, 7, 3),
+            result(1, 4, 5),
+            result(2, 3, 2),
+            result(1, 5, 3),
+        ];
+
+        let order: SortOrder = "query,twin,distance".parse().unwrap();
+        sort_results_by(&mut results, &order);
+        save_results_to_csv(&results, &path, &OutputOptions::default()).unwrap();
+        let loaded = load_results_from_csv(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let keys: Vec<_> = loaded
+            .iter()
+            .map(|r| (r.query_id, r.twin_id, r.distance))
+            .collect();
+        assert!(keys.windows(2).all(|w| w[0] <= w[1]), "{:?}", keys);
+        assert_eq!(keys[..2], [(1, 4, 5), (1, 5, 3)]);
+
+        // an unknown metric fails the load instead of reading as levenshtein
+        std::fs::write(
+            &path,
+            "query_id,twin_id,distance,metric\n1,2,1,osa\n1,3,2,cosine\n",
+        )
+        .unwrap();
+        let error = load_results_from_csv(&path).unwrap_err().to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            error.starts_with("line 3: invalid metric 'cosine'"),
+            "{}",
+            error
+        );
+
+        // missing columns break ties
+        let order: SortOrder = "distance".parse().unwrap();
+        sort_results_by(&mut results, &order);
+        let keys: Vec<_> = results.iter().map(|r| (r.query_id, r.twin_id)).collect();
+        assert_eq!(keys, [(2, 9), (2, 3), (1, 5), (1, 7), (1, 4)]);
+
+        assert!("query,query".parse::<SortOrder>().is_err());
+        assert!("metric".parse::<SortOrder>().is_err());
+    }
+
+    #[test]
+    fn test_suggest_max_distance() {
+        // ten families of one to two character variations, families are far apart
+        let bases = [
+            "alpha centauri",
+            "brave new world",
+            "catch twenty two",
+            "dune messiah",
+            "east of eden",
+            "fahrenheit",
+            "great expectations",
+            "heart of darkness",
+            "invisible man",
+            "jane eyre",
+        ];
+        let mut records = Vec::new();
+        for (i, base) in bases.iter().enumerate() {
+            records.push((i * 10, base.to_string()));
+            records.push((i * 10 + 1, format!("{}s", base)));
+            records.push((i * 10 + 2, base.replacen('e', "a", 1).replacen('a', "o", 1)));
+        }
+        let ids: Vec<usize> = records.iter().map(|(id, _)| *id).collect();
+        let builder = crate::index::IndexBuilder::new(0.5);
+        builder.bulk_add(records);
+        let indexer = builder.build();
+
+        let suggestion = suggest_max_distance(&indexer, &ids).unwrap();
+        assert_eq!(suggestion.nearest.len(), 30);
+        assert!(
+            (1..=2).contains(&suggestion.max_distance),
+            "{} {:?}",
+            suggestion,
+            suggestion.nearest
+        );
+
+        // one record per family, their near duplicates are found outside the sample
+        let spread: Vec<usize> = (0..10).map(|i| i * 10).collect();
+        let suggestion = suggest_max_distance(&indexer, &spread).unwrap();
+        assert_eq!(suggestion.nearest.len(), 10);
+        assert!(
+            suggestion.nearest.iter().all(|&d| d <= 2),
+            "{:?}",
+            suggestion.nearest
+        );
+
+        assert_eq!(
+            suggest_max_distance(&indexer, &[0]).unwrap().nearest,
+            vec![1]
+        );
+        assert_eq!(suggest_max_distance(&indexer, &[12345]), None);
+    }
+
+    #[test]
+    fn test_distance_summary() {
+        let results = vec![
+            result(1, 2, 4),
+            result(1, 3, 1),
+            result(2, 3, 10),
+            result(2, 4, 2),
+            result(3, 4, 3),
+        ];
+
+        let summary = DistanceSummary::from_results(&results).unwrap();
+
+        assert_eq!(summary.min, 1);
+        assert_eq!(summary.median, 3);
+        assert_eq!(summary.p90, 10);
+        assert_eq!(summary.max, 10);
+        assert!((summary.mean - 4.0).abs() < f64::EPSILON);
+
+        assert!(DistanceSummary::from_results(&[]).is_none());
+    }
+
+    #[test]
+    fn test_dedup_pairs_across_metrics() {
+        let results = vec![
+            result(1, 2, 2),
+            SimilarityResult {
+                metric: Metric::Osa,
+                ..result(1, 2, 1)
+            },
+            result(3, 4, 5),
+        ];
+
+        let mut deduped = dedup_pairs(results);
+        deduped.sort_unstable_by_key(|r| r.query_id);
+
+        assert_eq!(
+            deduped,
+            vec![
+                SimilarityResult {
+                    metric: Metric::Osa,
+                    ..result(1, 2, 1)
+                },
+                result(3, 4, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dedup_directed_pairs_keeps_crossing_ids() {
+        // input 2 matches blocklist entry 1 and input 1 matches blocklist entry 2
+        let results = vec![
+            result(2, 1, 1),
+            result(1, 2, 3),
+            SimilarityResult {
+                metric: Metric::Osa,
+                ..result(1, 2, 2)
+            },
+        ];
+        assert_eq!(dedup_pairs(results.clone()).len(), 1);
+
+        let mut deduped = dedup_directed_pairs(results);
+        deduped.sort_unstable_by_key(|r| r.query_id);
+        assert_eq!(
+            deduped,
+            vec![
+                SimilarityResult {
+                    metric: Metric::Osa,
+                    ..result(1, 2, 2)
+                },
+                result(2, 1, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_debug_columns_show_token_sorted_text() {
+        let builder = crate::index::IndexBuilder::new(0.5);
+        builder.bulk_add(vec![
+            (1, "World, Hello!".to_string()),
+            (2, "hello worlds".to_string()),
+        ]);
+        let indexer = builder.build();
+
+        let mut results: Vec<SimilarityResult> = indexer
+            .search_by_id(1, 2)
+            .into_iter()
+            .map(|r| SimilarityResult::new(1, r.id, r.distance, r.metric))
+            .collect();
+        add_normalized_columns(&mut results, &indexer);
+
+        let path = temp_path("debug_columns");
+        let options = OutputOptions {
+            debug_columns: true,
+            ..Default::default()
+        };
+        save_results_to_csv(&results, &path, &options).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            content,
+            "query_id,twin_id,distance,query_normalized,twin_normalized\n1,2,1,hello world,hello worlds\n"
+        );
+    }
+
+    #[test]
+    fn test_always_quote_and_crlf() {
+        let path = temp_path("always_quote");
+        let options = OutputOptions {
+            metric_column: true,
+            always_quote: true,
+            line_terminator: LineTerminator::Crlf,
+            ..Default::default()
+        };
+        save_results_to_csv(&[result(1, 2, 3)], &path, &options).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            content,
+            "\"query_id\",\"twin_id\",\"distance\",\"metric\"\r\n\"1\",\"2\",\"3\",\"levenshtein\"\r\n"
+        );
+        assert_eq!("crlf".parse(), Ok(LineTerminator::Crlf));
+        assert!("cr".parse::<LineTerminator>().is_err());
+    }
+
+    #[test]
+    fn test_explain_column() {
+        let builder = crate::index::IndexBuilder::new(0.5);
+        builder.bulk_add(vec![
+            (1, "hello world".to_string()),
+            (2, "hello worlds".to_string()),
+        ]);
+        let indexer = builder.build();
+
+        let mut results = vec![result(1, 2, 1)];
+        let options = OutputOptions {
+            explain: true,
+            ..Default::default()
+        };
+        add_output_columns(&mut results, &indexer, &options);
+
+        let path = temp_path("explain");
+        save_results_to_csv(&results, &path, &options).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (header, row) = content.trim_end().split_once('\n').unwrap();
+        assert_eq!(header, "query_id,twin_id,distance,shared_trigrams");
+        let shared = row.strip_prefix("1,2,1,").unwrap();
+        let mut shared: Vec<&str> = shared.split(' ').collect();
+        shared.sort_unstable();
+        assert_eq!(shared, vec!["ell", "hel", "llo", "orl", "rld", "wor"]);
+    }
+
+    #[test]
+    fn test_original_distance_column() {
+        let builder = crate::index::IndexBuilder::new(0.5);
+        builder.bulk_add(vec![
+            (1, "Hello World".to_string()),
+            (2, "world hello".to_string()),
+        ]);
+        let indexer = builder.build();
+
+        let mut results: Vec<SimilarityResult> = indexer
+            .search_by_id(1, 2)
+            .into_iter()
+            .map(|r| SimilarityResult::new(1, r.id, r.distance, r.metric))
+            .collect();
+        add_original_distances(&mut results, &indexer);
+
+        // same after lowercasing and token sorting, far apart as typed
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].distance, 0);
+        assert_eq!(results[0].original_distance, Some(8));
+
+        let path = temp_path("original_distance");
+        let options = OutputOptions {
+            original_distance: true,
+            ..Default::default()
+        };
+        save_results_to_csv(&results, &path, &options).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            content,
+            "query_id,twin_id,distance,original_distance\n1,2,0,8\n"
+        );
+    }
+
+    #[test]
+    fn test_rare_overlap_outranks_common_overlap() {
+        let builder = crate::index::IndexBuilder::new(0.5);
+        let mut records: Vec<(usize, String)> =
+            (10..30).map(|i| (i, format!("apple {}", i))).collect();
+        records.extend([
+            (1, "apple pie".to_string()),
+            (2, "apple pia".to_string()),
+            (3, "zebra fox".to_string()),
+            (4, "zebra fax".to_string()),
+        ]);
+        builder.bulk_add(records);
+        let indexer = builder.build();
+
+        // same distance and lengths, but "app", "ppl", "ple" are in most records
+        let mut results = vec![result(1, 2, 1), result(3, 4, 1)];
+        add_rarity_scores(&mut results, &indexer, 0.5);
+        let (common, rare) = (results[0].score.unwrap(), results[1].score.unwrap());
+        assert!(rare > common, "{} <= {}", rare, common);
+
+        add_rarity_scores(&mut results, &indexer, 0.0);
+        assert_eq!(results[0].score, results[1].score);
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet_round_trip() {
+        let mut results = vec![
+            result(1, 2, 3),
+            SimilarityResult::new(1, 5, 1, Metric::Osa),
+            result(4, 2, 0),
+        ];
+        results[0].query_normalized = Some("hello world".to_string());
+        results[0].twin_normalized = Some("hello worlds".to_string());
+        results[0].original_distance = Some(4);
+        results[2].score = Some(0.25);
+        results[1].shared_trigrams = Some("ell hel".to_string());
+
+        let path = temp_path("round_trip.parquet");
+        let options = OutputOptions {
+            metric_column: true,
+            debug_columns: true,
+            original_distance: true,
+            rarity_weight: Some(0.5),
+            explain: true,
+            ..Default::default()
+        };
+        save_results(&results, &path, OutputFormat::Parquet, &options).unwrap();
+        let loaded = load_results_from_parquet(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, results);
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet_weight_as_ratio_round_trip() {
+        let mut results = vec![result(1, 2, 0), SimilarityResult::new(1, 3, 0, Metric::Osa)];
+        results[0].similarity = Some(11.0 / 12.0);
+        results[1].similarity = Some(1.0);
+
+        let path = temp_path("weight_round_trip.parquet");
+        let options = OutputOptions {
+            weight_as: EdgeWeight::Ratio,
+            metric_column: true,
+            ..Default::default()
+        };
+        save_results(&results, &path, OutputFormat::Parquet, &options).unwrap();
+        let loaded = load_results_from_parquet(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // distances are not saved with ratio weights and load as 0, like from csv
+        assert_eq!(loaded, results);
+    }
+
+    #[test]
+    fn test_weight_as_ratio() {
+        let builder = crate::index::IndexBuilder::new(0.3);
+        builder.bulk_add(vec![
+            (1, "hello world".to_string()),
+            (2, "hello worlds".to_string()),
+            (3, "hello world".to_string()),
+            (4, "hello wrld".to_string()),
+        ]);
+        let indexer = builder.build();
+
+        let mut results: Vec<SimilarityResult> = [1, 2, 3, 4]
+            .iter()
+            .flat_map(|&q| {
+                indexer
+                    .search_by_id(q, 3)
+                    .into_iter()
+                    .map(move |r| SimilarityResult::new(q, r.id, r.distance, r.metric))
+            })
+            .collect();
+        add_similarity_ratios(&mut results, &indexer);
+        assert!(!results.is_empty());
+
+        let path = temp_path("weight_ratio");
+        let options = OutputOptions {
+            weight_as: EdgeWeight::Ratio,
+            ..Default::default()
+        };
+        save_results_to_csv(&results, &path, &options).unwrap();
+
+        let mut rdr = csv::Reader::from_path(&path).unwrap();
+        assert_eq!(
+            rdr.headers().unwrap(),
+            vec!["query_id", "twin_id", "weight"]
+        );
+        let weights: Vec<f64> = rdr
+            .records()
+            .map(|r| r.unwrap()[2].parse().unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(weights.len(), results.len());
+        assert!(weights.iter().all(|w| (0.0..=1.0).contains(w)));
+        // exact twins weigh 1.0, "hello world" / "hello worlds" 1 - 1/12
+        assert!(weights.contains(&1.0));
+        assert!(weights.iter().any(|w| (w - 11.0 / 12.0).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_weight_as_ratio_round_trip() {
+        let mut results = vec![result(1, 2, 1), result(1, 3, 0)];
+        results[0].similarity = Some(11.0 / 12.0);
+        results[1].similarity = Some(1.0);
+
+        let path = temp_path("weight_round_trip");
+        let options = OutputOptions {
+            weight_as: EdgeWeight::Ratio,
+            metric_column: true,
+            ..Default::default()
+        };
+        save_results_to_csv(&results, &path, &options).unwrap();
+        let loaded = load_results_from_csv(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let weights: Vec<_> = loaded
+            .iter()
+            .map(|r| (r.query_id, r.twin_id, r.similarity))
+            .collect();
+        assert_eq!(weights, [(1, 2, Some(11.0 / 12.0)), (1, 3, Some(1.0))]);
+        assert!(loaded.iter().all(|r| r.metric == Metric::Levenshtein));
+
+        // a weight baseline has no distances, pairs it shares with the current run never changed
+        let diff = compare_results(&loaded, &[result(1, 2, 1), result(1, 4, 2)]);
+        assert!(diff.changed.is_empty());
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.removed.len(), 1);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::index::{Indexer, SearchResult};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::Read;
+use std::net::SocketAddr;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Largest request body read, longer bodies are answered with 413.
+pub const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+/// Body of `POST /search`, `max_distance` falls back to the one the server was started with and is
+/// capped by it, so a client can not make a query arbitrarily expensive.
+#[derive(Debug, Deserialize)]
+pub struct SearchRequest {
+    pub query: String,
+    pub max_distance: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResponse {
+    pub results: Vec<SearchResult>,
+}
+
+/// HTTP/JSON front of a prebuilt `Indexer`, the index is built once and answers queries until
+/// the process stops (or `unblock` is called).
+pub struct SearchServer {
+    server: Server,
+    workers: usize,
+}
+
+impl SearchServer {
+    /// `addr` like "0.0.0.0:8080", a bare ":8080" only listens on localhost.
+    pub fn bind(addr: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let addr = match addr.strip_prefix(':') {
+            Some(port) => format!("127.0.0.1:{}", port),
+            None => addr.to_string(),
+        };
+        Ok(Self {
+            server: Server::http(addr)?,
+            workers: std::thread::available_parallelism().map_or(4, |n| n.get()),
+        })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Answers requests on one worker thread per core until unblocked. Workers are plain threads,
+    /// not rayon ones, so waiting for requests never blocks the pool.
+    pub fn run(&self, indexer: &Indexer, max_distance: usize) {
+        std::thread::scope(|scope| {
+            for _ in 0..self.workers {
+                scope.spawn(|| {
+                    for request in self.server.incoming_requests() {
+                        handle(request, indexer, max_distance);
+                    }
+                });
+            }
+        });
+    }
+
+    /// Makes `run` return once the requests in flight are answered.
+    pub fn unblock(&self) {
+        // every worker takes one unblock message
+        for _ in 0..self.workers {
+            self.server.unblock();
+        }
+    }
+}
+
+fn handle(mut request: Request, indexer: &Indexer, max_distance: usize) {
+    let (status, body) = match (request.method(), request.url()) {
+        (Method::Post, "/search") => {
+            // one byte more than allowed tells a full body from a cut one
+            let mut body = String::new();
+            match request
+                .as_reader()
+                .take(MAX_BODY_BYTES + 1)
+                .read_to_string(&mut body)
+            {
+                Ok(read) if read as u64 > MAX_BODY_BYTES => {
+                    (413, error_body("request body too large"))
+                }
+                Ok(_) => search(&body, indexer, max_distance),
+                Err(e) => (400, error_body(&e.to_string())),
+            }
+        }
+        (_, "/search") => (405, error_body("use POST /search")),
+        _ => (404, error_body("not found")),
+    };
+
+    let header = Header::from_bytes("Content-Type", "application/json").expect("valid header");
+    let response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header);
+    if let Err(e) = request.respond(response) {
+        eprintln!("Error sending response: {}", e);
+    }
+}
+
+fn search(body: &str, indexer: &Indexer, max_distance: usize) -> (u16, String) {
+    let request: SearchRequest = match serde_json::from_str(body) {
+        Ok(request) => request,
+        Err(e) => return (400, error_body(&format!("invalid request: {}", e))),
+    };
+
+    let max_distance = request
+        .max_distance
+        .map_or(max_distance, |requested| requested.min(max_distance));
+    let mut results = indexer.search(&request.query, max_distance);
+    results.sort_unstable_by_key(|r| (r.distance, r.id));
+
+    match serde_json::to_string(&SearchResponse { results }) {
+        Ok(body) => (200, body),
+        Err(e) => (500, error_body(&e.to_string())),
+    }
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
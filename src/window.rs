@@ -0,0 +1,241 @@
+use crate::distance::Metric;
+use crate::index::{IndexBuilder, Indexer};
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+/// How documents are cut into windows, see `WindowIndex`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowOptions {
+    /// window length in characters
+    pub size: usize,
+    /// distance between window starts, smaller steps align shared blocks better (a block shifted by `s`
+    /// characters costs up to `2 * s` edits) at the cost of more windows
+    pub step: usize,
+    /// window pairs two documents need to be reported, counted on the side with fewer matched windows
+    pub min_matching_windows: usize,
+}
+
+impl WindowOptions {
+    /// Step of a quarter window.
+    pub fn new(size: usize, min_matching_windows: usize) -> Self {
+        let size = size.max(1);
+        Self {
+            size,
+            step: (size / 4).max(1),
+            min_matching_windows: min_matching_windows.max(1),
+        }
+    }
+}
+
+/// Two documents sharing similar windows, `query_id < twin_id`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentMatch {
+    pub query_id: usize,
+    pub twin_id: usize,
+    /// see `WindowOptions::min_matching_windows`
+    pub matching_windows: usize,
+    /// distance of the closest window pair
+    pub distance: usize,
+    pub metric: Metric,
+}
+
+/// Near duplicate detection for long documents: whole text distances miss documents that share a
+/// large block (e.g. a quoted article) and differ elsewhere. Every document is cut into overlapping
+/// windows, the windows are indexed and verified like records, and documents are matched by how many
+/// of their windows have a twin in the other document.
+pub struct WindowIndex {
+    indexer: Indexer,
+    /// document of every window, windows are numbered from 0
+    documents: Vec<usize>,
+    options: WindowOptions,
+}
+
+impl WindowIndex {
+    /// Index the windows of `records` with the settings of `builder` (normalization, match ratio, metrics),
+    /// the max distance of `document_matches` applies to single windows.
+    pub fn build(
+        builder: IndexBuilder,
+        records: Vec<(usize, String)>,
+        options: WindowOptions,
+    ) -> Self {
+        let mut documents = Vec::new();
+        let mut windows = Vec::new();
+
+        for (id, text) in records {
+            for window in cut_windows(&text, options.size, options.step) {
+                windows.push((documents.len(), window));
+                documents.push(id);
+            }
+        }
+
+        builder.bulk_add(windows);
+        Self {
+            indexer: builder.build(),
+            documents,
+            options,
+        }
+    }
+
+    pub fn window_count(&self) -> usize {
+        self.documents.len()
+    }
+
+    /// Document pairs with at least `min_matching_windows` window twins within `max_distance`,
+    /// sorted by query then twin id.
+    pub fn document_matches(&self, max_distance: usize) -> Vec<DocumentMatch> {
+        // windows of a document and of its twin that found each other, and the closest distance
+        let pairs: Vec<WindowPair> = (0..self.documents.len())
+            .into_par_iter()
+            .flat_map_iter(|window| {
+                let document = self.documents[window];
+                self.indexer
+                    .search_by_id_unsorted(window, max_distance)
+                    .into_iter()
+                    .filter(move |twin| self.documents[twin.id] != document)
+                    .map(move |twin| {
+                        let other = self.documents[twin.id];
+                        let (documents, windows) = if document < other {
+                            ((document, other), (window, twin.id))
+                        } else {
+                            ((other, document), (twin.id, window))
+                        };
+                        WindowPair {
+                            documents,
+                            windows,
+                            closest: (twin.distance, twin.metric),
+                        }
+                    })
+            })
+            .collect();
+
+        let mut grouped: FxHashMap<(usize, usize), WindowPairs> = FxHashMap::default();
+        for pair in pairs {
+            let entry = grouped
+                .entry(pair.documents)
+                .or_insert_with(|| WindowPairs {
+                    query_windows: FxHashSet::default(),
+                    twin_windows: FxHashSet::default(),
+                    closest: pair.closest,
+                });
+            entry.query_windows.insert(pair.windows.0);
+            entry.twin_windows.insert(pair.windows.1);
+            entry.closest = entry.closest.min(pair.closest);
+        }
+
+        let mut matches: Vec<DocumentMatch> = grouped
+            .into_iter()
+            .map(|((query_id, twin_id), pairs)| DocumentMatch {
+                query_id,
+                twin_id,
+                matching_windows: pairs.query_windows.len().min(pairs.twin_windows.len()),
+                distance: pairs.closest.0,
+                metric: pairs.closest.1,
+            })
+            .filter(|m| m.matching_windows >= self.options.min_matching_windows)
+            .collect();
+        matches.sort_unstable_by_key(|m| (m.query_id, m.twin_id));
+        matches
+    }
+}
+
+/// Two similar windows of different documents, ordered by document id.
+struct WindowPair {
+    documents: (usize, usize),
+    windows: (usize, usize),
+    closest: (usize, Metric),
+}
+
+/// Every window pair of two documents.
+struct WindowPairs {
+    query_windows: FxHashSet<usize>,
+    twin_windows: FxHashSet<usize>,
+    closest: (usize, Metric),
+}
+
+/// Windows of `size` characters starting every `step` characters, the last one ends with the text.
+/// A text shorter than a window is a single window.
+fn cut_windows(text: &str, size: usize, step: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() <= size {
+        return vec![text.to_string()];
+    }
+
+    let last = chars.len() - size;
+    let mut starts: Vec<usize> = (0..=last).step_by(step.max(1)).collect();
+    if starts.last() != Some(&last) {
+        starts.push(last);
+    }
+
+    starts
+        .into_iter()
+        .map(|start| chars[start..start + size].iter().collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distance::NormalizeOptions;
+
+    fn news_records() -> Vec<(usize, String)> {
+        let quoted = "The committee approved the new budget on Tuesday after a long debate about \
+                      school funding, road repairs and the future of the public library downtown.";
+        vec![
+            (
+                1,
+                format!(
+                    "Local news. {} Residents are invited to comment online.",
+                    quoted
+                ),
+            ),
+            (
+                2,
+                format!(
+                    "Opinion: why this matters for families across the region. {} We think it is a good start.",
+                    quoted
+                ),
+            ),
+            (
+                3,
+                "Weather forecast for the weekend: sunny skies with light winds from the west, \
+                 temperatures rising slowly towards the middle of next week."
+                    .to_string(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_cut_windows() {
+        assert_eq!(cut_windows("abcdefg", 4, 2), vec!["abcd", "cdef", "defg"]);
+        assert_eq!(cut_windows("abc", 4, 2), vec!["abc"]);
+    }
+
+    #[test]
+    fn test_shared_block_is_detected() {
+        let builder = IndexBuilder::new(0.5).with_normalize_options(NormalizeOptions {
+            raw: true,
+            ..Default::default()
+        });
+        let index = WindowIndex::build(builder, news_records(), WindowOptions::new(40, 3));
+        let matches = index.document_matches(10);
+
+        assert_eq!(matches.len(), 1, "{:?}", matches);
+        assert_eq!((matches[0].query_id, matches[0].twin_id), (1, 2));
+        assert!(matches[0].matching_windows >= 3);
+    }
+
+    #[test]
+    fn test_shared_block_with_default_normalization() {
+        // windows are lowercased and token sorted like records, aligned windows of the block are equal
+        let index = WindowIndex::build(
+            IndexBuilder::new(0.5),
+            news_records(),
+            WindowOptions::new(40, 3),
+        );
+        let matches = index.document_matches(10);
+
+        assert_eq!(matches.len(), 1, "{:?}", matches);
+        assert_eq!((matches[0].query_id, matches[0].twin_id), (1, 2));
+        assert!(matches[0].matching_windows >= 3);
+    }
+}
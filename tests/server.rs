@@ -0,0 +1,71 @@
+//! `--serve` end to end: a real server on a free local port, queried over TCP.
+#![cfg(feature = "serve")]
+
+use crony_researcher::index::IndexBuilder;
+use crony_researcher::server::{MAX_BODY_BYTES, SearchServer};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+fn post(addr: SocketAddr, path: &str, body: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn test_search_over_http() {
+    let builder = IndexBuilder::new(0.5);
+    builder.bulk_add(vec![
+        (1, "hello world".to_string()),
+        (2, "foo bar baz".to_string()),
+    ]);
+    let indexer = builder.build();
+
+    let server = SearchServer::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| server.run(&indexer, 2));
+
+        let response = post(addr, "/search", r#"{"query": "hello worlds"}"#);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "results": [{ "id": 1, "distance": 1, "metric": "levenshtein" }]
+            })
+        );
+
+        let response = post(addr, "/search", "not json");
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+
+        // "hello world" is 3 edits away, beyond the server's limit of 2
+        assert!(!indexer.search("hello worldxyz", 3).is_empty());
+        let response = post(
+            addr,
+            "/search",
+            r#"{"query": "hello worldxyz", "max_distance": 10}"#,
+        );
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(json, serde_json::json!({ "results": [] }));
+
+        let query = "a".repeat(MAX_BODY_BYTES as usize);
+        let response = post(addr, "/search", &format!(r#"{{"query": "{}"}}"#, query));
+        assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+
+        server.unblock();
+    });
+}